@@ -0,0 +1,141 @@
+//! `kbd`, reads and writes the keymap of a connected keyboard over raw HID
+//!
+//! kbd pull > keymap.kbd
+//! kbd push keymap.kbd
+//! kbd set BASE 2 3 "(kp ESC)"
+//! kbd layers [LAYER..]
+//! kbd status
+
+use std::{env, fs, process};
+
+use config::kbd::{self, parse_layer};
+use config::parser::parse_single_behavior;
+use config::protocol::{Packet, Position, REPORT_LEN, SafeModeReason, Transport};
+use config::scanner::scan_input;
+use config::writer::{layer_name, write_config};
+use config::{NUM_LAYERS, parse_config};
+use hidapi::{HidApi, HidDevice, HidError};
+
+// The raw HID interface uses the same usage page and usage as QMK
+const USAGE_PAGE: u16 = 0xFF60;
+const USAGE: u16 = 0x61;
+const TIMEOUT_MS: i32 = 1000;
+
+const HELP: &str = "Usage:
+    kbd pull                        print the keymap on the keyboard
+    kbd push <file>                 write the layers of a keymap file to the keyboard
+    kbd set <layer> <row> <col> <behavior>
+                                    change one key, e.g. kbd set BASE 2 3 \"(kp ESC)\"
+    kbd layers [layer..]            print the active layers, or keep the given ones on
+    kbd status                      print whether the keyboard is in safe mode, and why
+
+Layers are BASE, L1, L2.. in the order the keymap defines them.";
+
+struct HidTransport {
+    device: HidDevice,
+}
+
+impl HidTransport {
+    fn open() -> Result<Self, HidError> {
+        let api = HidApi::new()?;
+        let info = api
+            .device_list()
+            .find(|d| d.usage_page() == USAGE_PAGE && d.usage() == USAGE)
+            .ok_or_else(|| HidError::HidApiError {
+                message: "No keyboard with a raw HID interface found".to_owned(),
+            })?;
+
+        Ok(Self {
+            device: info.open_device(&api)?,
+        })
+    }
+}
+
+impl Transport for HidTransport {
+    type Error = HidError;
+
+    fn send(&mut self, packet: &Packet) -> Result<(), Self::Error> {
+        // The first byte is the report id, which the raw HID interface doesn't use
+        let mut buf = [0; REPORT_LEN + 1];
+        buf[1..].copy_from_slice(packet);
+        self.device.write(&buf).map(|_| ())
+    }
+
+    fn receive(&mut self) -> Result<Packet, Self::Error> {
+        let mut packet = [0; REPORT_LEN];
+
+        match self.device.read_timeout(&mut packet, TIMEOUT_MS)? {
+            REPORT_LEN => Ok(packet),
+            _ => Err(HidError::HidApiError {
+                message: "Timed out waiting for the keyboard".to_owned(),
+            }),
+        }
+    }
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
+
+fn layer_arg(arg: &str) -> u32 {
+    parse_layer(arg).unwrap_or_else(|| fail(format!("Unknown layer: {}\n\n{}", arg, HELP)))
+}
+
+fn int_arg(arg: &str) -> u8 {
+    arg.parse()
+        .unwrap_or_else(|_| fail(format!("Expected a number, got {}\n\n{}", arg, HELP)))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    // Only opened once the arguments make sense, so mistakes get the help text
+    let open = || HidTransport::open().unwrap_or_else(|e| fail(e));
+
+    let res = match args.as_slice() {
+        ["pull"] => kbd::pull(&mut open()).map(|layers| print!("{}", write_config(&layers))),
+        ["push", path] => {
+            let input = fs::read(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            let config = parse_config(&mut scan_input(&mut input.into()));
+            kbd::push(&mut open(), &config)
+        }
+        ["set", layer, row, col, behavior] => {
+            let pos = Position {
+                layer: layer_arg(layer) as u8,
+                row: int_arg(row),
+                col: int_arg(col),
+            };
+            let behavior = parse_single_behavior(
+                &mut scan_input(&mut behavior.bytes().collect()),
+                parse_layer,
+            );
+            kbd::set(&mut open(), pos, behavior)
+        }
+        ["layers"] => kbd::layers(&mut open()).map(|layers| {
+            let names: Vec<String> = (0..NUM_LAYERS as u32)
+                .filter(|id| layers & (1 << id) != 0)
+                .map(layer_name)
+                .collect();
+            println!("{}", names.join(" "));
+        }),
+        ["status"] => kbd::boot_status(&mut open()).map(|reason| match reason {
+            None => println!("Normal"),
+            Some(SafeModeReason::InvalidKeymap) => println!("Safe mode, the keymap is invalid"),
+            Some(SafeModeReason::Panicked) => println!("Safe mode, the last boot panicked"),
+            Some(SafeModeReason::Bootmagic) => println!("Safe mode, bootmagic key held"),
+        }),
+        ["layers", names @ ..] => {
+            let layers = names
+                .iter()
+                .fold(0, |acc, name| acc | (1 << layer_arg(name)));
+            kbd::lock_layers(&mut open(), layers)
+        }
+        _ => fail(HELP),
+    };
+
+    if let Err(e) = res {
+        fail(format!("{:?}", e));
+    }
+}
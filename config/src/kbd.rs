@@ -0,0 +1,177 @@
+//! Host side of the raw HID protocol, the commands behind the `kbd` tool. Everything goes through
+//! a `Transport`, so the commands can be run against the keyboard or in-process, which the sim
+//! crate does against the firmware's own command handler.
+
+use crate::NUM_LAYERS;
+use crate::no_std::{Behavior, COLS, Config, KEYS, Layer, ROWS};
+use crate::protocol::{
+    CallError, PROTOCOL_VERSION, Position, Request, Response, SafeModeReason, Status, Transport,
+    call,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+    Call(CallError<E>),
+    // The keyboard answered with an error status
+    Device(Status),
+    // The keyboard answered with something that doesn't fit the request
+    Unexpected(Response),
+    UnsupportedVersion(u8),
+    // The keyboard was built for a different matrix, as (rows, cols)
+    MatrixSize(u8, u8),
+}
+
+impl<E> From<CallError<E>> for Error<E> {
+    fn from(value: CallError<E>) -> Self {
+        Self::Call(value)
+    }
+}
+
+/// Layer id from a command line argument, either the id itself or a name from
+/// `writer::layer_name`
+pub fn parse_layer(arg: &str) -> Option<u32> {
+    let id = match arg {
+        "BASE" => 0,
+        _ => arg.strip_prefix('L').unwrap_or(arg).parse().ok()?,
+    };
+
+    ((id as usize) < NUM_LAYERS).then_some(id)
+}
+
+fn request<T: Transport>(transport: &mut T, request: Request) -> Result<Response, Error<T::Error>> {
+    match call(transport, &request)? {
+        Response::Error(status) => Err(Error::Device(status)),
+        response => Ok(response),
+    }
+}
+
+fn expect_done<T: Transport>(transport: &mut T, req: Request) -> Result<(), Error<T::Error>> {
+    match request(transport, req)? {
+        Response::Done => Ok(()),
+        other => Err(Error::Unexpected(other)),
+    }
+}
+
+/// Check the keyboard speaks our protocol version and has the matrix this build expects
+pub fn connect<T: Transport>(transport: &mut T) -> Result<(), Error<T::Error>> {
+    match request(transport, Request::ProtocolInfo)? {
+        Response::ProtocolInfo {
+            version: PROTOCOL_VERSION,
+        } => {}
+        Response::ProtocolInfo { version } => return Err(Error::UnsupportedVersion(version)),
+        other => return Err(Error::Unexpected(other)),
+    }
+
+    match request(transport, Request::MatrixSize)? {
+        Response::MatrixSize { rows, cols, .. }
+            if (rows as usize, cols as usize) == (ROWS, COLS) =>
+        {
+            Ok(())
+        }
+        Response::MatrixSize { rows, cols, .. } => Err(Error::MatrixSize(rows, cols)),
+        other => Err(Error::Unexpected(other)),
+    }
+}
+
+fn position(layer: u32, pos: usize) -> Position {
+    Position {
+        layer: layer as u8,
+        row: (pos / COLS) as u8,
+        col: (pos % COLS) as u8,
+    }
+}
+
+/// Read every layer the keyboard has. Layers are defined in order, so the first one missing ends
+/// the keymap.
+pub fn pull<T: Transport>(
+    transport: &mut T,
+) -> Result<[Option<Layer>; NUM_LAYERS], Error<T::Error>> {
+    connect(transport)?;
+
+    let mut layers = [(); NUM_LAYERS].map(|_| None);
+
+    for id in 0..NUM_LAYERS as u32 {
+        let mut keys = [Behavior::None; KEYS];
+
+        for (pos, key) in keys.iter_mut().enumerate() {
+            *key = match request(transport, Request::GetBehavior(position(id, pos))) {
+                Ok(Response::Behavior(behavior)) => behavior,
+                Ok(other) => return Err(Error::Unexpected(other)),
+                Err(Error::Device(Status::InvalidPosition)) if pos == 0 => return Ok(layers),
+                Err(e) => return Err(e),
+            };
+        }
+
+        layers[id as usize] = Some(Layer { id, keys });
+    }
+
+    Ok(layers)
+}
+
+/// Write the layers of a config to the keyboard. Only the layers are live, the other sections
+/// need a firmware build.
+pub fn push<T: Transport>(transport: &mut T, config: &Config) -> Result<(), Error<T::Error>> {
+    connect(transport)?;
+
+    for layer in config.layers.iter().flatten() {
+        for (pos, behavior) in layer.keys.iter().enumerate() {
+            expect_done(
+                transport,
+                Request::SetBehavior(position(layer.id, pos), *behavior),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn set<T: Transport>(
+    transport: &mut T,
+    pos: Position,
+    behavior: Behavior,
+) -> Result<(), Error<T::Error>> {
+    connect(transport)?;
+    expect_done(transport, Request::SetBehavior(pos, behavior))
+}
+
+/// Active layers, bit n is set while layer n is active
+pub fn layers<T: Transport>(transport: &mut T) -> Result<u16, Error<T::Error>> {
+    connect(transport)?;
+
+    match request(transport, Request::GetLayers)? {
+        Response::Layers(layers) => Ok(layers),
+        other => Err(Error::Unexpected(other)),
+    }
+}
+
+/// Keep layers on regardless of the keys held, an empty mask releases them
+pub fn lock_layers<T: Transport>(transport: &mut T, layers: u16) -> Result<(), Error<T::Error>> {
+    connect(transport)?;
+    expect_done(transport, Request::SetLayers(layers))
+}
+
+/// Why the keyboard is in safe mode, `None` if it booted normally
+pub fn boot_status<T: Transport>(
+    transport: &mut T,
+) -> Result<Option<SafeModeReason>, Error<T::Error>> {
+    connect(transport)?;
+
+    match request(transport, Request::BootStatus)? {
+        Response::BootStatus(reason) => Ok(reason),
+        other => Err(Error::Unexpected(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kbd::parse_layer;
+
+    #[test]
+    fn test_parse_layer() {
+        assert_eq!(parse_layer("BASE"), Some(0));
+        assert_eq!(parse_layer("L3"), Some(3));
+        assert_eq!(parse_layer("9"), Some(9));
+        assert_eq!(parse_layer("L10"), None);
+        assert_eq!(parse_layer("LOWER"), None);
+    }
+}
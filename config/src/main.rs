@@ -1,3 +1,37 @@
+//! `config`, builds a UF2 that writes only the keymap partition, so a keymap can be changed by
+//! dragging it onto the boot drive without reflashing the firmware
+//!
+//! config uf2 keymap.kbd keymap.uf2
+
+use std::{env, fs, process};
+
+use config::parse_config;
+use config::partition::{KEYMAP_ADDR, encode_partition};
+use config::scanner::scan_input;
+use config::uf2::write_uf2;
+
+const HELP: &str = "Usage:
+    config uf2 <keymap> <output>    write a UF2 holding a keymap file
+
+Everything in the keymap file goes in the keymap partition, none of it needs a firmware build.";
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["uf2", input, output] => {
+            let keymap = fs::read(input).unwrap_or_else(|e| fail(format!("{}: {}", input, e)));
+            let config = parse_config(&mut scan_input(&mut keymap.into()));
+            let image = write_uf2(KEYMAP_ADDR, &encode_partition(&config));
+
+            fs::write(output, image).unwrap_or_else(|e| fail(format!("{}: {}", output, e)));
+        }
+        _ => fail(HELP),
+    }
 }
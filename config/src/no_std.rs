@@ -1,5 +1,6 @@
-#![no_std]
-/// Types to export to the firmware
+//! Types to export to the firmware
+
+use crate::NUM_LAYERS;
 
 #[macro_export]
 macro_rules! set_rows_and_columns {
@@ -12,15 +13,138 @@ macro_rules! set_rows_and_columns {
 
 set_rows_and_columns!(4, 6);
 
+pub const MAX_OVERRIDES: usize = 16;
+pub const MAX_ALT_REPEATS: usize = 16;
+pub const MAX_CONDITIONAL_LAYERS: usize = 8;
+pub const MAX_LED_LAYERS: usize = 5;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Config {
     pub options: Options,
-    pub layers: [Option<Layer>; 10],
+    pub layers: [Option<Layer>; NUM_LAYERS],
+    pub overrides: [Option<KeyOverride>; MAX_OVERRIDES],
+    pub alt_repeats: [Option<(Key, Key)>; MAX_ALT_REPEATS],
+    pub conditional_layers: [Option<ConditionalLayer>; MAX_CONDITIONAL_LAYERS],
+    pub led_layers: [Option<LedLayer>; MAX_LED_LAYERS],
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Options {
     pub tapping_term_ms: Option<u32>,
+    /// How long a key must settle for before a change counts
+    pub debounce_ms: u32,
+    pub auto_shift: AutoShift,
+    pub mouse_move: MouseCurve,
+    pub mouse_scroll: MouseCurve,
+    pub usb: UsbOptions,
+    pub bootmagic: Bootmagic,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            tapping_term_ms: None,
+            debounce_ms: 5,
+            auto_shift: AutoShift::default(),
+            mouse_move: MouseCurve::MOVE,
+            mouse_scroll: MouseCurve::SCROLL,
+            usb: UsbOptions::default(),
+            bootmagic: Bootmagic::default(),
+        }
+    }
+}
+
+/// Keys checked once at power on, by index into the matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bootmagic {
+    /// Held to come up in safe mode, with a minimal built-in keymap
+    pub safe_mode: Option<u8>,
+    /// Held to clear the keymap and settings saved in flash
+    pub clear: Option<u8>,
+}
+
+impl Default for Bootmagic {
+    fn default() -> Self {
+        Self {
+            safe_mode: Some(0),
+            clear: None,
+        }
+    }
+}
+
+/// USB interfaces to expose besides the keyboard, which is always present
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbOptions {
+    pub mouse: bool,
+    pub consumer: bool,
+    pub system: bool,
+    pub raw_hid: bool,
+    pub serial: bool,
+}
+
+impl Default for UsbOptions {
+    fn default() -> Self {
+        Self {
+            mouse: true,
+            consumer: true,
+            system: true,
+            raw_hid: false,
+            serial: false,
+        }
+    }
+}
+
+/// Acceleration for mouse keys. Speed goes from `start_speed` to `max_speed` over
+/// `time_to_max_ms` along `t^exponent`, speeds are in pixels (or scroll steps) per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseCurve {
+    pub start_speed: u32,
+    pub max_speed: u32,
+    pub time_to_max_ms: u32,
+    pub exponent: u32,
+}
+
+impl MouseCurve {
+    pub const MOVE: Self = Self {
+        start_speed: 100,
+        max_speed: 1200,
+        time_to_max_ms: 1000,
+        exponent: 2,
+    };
+    pub const SCROLL: Self = Self {
+        start_speed: 10,
+        max_speed: 40,
+        time_to_max_ms: 1000,
+        exponent: 1,
+    };
+}
+
+/// Hold times after which a key is sent shifted, per key class. `None` disables auto-shift for
+/// that class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AutoShift {
+    pub alpha_ms: Option<u32>,
+    pub number_ms: Option<u32>,
+    pub symbol_ms: Option<u32>,
+}
+
+impl AutoShift {
+    pub fn timeout_ms(&self, key: Key) -> Option<u32> {
+        match key.class() {
+            KeyClass::Alpha => self.alpha_ms,
+            KeyClass::Number => self.number_ms,
+            KeyClass::Symbol => self.symbol_ms,
+            KeyClass::Other => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyClass {
+    Alpha,
+    Number,
+    Symbol,
+    Other,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -86,6 +210,86 @@ pub enum Key {
     LFT,
     RHT,
     DN,
+    COMM, // Comma
+    DOT,  // Period
+    SCLN, // Semicolon
+    CAPS, // Locks
+    NLCK,
+    SLCK,
+}
+
+impl Key {
+    pub fn class(self) -> KeyClass {
+        match self {
+            Self::A
+            | Self::B
+            | Self::C
+            | Self::D
+            | Self::E
+            | Self::F
+            | Self::G
+            | Self::H
+            | Self::I
+            | Self::J
+            | Self::K
+            | Self::L
+            | Self::M
+            | Self::N
+            | Self::O
+            | Self::P
+            | Self::Q
+            | Self::R
+            | Self::S
+            | Self::T
+            | Self::U
+            | Self::V
+            | Self::W
+            | Self::X
+            | Self::Y
+            | Self::Z => KeyClass::Alpha,
+            Self::N0
+            | Self::N1
+            | Self::N2
+            | Self::N3
+            | Self::N4
+            | Self::N5
+            | Self::N6
+            | Self::N7
+            | Self::N8
+            | Self::N9 => KeyClass::Number,
+            Self::MNS
+            | Self::EQL
+            | Self::BSLH
+            | Self::FSLH
+            | Self::LSBR
+            | Self::RSBR
+            | Self::QUOT
+            | Self::COMM
+            | Self::DOT
+            | Self::SCLN => KeyClass::Symbol,
+            _ => KeyClass::Other,
+        }
+    }
+
+    /// The modifier this key represents, if it is a modifier key
+    pub fn modifier(self) -> Mods {
+        match self {
+            Self::LCTL => Mods::CTRL,
+            Self::LSFT => Mods::SHIFT,
+            Self::LALT => Mods::ALT,
+            Self::LGUI => Mods::GUI,
+            _ => Mods::NONE,
+        }
+    }
+
+    /// Modifiers the host needs to see alongside this key, for characters that only exist on the
+    /// shifted layer of a US keyboard
+    pub fn implicit_mods(self) -> Mods {
+        match self {
+            Self::LPRN | Self::RPRN | Self::LCBR | Self::RCBR => Mods::SHIFT,
+            _ => Mods::NONE,
+        }
+    }
 }
 
 impl From<&str> for Key {
@@ -152,26 +356,281 @@ impl From<&str> for Key {
             "LFT" => Self::LFT,
             "RHT" => Self::RHT,
             "DN" => Self::DN,
+            "COMM" => Self::COMM,
+            "DOT" => Self::DOT,
+            "SCLN" => Self::SCLN,
+            "CAPS" => Self::CAPS,
+            "NLCK" => Self::NLCK,
+            "SLCK" => Self::SLCK,
             _ => panic!("Unexpected key: {}", value),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl From<String> for Key {
     fn from(value: String) -> Self {
         value.as_str().into()
     }
 }
 
+/// Set of modifiers, laid out like the modifier byte of a HID keyboard report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Mods(pub u8);
+
+impl Mods {
+    pub const NONE: Self = Self(0);
+    pub const CTRL: Self = Self(0x01);
+    pub const SHIFT: Self = Self(0x02);
+    pub const ALT: Self = Self(0x04);
+    pub const GUI: Self = Self(0x08);
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl From<&str> for Mods {
+    fn from(value: &str) -> Self {
+        match value {
+            "ctrl" => Self::CTRL,
+            "shift" => Self::SHIFT,
+            "alt" => Self::ALT,
+            "gui" => Self::GUI,
+            _ => panic!("Unexpected modifier: {}", value),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<String> for Mods {
+    fn from(value: String) -> Self {
+        value.as_str().into()
+    }
+}
+
+/// Global replacement of `trigger` with `replacement` while any of `mods` is held. The matching
+/// modifiers are removed from the report for as long as the replacement is pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyOverride {
+    pub trigger: Key,
+    pub replacement: Key,
+    pub mods: Mods,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Behavior {
     Key(Key),
     MomentaryLayer(u32),
     HoldTap(Key, Key),
+    ModMorph(Key, Key, Mods), // Base key, morphed key, triggering modifiers
+    Repeat,
+    AltRepeat,
+    MouseButton(MouseButton),
+    MouseMove(Direction),
+    MouseScroll(Direction),
+    Consumer(Consumer),
+    System(SystemControl),
     None,
     Transparent, // 🏳️‍⚧️
 }
 
+/// Keys from the HID consumer page, written as `C_*` in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consumer {
+    VolUp,
+    VolDown,
+    Mute,
+    PlayPause,
+    Next,
+    Prev,
+    Stop,
+    BriUp,
+    BriDown,
+}
+
+impl From<&str> for Consumer {
+    fn from(value: &str) -> Self {
+        match value {
+            "C_VOL_UP" => Self::VolUp,
+            "C_VOL_DN" => Self::VolDown,
+            "C_MUTE" => Self::Mute,
+            "C_PLAY_PAUSE" => Self::PlayPause,
+            "C_NEXT" => Self::Next,
+            "C_PREV" => Self::Prev,
+            "C_STOP" => Self::Stop,
+            "C_BRI_UP" => Self::BriUp,
+            "C_BRI_DN" => Self::BriDown,
+            _ => panic!("Unexpected consumer key: {}", value),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<String> for Consumer {
+    fn from(value: String) -> Self {
+        value.as_str().into()
+    }
+}
+
+/// Keys from the HID system control collection, written as `SYS_*` in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemControl {
+    PowerDown,
+    Sleep,
+    Wake,
+}
+
+impl From<&str> for SystemControl {
+    fn from(value: &str) -> Self {
+        match value {
+            "SYS_PWR" => Self::PowerDown,
+            "SYS_SLEEP" => Self::Sleep,
+            "SYS_WAKE" => Self::Wake,
+            _ => panic!("Unexpected system control key: {}", value),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<String> for SystemControl {
+    fn from(value: String) -> Self {
+        value.as_str().into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+impl MouseButton {
+    /// Bit for this button in a HID mouse report
+    pub fn bit(self) -> u8 {
+        match self {
+            Self::Left => 0x01,
+            Self::Right => 0x02,
+            Self::Middle => 0x04,
+            Self::Back => 0x08,
+            Self::Forward => 0x10,
+        }
+    }
+}
+
+impl From<&str> for MouseButton {
+    fn from(value: &str) -> Self {
+        match value {
+            "LCLK" => Self::Left,
+            "RCLK" => Self::Right,
+            "MCLK" => Self::Middle,
+            "BCLK" => Self::Back,
+            "FCLK" => Self::Forward,
+            _ => panic!("Unexpected mouse button: {}", value),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<String> for MouseButton {
+    fn from(value: String) -> Self {
+        value.as_str().into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl From<&str> for Direction {
+    fn from(value: &str) -> Self {
+        match value {
+            "UP" => Self::Up,
+            "DN" => Self::Down,
+            "LFT" => Self::Left,
+            "RHT" => Self::Right,
+            _ => panic!("Unexpected direction: {}", value),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<String> for Direction {
+    fn from(value: String) -> Self {
+        value.as_str().into()
+    }
+}
+
+/// Activates `then_layer` whenever all of the layers in `if_layers` are active
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConditionalLayer {
+    pub if_layers: u16, // Bit n is set for layer id n
+    pub then_layer: u32,
+}
+
+/// Keyboard LEDs the host can set, in the bit order of the HID LED report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Led {
+    NumLock,
+    CapsLock,
+    ScrollLock,
+    Compose,
+    Kana,
+}
+
+impl Led {
+    /// Bit for this LED in a HID LED report
+    pub fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl From<&str> for Led {
+    fn from(value: &str) -> Self {
+        match value {
+            "num_lock" => Self::NumLock,
+            "caps_lock" => Self::CapsLock,
+            "scroll_lock" => Self::ScrollLock,
+            "compose" => Self::Compose,
+            "kana" => Self::Kana,
+            _ => panic!("Unexpected LED: {}", value),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<String> for Led {
+    fn from(value: String) -> Self {
+        value.as_str().into()
+    }
+}
+
+/// Activates `layer` while the host has `led` on, e.g. a numpad layer on Num Lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedLayer {
+    pub led: Led,
+    pub layer: u32,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Layer {
     pub id: u32,
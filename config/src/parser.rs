@@ -0,0 +1,826 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::NUM_LAYERS;
+use crate::no_std::{
+    AutoShift, Behavior, COLS, ConditionalLayer, Config, KEYS, Key, KeyOverride, Layer, LedLayer,
+    MAX_ALT_REPEATS, MAX_CONDITIONAL_LAYERS, MAX_LED_LAYERS, MAX_OVERRIDES, Options, ROWS,
+};
+use crate::scanner::{Bracket, ScanToken};
+
+pub fn parse_config(iter: &mut VecDeque<ScanToken>) -> Config {
+    assert_eq!(
+        iter.pop_front(),
+        Some(ScanToken::Ident("options".to_owned()))
+    );
+    let options = parse_options(iter);
+
+    assert_eq!(
+        iter.pop_front(),
+        Some(ScanToken::Ident("layers".to_owned()))
+    );
+    let (layers, layer_ids) = parse_layers(iter);
+
+    let mut overrides = [None; MAX_OVERRIDES];
+    let mut alt_repeats = [None; MAX_ALT_REPEATS];
+    let mut conditional_layers = [None; MAX_CONDITIONAL_LAYERS];
+    let mut led_layers = [None; MAX_LED_LAYERS];
+
+    // Remaining sections are optional
+    while let Some(token) = iter.pop_front() {
+        match token {
+            ScanToken::Ident(section) if section == "overrides" => {
+                overrides = parse_overrides(iter);
+            }
+            ScanToken::Ident(section) if section == "alt_repeats" => {
+                alt_repeats = parse_alt_repeats(iter);
+            }
+            ScanToken::Ident(section) if section == "conditional_layers" => {
+                conditional_layers = parse_conditional_layers(iter, &layer_ids);
+            }
+            ScanToken::Ident(section) if section == "led_layers" => {
+                led_layers = parse_led_layers(iter, &layer_ids);
+            }
+            other => panic!("Unexpected section: {:?}", other),
+        }
+    }
+
+    Config {
+        options,
+        layers,
+        overrides,
+        alt_repeats,
+        conditional_layers,
+        led_layers,
+    }
+}
+
+fn parse_options(iter: &mut VecDeque<ScanToken>) -> Options {
+    let mut options = Options::default();
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Colon));
+    assert_eq!(iter.pop_front(), Some(Bracket::LCUBRK.into()));
+
+    loop {
+        match iter.pop_front() {
+            Some(ScanToken::Ident(name)) => {
+                assert_eq!(iter.pop_front(), Some(ScanToken::Colon));
+
+                let value = if let Some(ScanToken::Int(value)) = iter.pop_front() {
+                    value
+                } else {
+                    panic!("Unable to parse {} as int", name)
+                };
+
+                assert_eq!(iter.pop_front(), Some(ScanToken::Comma));
+
+                match name.as_str() {
+                    "tapping_term_ms" => options.tapping_term_ms = Some(value),
+                    "debounce_ms" => options.debounce_ms = value,
+                    // Sets all classes, so must come before the per-class options
+                    "auto_shift_ms" => {
+                        options.auto_shift = AutoShift {
+                            alpha_ms: Some(value),
+                            number_ms: Some(value),
+                            symbol_ms: Some(value),
+                        }
+                    }
+                    "auto_shift_alpha_ms" => options.auto_shift.alpha_ms = Some(value),
+                    "auto_shift_number_ms" => options.auto_shift.number_ms = Some(value),
+                    "auto_shift_symbol_ms" => options.auto_shift.symbol_ms = Some(value),
+                    "mouse_move_speed" => options.mouse_move.start_speed = value,
+                    "mouse_move_max_speed" => options.mouse_move.max_speed = value,
+                    "mouse_move_time_to_max_ms" => options.mouse_move.time_to_max_ms = value,
+                    "mouse_move_exponent" => options.mouse_move.exponent = value,
+                    "mouse_scroll_speed" => options.mouse_scroll.start_speed = value,
+                    "mouse_scroll_max_speed" => options.mouse_scroll.max_speed = value,
+                    "mouse_scroll_time_to_max_ms" => options.mouse_scroll.time_to_max_ms = value,
+                    "mouse_scroll_exponent" => options.mouse_scroll.exponent = value,
+                    // USB interfaces are switched on with 1 and off with 0
+                    "usb_mouse" => options.usb.mouse = value != 0,
+                    "usb_consumer" => options.usb.consumer = value != 0,
+                    "usb_system" => options.usb.system = value != 0,
+                    "usb_raw_hid" => options.usb.raw_hid = value != 0,
+                    "usb_serial" => options.usb.serial = value != 0,
+                    "bootmagic_key" => options.bootmagic.safe_mode = Some(key_index(value)),
+                    "bootmagic_clear_key" => options.bootmagic.clear = Some(key_index(value)),
+                    other => panic!("Unexpected option: {}", other),
+                }
+            }
+            Some(ScanToken::Bracket(Bracket::RCUBRK)) => break,
+            _ => panic!("Expected option name"),
+        }
+    }
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Semicolon));
+
+    options
+}
+
+fn key_index(value: u32) -> u8 {
+    if value as usize >= KEYS {
+        panic!("Key index {} is outside the matrix", value)
+    }
+    value as u8
+}
+
+pub struct RichLayer {
+    id: u32,
+    behaviors: [RichBehavior; (ROWS * COLS) as usize],
+}
+
+// Also returns the id assigned to each layer name, for sections that refer to layers
+fn parse_layers(
+    iter: &mut VecDeque<ScanToken>,
+) -> ([Option<Layer>; NUM_LAYERS], HashMap<String, u32>) {
+    let mut res = [(); NUM_LAYERS].map(|_| None);
+    let mut map: Vec<RichLayer> = vec![];
+    let mut name_id_map: HashMap<String, u32> = HashMap::new();
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Colon));
+    assert_eq!(iter.pop_front(), Some(Bracket::LCUBRK.into()));
+
+    loop {
+        match iter.pop_front() {
+            Some(ScanToken::Ident(name)) => {
+                assert_eq!(iter.pop_front(), Some(ScanToken::Colon));
+                assert_eq!(iter.pop_front(), Some(Bracket::LSBRK.into()));
+
+                let mut layer = RichLayer {
+                    id: map.len() as u32,
+                    behaviors: [false; (ROWS * COLS) as usize].map(|_| RichBehavior {
+                        base: Behavior::None,
+                        layer_name: None,
+                    }),
+                };
+
+                let mut i = 0;
+
+                loop {
+                    assert_eq!(iter.pop_front(), Some(Bracket::LPAREN.into()));
+
+                    layer.behaviors[i] = parse_behavior(iter);
+
+                    assert_eq!(iter.pop_front(), Some(Bracket::RPAREN.into()));
+
+                    i += 1;
+
+                    if i == KEYS {
+                        // Processed all keys, stop parsing
+                        break;
+                    }
+                }
+
+                assert_eq!(iter.pop_front(), Some(Bracket::RSBRK.into()));
+                assert_eq!(iter.pop_front(), Some(ScanToken::Comma));
+
+                name_id_map.insert(name, map.len() as u32);
+                map.push(layer);
+            }
+            Some(ScanToken::Bracket(Bracket::RCUBRK)) => {
+                break;
+            }
+            _ => panic!("Expected layer name"),
+        }
+    }
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Semicolon));
+
+    if map.len() > NUM_LAYERS {
+        panic!("Only supports up to 10 layers")
+    }
+
+    // Set up correct layer ids. Needs to be done after base processing since that's when we find
+    // out what layers they are and what id they'll have.
+    // TODO Probably lan start pre-generating layers as soon as they're referenced?
+    for layer in map.iter_mut() {
+        for behavior in layer.behaviors.iter_mut() {
+            if let Some(ref name) = behavior.layer_name {
+                if let Some(id) = name_id_map.get(name) {
+                    if let Behavior::MomentaryLayer(_) = behavior.base {
+                        behavior.base = Behavior::MomentaryLayer(*id);
+                    }
+                }
+            }
+        }
+    }
+
+    for (i, layer) in map.into_iter().enumerate() {
+        res[i] = Some(Layer {
+            id: layer.id,
+            keys: layer.behaviors.map(|rb| rb.base),
+        });
+    }
+
+    (res, name_id_map)
+}
+
+fn parse_overrides(iter: &mut VecDeque<ScanToken>) -> [Option<KeyOverride>; MAX_OVERRIDES] {
+    let mut res = [None; MAX_OVERRIDES];
+    let mut i = 0;
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Colon));
+    assert_eq!(iter.pop_front(), Some(Bracket::LSBRK.into()));
+
+    loop {
+        match iter.pop_front() {
+            Some(ScanToken::Bracket(Bracket::LPAREN)) => {
+                if i == MAX_OVERRIDES {
+                    panic!("Only supports up to {} overrides", MAX_OVERRIDES)
+                }
+
+                if let (
+                    Some(ScanToken::Ident(trigger)),
+                    Some(ScanToken::Ident(replacement)),
+                    Some(ScanToken::Ident(mods)),
+                ) = (iter.pop_front(), iter.pop_front(), iter.pop_front())
+                {
+                    res[i] = Some(KeyOverride {
+                        trigger: trigger.into(),
+                        replacement: replacement.into(),
+                        mods: mods.into(),
+                    });
+                } else {
+                    panic!("Invalid args for override")
+                }
+
+                assert_eq!(iter.pop_front(), Some(Bracket::RPAREN.into()));
+                i += 1;
+            }
+            Some(ScanToken::Bracket(Bracket::RSBRK)) => break,
+            _ => panic!("Expected override"),
+        }
+    }
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Semicolon));
+
+    res
+}
+
+fn parse_alt_repeats(iter: &mut VecDeque<ScanToken>) -> [Option<(Key, Key)>; MAX_ALT_REPEATS] {
+    let mut res = [None; MAX_ALT_REPEATS];
+    let mut i = 0;
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Colon));
+    assert_eq!(iter.pop_front(), Some(Bracket::LSBRK.into()));
+
+    loop {
+        match iter.pop_front() {
+            Some(ScanToken::Bracket(Bracket::LPAREN)) => {
+                if i == MAX_ALT_REPEATS {
+                    panic!("Only supports up to {} alt repeat pairs", MAX_ALT_REPEATS)
+                }
+
+                if let (Some(ScanToken::Ident(first)), Some(ScanToken::Ident(second))) =
+                    (iter.pop_front(), iter.pop_front())
+                {
+                    res[i] = Some((first.into(), second.into()));
+                } else {
+                    panic!("Invalid args for alt repeat pair")
+                }
+
+                assert_eq!(iter.pop_front(), Some(Bracket::RPAREN.into()));
+                i += 1;
+            }
+            Some(ScanToken::Bracket(Bracket::RSBRK)) => break,
+            _ => panic!("Expected alt repeat pair"),
+        }
+    }
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Semicolon));
+
+    res
+}
+
+fn parse_conditional_layers(
+    iter: &mut VecDeque<ScanToken>,
+    layer_ids: &HashMap<String, u32>,
+) -> [Option<ConditionalLayer>; MAX_CONDITIONAL_LAYERS] {
+    let mut res = [None; MAX_CONDITIONAL_LAYERS];
+    let mut i = 0;
+
+    let layer_id = |name: &String| match layer_ids.get(name) {
+        Some(id) => *id,
+        None => panic!("Unknown layer: {}", name),
+    };
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Colon));
+    assert_eq!(iter.pop_front(), Some(Bracket::LSBRK.into()));
+
+    loop {
+        match iter.pop_front() {
+            Some(ScanToken::Bracket(Bracket::LPAREN)) => {
+                if i == MAX_CONDITIONAL_LAYERS {
+                    panic!(
+                        "Only supports up to {} conditional layers",
+                        MAX_CONDITIONAL_LAYERS
+                    )
+                }
+
+                // All names but the last are the condition, the last is the layer to activate
+                let mut names = vec![];
+                while let Some(ScanToken::Ident(_)) = iter.front() {
+                    if let Some(ScanToken::Ident(name)) = iter.pop_front() {
+                        names.push(name);
+                    }
+                }
+
+                assert_eq!(iter.pop_front(), Some(Bracket::RPAREN.into()));
+
+                if let Some((then_layer, if_layers)) = names.split_last() {
+                    if if_layers.is_empty() {
+                        panic!("Conditional layer needs at least one condition")
+                    }
+
+                    res[i] = Some(ConditionalLayer {
+                        if_layers: if_layers
+                            .iter()
+                            .fold(0, |acc, name| acc | (1 << layer_id(name))),
+                        then_layer: layer_id(then_layer),
+                    });
+                } else {
+                    panic!("Invalid args for conditional layer")
+                }
+
+                i += 1;
+            }
+            Some(ScanToken::Bracket(Bracket::RSBRK)) => break,
+            _ => panic!("Expected conditional layer"),
+        }
+    }
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Semicolon));
+
+    res
+}
+
+fn parse_led_layers(
+    iter: &mut VecDeque<ScanToken>,
+    layer_ids: &HashMap<String, u32>,
+) -> [Option<LedLayer>; MAX_LED_LAYERS] {
+    let mut res = [None; MAX_LED_LAYERS];
+    let mut i = 0;
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Colon));
+    assert_eq!(iter.pop_front(), Some(Bracket::LSBRK.into()));
+
+    loop {
+        match iter.pop_front() {
+            Some(ScanToken::Bracket(Bracket::LPAREN)) => {
+                if i == MAX_LED_LAYERS {
+                    panic!("Only supports up to {} LED layers", MAX_LED_LAYERS)
+                }
+
+                if let (Some(ScanToken::Ident(led)), Some(ScanToken::Ident(layer))) =
+                    (iter.pop_front(), iter.pop_front())
+                {
+                    res[i] = Some(LedLayer {
+                        led: led.into(),
+                        layer: match layer_ids.get(&layer) {
+                            Some(id) => *id,
+                            None => panic!("Unknown layer: {}", layer),
+                        },
+                    });
+                } else {
+                    panic!("Invalid args for LED layer")
+                }
+
+                assert_eq!(iter.pop_front(), Some(Bracket::RPAREN.into()));
+                i += 1;
+            }
+            Some(ScanToken::Bracket(Bracket::RSBRK)) => break,
+            _ => panic!("Expected LED layer"),
+        }
+    }
+
+    assert_eq!(iter.pop_front(), Some(ScanToken::Semicolon));
+
+    res
+}
+
+/// Parse one behavior on its own, e.g. `(kp ESC)`. `layer_id` looks up layer names for `ml`.
+pub fn parse_single_behavior(
+    iter: &mut VecDeque<ScanToken>,
+    layer_id: impl Fn(&str) -> Option<u32>,
+) -> Behavior {
+    assert_eq!(iter.pop_front(), Some(Bracket::LPAREN.into()));
+    let behavior = parse_behavior(iter);
+    assert_eq!(iter.pop_front(), Some(Bracket::RPAREN.into()));
+
+    match behavior.layer_name {
+        Some(name) => match layer_id(&name) {
+            Some(id) => Behavior::MomentaryLayer(id),
+            None => panic!("Unknown layer: {}", name),
+        },
+        None => behavior.base,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct RichBehavior {
+    base: Behavior,
+    layer_name: Option<String>,
+}
+
+impl RichBehavior {
+    fn new(base: Behavior, layer_name: Option<String>) -> Self {
+        Self { base, layer_name }
+    }
+}
+
+// If the behavior has a layer arg, that will need to be converted to int after layers are parsed.
+// The second part of the return tuple holds this
+fn parse_behavior(iter: &mut VecDeque<ScanToken>) -> RichBehavior {
+    if let Some(ScanToken::Ident(behavior)) = iter.pop_front() {
+        match behavior.as_str() {
+            "kp" => {
+                if let Some(ScanToken::Ident(key)) = iter.pop_front() {
+                    // Non-keyboard keys are told apart by their prefix
+                    let base = if key.starts_with("C_") {
+                        Behavior::Consumer(key.into())
+                    } else if key.starts_with("SYS_") {
+                        Behavior::System(key.into())
+                    } else {
+                        Behavior::Key(key.into())
+                    };
+
+                    RichBehavior::new(base, None)
+                } else {
+                    panic!("Expected key name")
+                }
+            }
+            "ml" => {
+                if let Some(ScanToken::Ident(layer)) = iter.pop_front() {
+                    RichBehavior::new(Behavior::MomentaryLayer(0), Some(layer))
+                } else {
+                    panic!("Expected layer name")
+                }
+            }
+            "ht" => {
+                if let (Some(ScanToken::Ident(hold)), Some(ScanToken::Ident(tap))) =
+                    (iter.pop_front(), iter.pop_front())
+                {
+                    RichBehavior::new(Behavior::HoldTap(hold.into(), tap.into()), None)
+                } else {
+                    panic!("Invalid args for ht behavior")
+                }
+            }
+            "mm" => {
+                if let (
+                    Some(ScanToken::Ident(base)),
+                    Some(ScanToken::Ident(morphed)),
+                    Some(ScanToken::Ident(mods)),
+                ) = (iter.pop_front(), iter.pop_front(), iter.pop_front())
+                {
+                    RichBehavior::new(
+                        Behavior::ModMorph(base.into(), morphed.into(), mods.into()),
+                        None,
+                    )
+                } else {
+                    panic!("Invalid args for mm behavior")
+                }
+            }
+            "mkp" => {
+                if let Some(ScanToken::Ident(button)) = iter.pop_front() {
+                    RichBehavior::new(Behavior::MouseButton(button.into()), None)
+                } else {
+                    panic!("Expected mouse button")
+                }
+            }
+            "mmv" => {
+                if let Some(ScanToken::Ident(direction)) = iter.pop_front() {
+                    RichBehavior::new(Behavior::MouseMove(direction.into()), None)
+                } else {
+                    panic!("Expected mouse move direction")
+                }
+            }
+            "msc" => {
+                if let Some(ScanToken::Ident(direction)) = iter.pop_front() {
+                    RichBehavior::new(Behavior::MouseScroll(direction.into()), None)
+                } else {
+                    panic!("Expected mouse scroll direction")
+                }
+            }
+            "rep" => RichBehavior::new(Behavior::Repeat, None),
+            "arep" => RichBehavior::new(Behavior::AltRepeat, None),
+            "t" => RichBehavior::new(Behavior::Transparent, None),
+            "n" => RichBehavior::new(Behavior::None, None),
+            other => panic!("Unexpected behavior ident: {}", other),
+        }
+    } else {
+        panic!("Unexpected value, expected behavior specifier");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        RichBehavior, parse_alt_repeats, parse_behavior, parse_conditional_layers, parse_config,
+        parse_layers, parse_led_layers, parse_options, parse_overrides, parse_single_behavior,
+    };
+    use crate::{
+        no_std::{
+            AutoShift, Behavior, Bootmagic, COLS, ConditionalLayer, Config, Consumer, Direction,
+            KEYS, Key, KeyOverride, Layer, Led, LedLayer, MAX_ALT_REPEATS, MAX_CONDITIONAL_LAYERS,
+            MAX_LED_LAYERS, MAX_OVERRIDES, Mods, MouseCurve, Options, ROWS, SystemControl,
+            UsbOptions,
+        },
+        scanner::scan_input,
+    };
+
+    #[test]
+    fn test_parse_behavior() {
+        let e1 = RichBehavior {
+            base: Behavior::Transparent,
+            layer_name: None,
+        };
+        let e2 = RichBehavior {
+            base: Behavior::MomentaryLayer(0),
+            layer_name: Some("TestLayer".to_owned()),
+        };
+        let e3 = RichBehavior {
+            base: Behavior::Key(Key::B),
+            layer_name: None,
+        };
+
+        let e4 = RichBehavior {
+            base: Behavior::ModMorph(Key::BKSP, Key::DEL, Mods::SHIFT),
+            layer_name: None,
+        };
+        let e5 = RichBehavior {
+            base: Behavior::MouseMove(Direction::Left),
+            layer_name: None,
+        };
+        let e6 = RichBehavior {
+            base: Behavior::Consumer(Consumer::VolUp),
+            layer_name: None,
+        };
+        let e7 = RichBehavior {
+            base: Behavior::System(SystemControl::Sleep),
+            layer_name: None,
+        };
+
+        let mut s1 = "t".bytes();
+        let mut s2 = "ml TestLayer".bytes();
+        let mut s3 = "kp B".bytes();
+        let mut s4 = "mm BKSP DEL shift".bytes();
+        let mut s5 = "mmv LFT".bytes();
+        let mut s6 = "kp C_VOL_UP".bytes();
+        let mut s7 = "kp SYS_SLEEP".bytes();
+
+        let mut t1 = scan_input(&mut s1.collect());
+        let mut t2 = scan_input(&mut s2.collect());
+        let mut t3 = scan_input(&mut s3.collect());
+        let mut t4 = scan_input(&mut s4.collect());
+        let mut t5 = scan_input(&mut s5.collect());
+        let mut t6 = scan_input(&mut s6.collect());
+        let mut t7 = scan_input(&mut s7.collect());
+
+        assert_eq!(e1, parse_behavior(&mut t1));
+        assert_eq!(e2, parse_behavior(&mut t2));
+        assert_eq!(e3, parse_behavior(&mut t3));
+        assert_eq!(e4, parse_behavior(&mut t4));
+        assert_eq!(e5, parse_behavior(&mut t5));
+        assert_eq!(e6, parse_behavior(&mut t6));
+        assert_eq!(e7, parse_behavior(&mut t7));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let e1 = Options {
+            tapping_term_ms: Some(150),
+            debounce_ms: 8,
+            ..Options::default()
+        };
+        let e2 = Options {
+            auto_shift: AutoShift {
+                alpha_ms: Some(175),
+                number_ms: Some(175),
+                symbol_ms: Some(250),
+            },
+            ..Options::default()
+        };
+        let e3 = Options {
+            mouse_move: MouseCurve {
+                max_speed: 2000,
+                exponent: 3,
+                ..MouseCurve::MOVE
+            },
+            usb: UsbOptions {
+                mouse: false,
+                serial: true,
+                ..UsbOptions::default()
+            },
+            bootmagic: Bootmagic {
+                safe_mode: Some(5),
+                clear: Some(23),
+            },
+            ..Options::default()
+        };
+
+        let mut s1 = ": {
+            tapping_term_ms: 150,
+            debounce_ms: 8,
+        };"
+        .bytes();
+        let mut s2 = ": {
+            auto_shift_ms: 175,
+            auto_shift_symbol_ms: 250,
+        };"
+        .bytes();
+
+        let mut s3 = ": {
+            mouse_move_max_speed: 2000,
+            mouse_move_exponent: 3,
+            usb_mouse: 0,
+            usb_serial: 1,
+            bootmagic_key: 5,
+            bootmagic_clear_key: 23,
+        };"
+        .bytes();
+
+        let mut t1 = scan_input(&mut s1.collect());
+        let mut t2 = scan_input(&mut s2.collect());
+        let mut t3 = scan_input(&mut s3.collect());
+
+        assert_eq!(e1, parse_options(&mut t1));
+        assert_eq!(e2, parse_options(&mut t2));
+        assert_eq!(e3, parse_options(&mut t3));
+    }
+
+    #[test]
+    fn test_parse_layers() {
+        let e1 = [
+            Some(Layer {
+                id: 0,
+                keys: [Behavior::Key(Key::BKSP); (ROWS * COLS) as usize],
+            }),
+            Some(Layer {
+                id: 1,
+                keys: [Behavior::MomentaryLayer(2); (ROWS * COLS) as usize],
+            }),
+            Some(Layer {
+                id: 2,
+                keys: [Behavior::Transparent; (ROWS * COLS) as usize],
+            }),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ];
+
+        let mut s1 = ": { BASE: [".to_owned();
+        s1.push_str(["(kp BKSP)"; (ROWS * COLS) as usize].join(" ").as_str());
+        s1.push_str("], RAISE: [");
+
+        s1.push_str(["(ml LOWER)"; (ROWS * COLS) as usize].join(" ").as_str());
+        s1.push_str("], LOWER: [");
+
+        s1.push_str(["(t)"; (ROWS * COLS) as usize].join(" ").as_str());
+        s1.push_str("],};");
+
+        let mut t1 = scan_input(&mut s1.bytes().collect());
+
+        assert_eq!(e1, parse_layers(&mut t1).0);
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let mut e1 = [None; MAX_OVERRIDES];
+        e1[0] = Some(KeyOverride {
+            trigger: Key::COMM,
+            replacement: Key::SCLN,
+            mods: Mods::SHIFT,
+        });
+        e1[1] = Some(KeyOverride {
+            trigger: Key::BKSP,
+            replacement: Key::DEL,
+            mods: Mods::CTRL,
+        });
+
+        let s1 = ": [(COMM SCLN shift) (BKSP DEL ctrl)];";
+        let mut t1 = scan_input(&mut s1.bytes().collect());
+
+        assert_eq!(e1, parse_overrides(&mut t1));
+    }
+
+    #[test]
+    fn test_parse_alt_repeats() {
+        let mut e1 = [None; MAX_ALT_REPEATS];
+        e1[0] = Some((Key::LFT, Key::RHT));
+        e1[1] = Some((Key::Z, Key::Y));
+
+        let s1 = ": [(LFT RHT) (Z Y)];";
+        let mut t1 = scan_input(&mut s1.bytes().collect());
+
+        assert_eq!(e1, parse_alt_repeats(&mut t1));
+    }
+
+    #[test]
+    fn test_parse_conditional_layers() {
+        let mut e1 = [None; MAX_CONDITIONAL_LAYERS];
+        e1[0] = Some(ConditionalLayer {
+            if_layers: 0b0110,
+            then_layer: 3,
+        });
+
+        let layer_ids = [("BASE", 0), ("LOWER", 1), ("RAISE", 2), ("ADJUST", 3)]
+            .map(|(name, id)| (name.to_owned(), id))
+            .into();
+
+        let s1 = ": [(LOWER RAISE ADJUST)];";
+        let mut t1 = scan_input(&mut s1.bytes().collect());
+
+        assert_eq!(e1, parse_conditional_layers(&mut t1, &layer_ids));
+    }
+
+    #[test]
+    fn test_parse_single_behavior() {
+        let layer_id = |name: &str| (name == "NUM").then_some(3);
+
+        let s1 = "(kp ESC)";
+        let mut t1 = scan_input(&mut s1.bytes().collect());
+        assert_eq!(
+            parse_single_behavior(&mut t1, layer_id),
+            Behavior::Key(Key::ESC)
+        );
+
+        let s2 = "(ml NUM)";
+        let mut t2 = scan_input(&mut s2.bytes().collect());
+        assert_eq!(
+            parse_single_behavior(&mut t2, layer_id),
+            Behavior::MomentaryLayer(3)
+        );
+    }
+
+    #[test]
+    fn test_parse_led_layers() {
+        let mut e1 = [None; MAX_LED_LAYERS];
+        e1[0] = Some(LedLayer {
+            led: Led::NumLock,
+            layer: 1,
+        });
+        e1[1] = Some(LedLayer {
+            led: Led::ScrollLock,
+            layer: 0,
+        });
+
+        let layer_ids = [("BASE", 0), ("NUMPAD", 1)]
+            .map(|(name, id)| (name.to_owned(), id))
+            .into();
+
+        let s1 = ": [(num_lock NUMPAD) (scroll_lock BASE)];";
+        let mut t1 = scan_input(&mut s1.bytes().collect());
+
+        assert_eq!(e1, parse_led_layers(&mut t1, &layer_ids));
+    }
+
+    #[test]
+
+    fn test_parse_config() {
+        let mut behaviors: [Behavior; KEYS] = [Behavior::None; KEYS];
+        behaviors[0] = Behavior::Transparent;
+        behaviors[6] = Behavior::HoldTap(Key::A, Key::LCTL);
+        behaviors[12] = Behavior::Key(Key::B);
+
+        let e1 = Config {
+            options: Options {
+                tapping_term_ms: Some(100),
+                ..Options::default()
+            },
+            layers: [
+                Some(Layer {
+                    id: 0,
+                    keys: behaviors,
+                }),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            ],
+            overrides: [None; MAX_OVERRIDES],
+            alt_repeats: [None; MAX_ALT_REPEATS],
+            conditional_layers: [None; MAX_CONDITIONAL_LAYERS],
+            led_layers: [None; MAX_LED_LAYERS],
+        };
+
+        let s1 = "options: {tapping_term_ms: 100,}; layers: {BASE: [
+                    (t)         (n)(n)(n)(n)(n)
+                    (ht A LCTL) (n)(n)(n)(n)(n)
+                    (kp B)      (n)(n)(n)(n)(n)
+                    (n)         (n)(n)(n)(n)(n)],
+                };";
+        let mut t1 = scan_input(&mut s1.bytes().collect());
+
+        let c1 = parse_config(&mut t1);
+
+        assert_eq!(c1, e1);
+    }
+}
@@ -0,0 +1,203 @@
+//! The keymap partition, a region of flash holding a keymap that is written separately from the
+//! firmware, so a new keymap can be dragged onto the boot drive as a UF2
+//!
+//! The partition starts with a header of a magic number, the format version, the data length and
+//! the CRC of the data, followed by the whole config in the binary form from
+//! `protocol::encode_config`.
+
+use crate::crc::crc32;
+use crate::no_std::Config;
+use crate::protocol::{CONFIG_LEN, DecodeError, decode_config, encode_config};
+
+/// Address of the partition in the RP2040's flash, memory.x keeps the firmware out of it
+pub const KEYMAP_ADDR: u32 = 0x101E_F000;
+/// Size of the partition, one flash sector
+pub const PARTITION_LEN: usize = 4096;
+
+const MAGIC: u32 = 0x4B42_4B4D;
+const FORMAT_VERSION: u16 = 4;
+const HEADER_LEN: usize = 12;
+/// Bytes of the partition in use, header included
+pub const IMAGE_LEN: usize = HEADER_LEN + CONFIG_LEN;
+
+const _: () = assert!(IMAGE_LEN <= PARTITION_LEN);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    /// Nothing was ever written, or it isn't a keymap
+    Missing,
+    UnsupportedVersion(u16),
+    BadLength,
+    BadCrc,
+    Decode(DecodeError),
+}
+
+pub fn encode_partition(config: &Config) -> [u8; IMAGE_LEN] {
+    let mut image = [0; IMAGE_LEN];
+    let data = encode_config(config);
+
+    image[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    image[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    image[6..8].copy_from_slice(&(CONFIG_LEN as u16).to_le_bytes());
+    image[8..12].copy_from_slice(&crc32(&data).to_le_bytes());
+    image[HEADER_LEN..].copy_from_slice(&data);
+    image
+}
+
+/// The config in a partition along with its CRC, which identifies the keymap
+pub fn decode_partition(partition: &[u8]) -> Result<(Config, u32), PartitionError> {
+    if partition.len() < HEADER_LEN
+        || u32::from_le_bytes(partition[0..4].try_into().unwrap()) != MAGIC
+    {
+        return Err(PartitionError::Missing);
+    }
+
+    let version = u16::from_le_bytes([partition[4], partition[5]]);
+    if version != FORMAT_VERSION {
+        return Err(PartitionError::UnsupportedVersion(version));
+    }
+
+    let len = u16::from_le_bytes([partition[6], partition[7]]) as usize;
+    if len != CONFIG_LEN || partition.len() < HEADER_LEN + len {
+        return Err(PartitionError::BadLength);
+    }
+
+    let data = &partition[HEADER_LEN..HEADER_LEN + len];
+    let crc = u32::from_le_bytes(partition[8..12].try_into().unwrap());
+    if crc32(data) != crc {
+        return Err(PartitionError::BadCrc);
+    }
+
+    let config = decode_config(data).map_err(PartitionError::Decode)?;
+    Ok((config, crc))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::NUM_LAYERS;
+    use crate::crc::crc32;
+    use crate::no_std::{
+        AutoShift, Behavior, Bootmagic, ConditionalLayer, Config, KEYS, Key, KeyOverride, Layer,
+        Led, LedLayer, MAX_ALT_REPEATS, MAX_CONDITIONAL_LAYERS, MAX_LED_LAYERS, MAX_OVERRIDES,
+        Mods, MouseCurve, Options, UsbOptions,
+    };
+    use crate::partition::{
+        HEADER_LEN, IMAGE_LEN, PARTITION_LEN, PartitionError, decode_partition, encode_partition,
+    };
+    use crate::protocol::{DecodeError, encode_config};
+
+    /// A config with something other than the defaults in every section
+    fn config() -> Config {
+        let mut layers = [(); NUM_LAYERS].map(|_| None);
+        layers[0] = Some(Layer {
+            id: 0,
+            keys: [Behavior::Key(Key::Q); KEYS],
+        });
+        layers[1] = Some(Layer {
+            id: 1,
+            keys: [Behavior::Transparent; KEYS],
+        });
+
+        let mut overrides = [None; MAX_OVERRIDES];
+        overrides[0] = Some(KeyOverride {
+            trigger: Key::COMM,
+            replacement: Key::SCLN,
+            mods: Mods::SHIFT,
+        });
+        let mut alt_repeats = [None; MAX_ALT_REPEATS];
+        alt_repeats[3] = Some((Key::LPRN, Key::RPRN));
+        let mut conditional_layers = [None; MAX_CONDITIONAL_LAYERS];
+        conditional_layers[0] = Some(ConditionalLayer {
+            if_layers: 0b110,
+            then_layer: 3,
+        });
+        let mut led_layers = [None; MAX_LED_LAYERS];
+        led_layers[0] = Some(LedLayer {
+            led: Led::NumLock,
+            layer: 1,
+        });
+
+        Config {
+            options: Options {
+                tapping_term_ms: Some(180),
+                debounce_ms: 8,
+                auto_shift: AutoShift {
+                    alpha_ms: Some(150),
+                    number_ms: None,
+                    symbol_ms: Some(0),
+                },
+                mouse_move: MouseCurve::SCROLL,
+                mouse_scroll: MouseCurve::MOVE,
+                usb: UsbOptions {
+                    mouse: false,
+                    consumer: true,
+                    system: false,
+                    raw_hid: true,
+                    serial: true,
+                },
+                bootmagic: Bootmagic {
+                    safe_mode: None,
+                    clear: Some(23),
+                },
+            },
+            layers,
+            overrides,
+            alt_repeats,
+            conditional_layers,
+            led_layers,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let image = encode_partition(&config());
+        let crc = crc32(&encode_config(&config()));
+        assert_eq!(decode_partition(&image), Ok((config(), crc)));
+
+        // Whatever follows the image in the partition is ignored
+        let mut partition = [0xFF; PARTITION_LEN];
+        partition[..IMAGE_LEN].copy_from_slice(&image);
+        assert_eq!(decode_partition(&partition), Ok((config(), crc)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let image = encode_partition(&config());
+
+        assert_eq!(
+            decode_partition(&[0xFF; PARTITION_LEN]),
+            Err(PartitionError::Missing)
+        );
+        assert_eq!(decode_partition(&[]), Err(PartitionError::Missing));
+
+        // Images from before the partition held the whole config
+        let mut bad = image;
+        bad[4] = 1;
+        assert_eq!(
+            decode_partition(&bad),
+            Err(PartitionError::UnsupportedVersion(1))
+        );
+
+        assert_eq!(
+            decode_partition(&image[..IMAGE_LEN - 1]),
+            Err(PartitionError::BadLength)
+        );
+
+        let mut bad = image;
+        bad[HEADER_LEN + 7] ^= 1;
+        assert_eq!(decode_partition(&bad), Err(PartitionError::BadCrc));
+
+        // Configs this firmware can't decode, with a CRC that checks out, in the keymap and
+        // after it
+        for at in [HEADER_LEN + 2, IMAGE_LEN - MAX_LED_LAYERS * 3 + 1] {
+            let mut bad = image;
+            bad[at] = 0xFF;
+            let crc = crc32(&bad[HEADER_LEN..]);
+            bad[8..12].copy_from_slice(&crc.to_le_bytes());
+            assert_eq!(
+                decode_partition(&bad),
+                Err(PartitionError::Decode(DecodeError::InvalidValue))
+            );
+        }
+    }
+}
@@ -0,0 +1,1265 @@
+//! Raw HID command protocol, shared by the firmware and host tools
+//!
+//! Every packet is `REPORT_LEN` bytes. Requests are `[version, command, args..]`, responses echo
+//! the command as `[version, command, status, payload..]`. The protocol info command is answered
+//! whatever the version, so a host can always find out what the keyboard speaks.
+
+use crate::NUM_LAYERS;
+use crate::no_std::{
+    AutoShift, Behavior, Bootmagic, COLS, ConditionalLayer, Config, Consumer, Direction, KEYS, Key,
+    KeyOverride, Layer, Led, LedLayer, MAX_ALT_REPEATS, MAX_CONDITIONAL_LAYERS, MAX_LED_LAYERS,
+    MAX_OVERRIDES, Mods, MouseButton, MouseCurve, Options, ROWS, SystemControl, UsbOptions,
+};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Size of every packet, the same as a raw HID report
+pub const REPORT_LEN: usize = 32;
+/// Bytes in a matrix state response, bit `i % 8` of byte `i / 8` is set while key `i` is down
+pub const MATRIX_BYTES: usize = KEYS.div_ceil(8);
+
+pub type Packet = [u8; REPORT_LEN];
+
+/// Bytes in an encoded behavior
+const BEHAVIOR_LEN: usize = 4;
+/// Bytes in a binary keymap, a mask of the defined layers followed by every key of every layer
+pub const KEYMAP_LEN: usize = 2 + NUM_LAYERS * KEYS * BEHAVIOR_LEN;
+/// Bytes in the options of a binary config, four optional timeouts, the debounce time, two mouse
+/// curves, the USB interfaces and the two bootmagic keys
+const OPTIONS_LEN: usize = 4 * 5 + 4 + 2 * 16 + 1 + 2 * 2;
+/// Bytes in a binary config, the keymap followed by the options and the other sections. Entries
+/// of the sections start with a byte that is 1 if they are there, and are zero otherwise.
+pub const CONFIG_LEN: usize = KEYMAP_LEN
+    + OPTIONS_LEN
+    + MAX_OVERRIDES * 4
+    + MAX_ALT_REPEATS * 3
+    + MAX_CONDITIONAL_LAYERS * 4
+    + MAX_LED_LAYERS * 3;
+
+// Keys in declaration order, so `key as u8` is an index into this
+const KEY_CODES: [Key; 67] = [
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+    Key::ESC,
+    Key::LCTL,
+    Key::LSFT,
+    Key::LGUI,
+    Key::LALT,
+    Key::BKSP,
+    Key::TAB,
+    Key::SPC,
+    Key::N0,
+    Key::N1,
+    Key::N2,
+    Key::N3,
+    Key::N4,
+    Key::N5,
+    Key::N6,
+    Key::N7,
+    Key::N8,
+    Key::N9,
+    Key::RET,
+    Key::DEL,
+    Key::MNS,
+    Key::EQL,
+    Key::BSLH,
+    Key::FSLH,
+    Key::LPRN,
+    Key::RPRN,
+    Key::LSBR,
+    Key::RSBR,
+    Key::LCBR,
+    Key::RCBR,
+    Key::QUOT,
+    Key::UP,
+    Key::LFT,
+    Key::RHT,
+    Key::DN,
+    Key::COMM,
+    Key::DOT,
+    Key::SCLN,
+    Key::CAPS,
+    Key::NLCK,
+    Key::SLCK,
+];
+
+const MOUSE_BUTTON_CODES: [MouseButton; 5] = [
+    MouseButton::Left,
+    MouseButton::Right,
+    MouseButton::Middle,
+    MouseButton::Back,
+    MouseButton::Forward,
+];
+
+const DIRECTION_CODES: [Direction; 4] = [
+    Direction::Up,
+    Direction::Down,
+    Direction::Left,
+    Direction::Right,
+];
+
+const CONSUMER_CODES: [Consumer; 9] = [
+    Consumer::VolUp,
+    Consumer::VolDown,
+    Consumer::Mute,
+    Consumer::PlayPause,
+    Consumer::Next,
+    Consumer::Prev,
+    Consumer::Stop,
+    Consumer::BriUp,
+    Consumer::BriDown,
+];
+
+const SYSTEM_CODES: [SystemControl; 3] = [
+    SystemControl::PowerDown,
+    SystemControl::Sleep,
+    SystemControl::Wake,
+];
+
+const LED_CODES: [Led; 5] = [
+    Led::NumLock,
+    Led::CapsLock,
+    Led::ScrollLock,
+    Led::Compose,
+    Led::Kana,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    UnknownCommand(u8),
+    UnknownStatus(u8),
+    // A field holds a value that doesn't map to anything
+    InvalidValue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    ProtocolInfo = 0x01,
+    MatrixSize = 0x02,
+    GetBehavior = 0x03,
+    SetBehavior = 0x04,
+    GetLayers = 0x05,
+    SetLayers = 0x06,
+    MatrixState = 0x07,
+    Bootloader = 0x08,
+    BootStatus = 0x09,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::ProtocolInfo),
+            0x02 => Ok(Self::MatrixSize),
+            0x03 => Ok(Self::GetBehavior),
+            0x04 => Ok(Self::SetBehavior),
+            0x05 => Ok(Self::GetLayers),
+            0x06 => Ok(Self::SetLayers),
+            0x07 => Ok(Self::MatrixState),
+            0x08 => Ok(Self::Bootloader),
+            0x09 => Ok(Self::BootStatus),
+            _ => Err(DecodeError::UnknownCommand(value)),
+        }
+    }
+}
+
+/// Whether a request worked, sent in every response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0x00,
+    UnknownCommand = 0x01,
+    UnsupportedVersion = 0x02,
+    InvalidArgs = 0x03,
+    // Layer, row or column out of range, or the layer isn't defined in the keymap
+    InvalidPosition = 0x04,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Ok),
+            0x01 => Ok(Self::UnknownCommand),
+            0x02 => Ok(Self::UnsupportedVersion),
+            0x03 => Ok(Self::InvalidArgs),
+            0x04 => Ok(Self::InvalidPosition),
+            _ => Err(DecodeError::UnknownStatus(value)),
+        }
+    }
+}
+
+/// Why the keyboard came up in safe mode, with its minimal built-in keymap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafeModeReason {
+    // The keymap partition or the keymap saved in flash didn't pass validation
+    InvalidKeymap = 0x01,
+    // The firmware panicked on the previous boot
+    Panicked = 0x02,
+    // The bootmagic key was held at power on
+    Bootmagic = 0x03,
+}
+
+impl TryFrom<u8> for SafeModeReason {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::InvalidKeymap),
+            0x02 => Ok(Self::Panicked),
+            0x03 => Ok(Self::Bootmagic),
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+}
+
+/// A key on a layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub layer: u8,
+    pub row: u8,
+    pub col: u8,
+}
+
+impl Position {
+    /// The layer and key index, if they are in range
+    pub fn index(self) -> Option<(usize, usize)> {
+        let (layer, row, col) = (self.layer as usize, self.row as usize, self.col as usize);
+
+        if layer < NUM_LAYERS && row < ROWS && col < COLS {
+            Some((layer, COLS * row + col))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    ProtocolInfo,
+    MatrixSize,
+    GetBehavior(Position),
+    SetBehavior(Position, Behavior),
+    GetLayers,
+    // Layers to keep on, on top of the ones held or switched on by the keymap
+    SetLayers(u16),
+    MatrixState,
+    Bootloader,
+    BootStatus,
+}
+
+impl Request {
+    pub fn command(&self) -> Command {
+        match self {
+            Self::ProtocolInfo => Command::ProtocolInfo,
+            Self::MatrixSize => Command::MatrixSize,
+            Self::GetBehavior(_) => Command::GetBehavior,
+            Self::SetBehavior(..) => Command::SetBehavior,
+            Self::GetLayers => Command::GetLayers,
+            Self::SetLayers(_) => Command::SetLayers,
+            Self::MatrixState => Command::MatrixState,
+            Self::Bootloader => Command::Bootloader,
+            Self::BootStatus => Command::BootStatus,
+        }
+    }
+
+    pub fn encode(&self) -> Packet {
+        let mut packet = [0; REPORT_LEN];
+        packet[0] = PROTOCOL_VERSION;
+        packet[1] = self.command() as u8;
+
+        match self {
+            Self::GetBehavior(pos) => encode_position(*pos, &mut packet[2..5]),
+            Self::SetBehavior(pos, behavior) => {
+                encode_position(*pos, &mut packet[2..5]);
+                encode_behavior(*behavior, &mut packet[5..9]);
+            }
+            Self::SetLayers(layers) => packet[2..4].copy_from_slice(&layers.to_le_bytes()),
+            Self::ProtocolInfo
+            | Self::MatrixSize
+            | Self::GetLayers
+            | Self::MatrixState
+            | Self::Bootloader
+            | Self::BootStatus => {}
+        }
+
+        packet
+    }
+
+    pub fn decode(packet: &Packet) -> Result<Self, DecodeError> {
+        let command = Command::try_from(packet[1])?;
+
+        if command != Command::ProtocolInfo && packet[0] != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(packet[0]));
+        }
+
+        Ok(match command {
+            Command::ProtocolInfo => Self::ProtocolInfo,
+            Command::MatrixSize => Self::MatrixSize,
+            Command::GetBehavior => Self::GetBehavior(decode_position(&packet[2..5])),
+            Command::SetBehavior => Self::SetBehavior(
+                decode_position(&packet[2..5]),
+                decode_behavior(&packet[5..9])?,
+            ),
+            Command::GetLayers => Self::GetLayers,
+            Command::SetLayers => Self::SetLayers(u16::from_le_bytes([packet[2], packet[3]])),
+            Command::MatrixState => Self::MatrixState,
+            Command::Bootloader => Self::Bootloader,
+            Command::BootStatus => Self::BootStatus,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    ProtocolInfo { version: u8 },
+    MatrixSize { rows: u8, cols: u8, layers: u8 },
+    Behavior(Behavior),
+    Layers(u16),
+    MatrixState([u8; MATRIX_BYTES]),
+    // `None` for a normal boot
+    BootStatus(Option<SafeModeReason>),
+    // Success for commands with nothing to report
+    Done,
+    Error(Status),
+}
+
+impl Response {
+    /// Encode as the response to `command`, which is the raw byte so unknown commands can be
+    /// echoed back
+    pub fn encode(&self, command: u8) -> Packet {
+        let mut packet = [0; REPORT_LEN];
+        packet[0] = PROTOCOL_VERSION;
+        packet[1] = command;
+        packet[2] = Status::Ok as u8;
+
+        match self {
+            Self::ProtocolInfo { version } => packet[3] = *version,
+            Self::MatrixSize { rows, cols, layers } => {
+                packet[3..6].copy_from_slice(&[*rows, *cols, *layers])
+            }
+            Self::Behavior(behavior) => encode_behavior(*behavior, &mut packet[3..7]),
+            Self::Layers(layers) => packet[3..5].copy_from_slice(&layers.to_le_bytes()),
+            Self::MatrixState(state) => packet[3..3 + MATRIX_BYTES].copy_from_slice(state),
+            Self::BootStatus(reason) => packet[3] = reason.map_or(0, |r| r as u8),
+            Self::Done => {}
+            Self::Error(status) => packet[2] = *status as u8,
+        }
+
+        packet
+    }
+
+    /// Decode a response, returning the command it answers along with it
+    pub fn decode(packet: &Packet) -> Result<(Command, Self), DecodeError> {
+        let command = Command::try_from(packet[1])?;
+        let status = Status::try_from(packet[2])?;
+
+        if status != Status::Ok {
+            return Ok((command, Self::Error(status)));
+        }
+
+        if command != Command::ProtocolInfo && packet[0] != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(packet[0]));
+        }
+
+        let response = match command {
+            Command::ProtocolInfo => Self::ProtocolInfo { version: packet[3] },
+            Command::MatrixSize => Self::MatrixSize {
+                rows: packet[3],
+                cols: packet[4],
+                layers: packet[5],
+            },
+            Command::GetBehavior => Self::Behavior(decode_behavior(&packet[3..7])?),
+            Command::GetLayers => Self::Layers(u16::from_le_bytes([packet[3], packet[4]])),
+            Command::MatrixState => {
+                let mut state = [0; MATRIX_BYTES];
+                state.copy_from_slice(&packet[3..3 + MATRIX_BYTES]);
+                Self::MatrixState(state)
+            }
+            Command::BootStatus => Self::BootStatus(match packet[3] {
+                0 => None,
+                reason => Some(SafeModeReason::try_from(reason)?),
+            }),
+            Command::SetBehavior | Command::SetLayers | Command::Bootloader => Self::Done,
+        };
+
+        Ok((command, response))
+    }
+}
+
+fn encode_position(pos: Position, buf: &mut [u8]) {
+    buf.copy_from_slice(&[pos.layer, pos.row, pos.col]);
+}
+
+fn decode_position(buf: &[u8]) -> Position {
+    Position {
+        layer: buf[0],
+        row: buf[1],
+        col: buf[2],
+    }
+}
+
+/// Behaviors take 4 bytes, a tag followed by up to 3 arguments
+fn encode_behavior(behavior: Behavior, buf: &mut [u8]) {
+    let bytes = match behavior {
+        Behavior::None => [0x00, 0, 0, 0],
+        Behavior::Transparent => [0x01, 0, 0, 0],
+        Behavior::Key(key) => [0x02, key as u8, 0, 0],
+        Behavior::MomentaryLayer(layer) => [0x03, layer as u8, 0, 0],
+        Behavior::HoldTap(hold, tap) => [0x04, hold as u8, tap as u8, 0],
+        Behavior::ModMorph(base, morphed, mods) => [0x05, base as u8, morphed as u8, mods.0],
+        Behavior::Repeat => [0x06, 0, 0, 0],
+        Behavior::AltRepeat => [0x07, 0, 0, 0],
+        Behavior::MouseButton(button) => [0x08, button as u8, 0, 0],
+        Behavior::MouseMove(direction) => [0x09, direction as u8, 0, 0],
+        Behavior::MouseScroll(direction) => [0x0A, direction as u8, 0, 0],
+        Behavior::Consumer(consumer) => [0x0B, consumer as u8, 0, 0],
+        Behavior::System(system) => [0x0C, system as u8, 0, 0],
+    };
+
+    buf.copy_from_slice(&bytes);
+}
+
+fn decode_behavior(buf: &[u8]) -> Result<Behavior, DecodeError> {
+    let key = |code: u8| lookup(&KEY_CODES, code);
+
+    Ok(match buf[0] {
+        0x00 => Behavior::None,
+        0x01 => Behavior::Transparent,
+        0x02 => Behavior::Key(key(buf[1])?),
+        0x03 if (buf[1] as usize) < NUM_LAYERS => Behavior::MomentaryLayer(buf[1] as u32),
+        0x04 => Behavior::HoldTap(key(buf[1])?, key(buf[2])?),
+        0x05 => Behavior::ModMorph(key(buf[1])?, key(buf[2])?, Mods(buf[3])),
+        0x06 => Behavior::Repeat,
+        0x07 => Behavior::AltRepeat,
+        0x08 => Behavior::MouseButton(lookup(&MOUSE_BUTTON_CODES, buf[1])?),
+        0x09 => Behavior::MouseMove(lookup(&DIRECTION_CODES, buf[1])?),
+        0x0A => Behavior::MouseScroll(lookup(&DIRECTION_CODES, buf[1])?),
+        0x0B => Behavior::Consumer(lookup(&CONSUMER_CODES, buf[1])?),
+        0x0C => Behavior::System(lookup(&SYSTEM_CODES, buf[1])?),
+        _ => return Err(DecodeError::InvalidValue),
+    })
+}
+
+/// Binary form of a keymap's layers, for keeping them in flash. Layers are stored by id, keys of
+/// undefined layers are zero.
+pub fn encode_keymap(layers: &[Option<Layer>; NUM_LAYERS]) -> [u8; KEYMAP_LEN] {
+    let mut buf = [0; KEYMAP_LEN];
+    let mut mask = 0u16;
+
+    for layer in layers.iter().flatten() {
+        mask |= 1 << layer.id;
+
+        let start = 2 + layer.id as usize * KEYS * BEHAVIOR_LEN;
+        for (pos, behavior) in layer.keys.iter().enumerate() {
+            let at = start + pos * BEHAVIOR_LEN;
+            encode_behavior(*behavior, &mut buf[at..at + BEHAVIOR_LEN]);
+        }
+    }
+
+    buf[..2].copy_from_slice(&mask.to_le_bytes());
+    buf
+}
+
+pub fn decode_keymap(buf: &[u8]) -> Result<[Option<Layer>; NUM_LAYERS], DecodeError> {
+    if buf.len() != KEYMAP_LEN {
+        return Err(DecodeError::InvalidValue);
+    }
+
+    let mask = u16::from_le_bytes([buf[0], buf[1]]);
+    if mask >> NUM_LAYERS != 0 {
+        return Err(DecodeError::InvalidValue);
+    }
+
+    let mut layers = [(); NUM_LAYERS].map(|_| None);
+
+    for (id, layer) in layers.iter_mut().enumerate() {
+        if mask & (1 << id) == 0 {
+            continue;
+        }
+
+        let start = 2 + id * KEYS * BEHAVIOR_LEN;
+        let mut keys = [Behavior::None; KEYS];
+        for (pos, key) in keys.iter_mut().enumerate() {
+            let at = start + pos * BEHAVIOR_LEN;
+            *key = decode_behavior(&buf[at..at + BEHAVIOR_LEN])?;
+        }
+
+        *layer = Some(Layer {
+            id: id as u32,
+            keys,
+        });
+    }
+
+    Ok(layers)
+}
+
+/// Binary form of a whole config, for the keymap partition
+pub fn encode_config(config: &Config) -> [u8; CONFIG_LEN] {
+    let mut buf = [0; CONFIG_LEN];
+    buf[..KEYMAP_LEN].copy_from_slice(&encode_keymap(&config.layers));
+
+    let mut writer = Writer {
+        buf: &mut buf[KEYMAP_LEN..],
+        at: 0,
+    };
+
+    let options = &config.options;
+    writer.optional(options.tapping_term_ms);
+    writer.u32(options.debounce_ms);
+    writer.optional(options.auto_shift.alpha_ms);
+    writer.optional(options.auto_shift.number_ms);
+    writer.optional(options.auto_shift.symbol_ms);
+    for curve in [options.mouse_move, options.mouse_scroll] {
+        writer.u32(curve.start_speed);
+        writer.u32(curve.max_speed);
+        writer.u32(curve.time_to_max_ms);
+        writer.u32(curve.exponent);
+    }
+
+    let usb = options.usb;
+    writer.bytes(&[usb.mouse as u8
+        | (usb.consumer as u8) << 1
+        | (usb.system as u8) << 2
+        | (usb.raw_hid as u8) << 3
+        | (usb.serial as u8) << 4]);
+    writer.entry(options.bootmagic.safe_mode.map(|key| [key]));
+    writer.entry(options.bootmagic.clear.map(|key| [key]));
+
+    for o in config.overrides {
+        writer.entry(o.map(|o| [o.trigger as u8, o.replacement as u8, o.mods.0]));
+    }
+    for alt in config.alt_repeats {
+        writer.entry(alt.map(|(a, b)| [a as u8, b as u8]));
+    }
+    for conditional in config.conditional_layers {
+        writer.entry(conditional.map(|c| {
+            let [low, high] = c.if_layers.to_le_bytes();
+            [low, high, c.then_layer as u8]
+        }));
+    }
+    for led_layer in config.led_layers {
+        writer.entry(led_layer.map(|l| [l.led as u8, l.layer as u8]));
+    }
+
+    buf
+}
+
+pub fn decode_config(buf: &[u8]) -> Result<Config, DecodeError> {
+    if buf.len() != CONFIG_LEN {
+        return Err(DecodeError::InvalidValue);
+    }
+
+    let layers = decode_keymap(&buf[..KEYMAP_LEN])?;
+    let mut reader = Reader {
+        buf: &buf[KEYMAP_LEN..],
+        at: 0,
+    };
+    let key = |code: u8| lookup(&KEY_CODES, code);
+    let layer = |id: u8| match id as usize {
+        id if id < NUM_LAYERS => Ok(id as u32),
+        _ => Err(DecodeError::InvalidValue),
+    };
+    let curve = |reader: &mut Reader| MouseCurve {
+        start_speed: reader.u32(),
+        max_speed: reader.u32(),
+        time_to_max_ms: reader.u32(),
+        exponent: reader.u32(),
+    };
+
+    let tapping_term_ms = reader.optional()?;
+    let debounce_ms = reader.u32();
+    let auto_shift = AutoShift {
+        alpha_ms: reader.optional()?,
+        number_ms: reader.optional()?,
+        symbol_ms: reader.optional()?,
+    };
+    let mouse_move = curve(&mut reader);
+    let mouse_scroll = curve(&mut reader);
+
+    let [usb] = reader.bytes();
+    if usb >> 5 != 0 {
+        return Err(DecodeError::InvalidValue);
+    }
+    let usb = UsbOptions {
+        mouse: usb & 0x01 != 0,
+        consumer: usb & 0x02 != 0,
+        system: usb & 0x04 != 0,
+        raw_hid: usb & 0x08 != 0,
+        serial: usb & 0x10 != 0,
+    };
+
+    let mut bootmagic_key = || match reader.entry()? {
+        Some([key]) if key as usize >= KEYS => Err(DecodeError::InvalidValue),
+        entry => Ok(entry.map(|[key]| key)),
+    };
+    let bootmagic = Bootmagic {
+        safe_mode: bootmagic_key()?,
+        clear: bootmagic_key()?,
+    };
+
+    let mut overrides = [None; MAX_OVERRIDES];
+    for o in overrides.iter_mut() {
+        if let Some([trigger, replacement, mods]) = reader.entry()? {
+            *o = Some(KeyOverride {
+                trigger: key(trigger)?,
+                replacement: key(replacement)?,
+                mods: Mods(mods),
+            });
+        }
+    }
+
+    let mut alt_repeats = [None; MAX_ALT_REPEATS];
+    for alt in alt_repeats.iter_mut() {
+        if let Some([a, b]) = reader.entry()? {
+            *alt = Some((key(a)?, key(b)?));
+        }
+    }
+
+    let mut conditional_layers = [None; MAX_CONDITIONAL_LAYERS];
+    for conditional in conditional_layers.iter_mut() {
+        if let Some([low, high, then_layer]) = reader.entry()? {
+            let if_layers = u16::from_le_bytes([low, high]);
+            if if_layers >> NUM_LAYERS != 0 {
+                return Err(DecodeError::InvalidValue);
+            }
+            *conditional = Some(ConditionalLayer {
+                if_layers,
+                then_layer: layer(then_layer)?,
+            });
+        }
+    }
+
+    let mut led_layers = [None; MAX_LED_LAYERS];
+    for led_layer in led_layers.iter_mut() {
+        if let Some([led, id]) = reader.entry()? {
+            *led_layer = Some(LedLayer {
+                led: lookup(&LED_CODES, led)?,
+                layer: layer(id)?,
+            });
+        }
+    }
+
+    Ok(Config {
+        options: Options {
+            tapping_term_ms,
+            debounce_ms,
+            auto_shift,
+            mouse_move,
+            mouse_scroll,
+            usb,
+            bootmagic,
+        },
+        layers,
+        overrides,
+        alt_repeats,
+        conditional_layers,
+        led_layers,
+    })
+}
+
+/// Fills a buffer field by field, for `encode_config`
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    at: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.at..self.at + bytes.len()].copy_from_slice(bytes);
+        self.at += bytes.len();
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn optional(&mut self, value: Option<u32>) {
+        self.bytes(&[value.is_some() as u8]);
+        self.u32(value.unwrap_or(0));
+    }
+
+    fn entry<const N: usize>(&mut self, entry: Option<[u8; N]>) {
+        self.bytes(&[entry.is_some() as u8]);
+        self.bytes(&entry.unwrap_or([0; N]));
+    }
+}
+
+/// Reads a buffer field by field, for `decode_config`
+struct Reader<'a> {
+    buf: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self.buf[self.at..self.at + N].try_into().unwrap();
+        self.at += N;
+        bytes
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes())
+    }
+
+    fn present(&mut self) -> Result<bool, DecodeError> {
+        match self.bytes() {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+
+    fn optional(&mut self) -> Result<Option<u32>, DecodeError> {
+        let present = self.present()?;
+        let value = self.u32();
+        Ok(present.then_some(value))
+    }
+
+    fn entry<const N: usize>(&mut self) -> Result<Option<[u8; N]>, DecodeError> {
+        let present = self.present()?;
+        let bytes = self.bytes();
+        Ok(present.then_some(bytes))
+    }
+}
+
+fn lookup<T: Copy>(table: &[T], code: u8) -> Result<T, DecodeError> {
+    table
+        .get(code as usize)
+        .copied()
+        .ok_or(DecodeError::InvalidValue)
+}
+
+/// The parts of the firmware that commands can read and change
+pub trait Keyboard {
+    /// Behavior of key `pos` on `layer`, `None` if the layer isn't defined
+    fn behavior(&self, layer: usize, pos: usize) -> Option<Behavior>;
+    /// Returns false if the layer isn't defined
+    fn set_behavior(&mut self, layer: usize, pos: usize, behavior: Behavior) -> bool;
+    fn active_layers(&self) -> u16;
+    fn set_active_layers(&mut self, layers: u16);
+    fn matrix(&self) -> [bool; KEYS];
+    /// Called before the response is sent, the firmware should reset once it has gone out
+    fn reset_to_bootloader(&mut self);
+    fn safe_mode(&self) -> Option<SafeModeReason>;
+}
+
+/// Run a request packet against the keyboard, returning the response packet
+pub fn handle<K: Keyboard>(keyboard: &mut K, packet: &Packet) -> Packet {
+    let response = match Request::decode(packet) {
+        Ok(request) => execute(keyboard, request),
+        Err(DecodeError::UnsupportedVersion(_)) => Response::Error(Status::UnsupportedVersion),
+        Err(DecodeError::UnknownCommand(_)) => Response::Error(Status::UnknownCommand),
+        Err(_) => Response::Error(Status::InvalidArgs),
+    };
+
+    response.encode(packet[1])
+}
+
+fn execute<K: Keyboard>(keyboard: &mut K, request: Request) -> Response {
+    match request {
+        Request::ProtocolInfo => Response::ProtocolInfo {
+            version: PROTOCOL_VERSION,
+        },
+        Request::MatrixSize => Response::MatrixSize {
+            rows: ROWS as u8,
+            cols: COLS as u8,
+            layers: NUM_LAYERS as u8,
+        },
+        Request::GetBehavior(pos) => match pos
+            .index()
+            .and_then(|(layer, pos)| keyboard.behavior(layer, pos))
+        {
+            Some(behavior) => Response::Behavior(behavior),
+            None => Response::Error(Status::InvalidPosition),
+        },
+        Request::SetBehavior(pos, behavior) => match pos.index() {
+            Some((layer, pos)) if keyboard.set_behavior(layer, pos, behavior) => Response::Done,
+            _ => Response::Error(Status::InvalidPosition),
+        },
+        Request::GetLayers => Response::Layers(keyboard.active_layers()),
+        Request::SetLayers(layers) if layers >> NUM_LAYERS == 0 => {
+            keyboard.set_active_layers(layers);
+            Response::Done
+        }
+        Request::SetLayers(_) => Response::Error(Status::InvalidArgs),
+        Request::MatrixState => {
+            let mut state = [0; MATRIX_BYTES];
+            for (i, pressed) in keyboard.matrix().iter().enumerate() {
+                if *pressed {
+                    state[i / 8] |= 1 << (i % 8);
+                }
+            }
+            Response::MatrixState(state)
+        }
+        Request::Bootloader => {
+            keyboard.reset_to_bootloader();
+            Response::Done
+        }
+        Request::BootStatus => Response::BootStatus(keyboard.safe_mode()),
+    }
+}
+
+/// Carries packets between a host and a keyboard
+pub trait Transport {
+    type Error;
+
+    fn send(&mut self, packet: &Packet) -> Result<(), Self::Error>;
+    fn receive(&mut self) -> Result<Packet, Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError<E> {
+    Transport(E),
+    Decode(DecodeError),
+    // The response answers a different command than the one sent
+    Mismatch(Command),
+}
+
+/// Send a request and wait for its response. Errors reported by the keyboard come back as
+/// `Response::Error`.
+pub fn call<T: Transport>(
+    transport: &mut T,
+    request: &Request,
+) -> Result<Response, CallError<T::Error>> {
+    transport
+        .send(&request.encode())
+        .map_err(CallError::Transport)?;
+    let packet = transport.receive().map_err(CallError::Transport)?;
+
+    match Response::decode(&packet).map_err(CallError::Decode)? {
+        (command, response) if command == request.command() => Ok(response),
+        (command, _) => Err(CallError::Mismatch(command)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::NUM_LAYERS;
+    use crate::no_std::{
+        Behavior, COLS, Consumer, Direction, KEYS, Key, Layer, Mods, MouseButton, ROWS,
+        SystemControl,
+    };
+    use crate::protocol::{
+        CallError, Command, DecodeError, KEY_CODES, Keyboard, PROTOCOL_VERSION, Packet, Position,
+        REPORT_LEN, Request, Response, SafeModeReason, Status, Transport, call, decode_behavior,
+        decode_keymap, encode_behavior, encode_keymap, handle,
+    };
+
+    struct FakeKeyboard {
+        layers: [Option<[Behavior; KEYS]>; NUM_LAYERS],
+        active_layers: u16,
+        matrix: [bool; KEYS],
+        bootloader: bool,
+        safe_mode: Option<SafeModeReason>,
+    }
+
+    impl Keyboard for FakeKeyboard {
+        fn behavior(&self, layer: usize, pos: usize) -> Option<Behavior> {
+            self.layers[layer].map(|keys| keys[pos])
+        }
+
+        fn set_behavior(&mut self, layer: usize, pos: usize, behavior: Behavior) -> bool {
+            match &mut self.layers[layer] {
+                Some(keys) => {
+                    keys[pos] = behavior;
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn active_layers(&self) -> u16 {
+            self.active_layers
+        }
+
+        fn set_active_layers(&mut self, layers: u16) {
+            self.active_layers = layers | 1;
+        }
+
+        fn matrix(&self) -> [bool; KEYS] {
+            self.matrix
+        }
+
+        fn reset_to_bootloader(&mut self) {
+            self.bootloader = true;
+        }
+
+        fn safe_mode(&self) -> Option<SafeModeReason> {
+            self.safe_mode
+        }
+    }
+
+    /// Handles each packet as soon as it's sent
+    struct MemoryTransport {
+        keyboard: FakeKeyboard,
+        response: Option<Packet>,
+    }
+
+    impl MemoryTransport {
+        fn new() -> Self {
+            let mut layers = [None; NUM_LAYERS];
+            layers[0] = Some([Behavior::Key(Key::A); KEYS]);
+            layers[1] = Some([Behavior::Transparent; KEYS]);
+
+            Self {
+                keyboard: FakeKeyboard {
+                    layers,
+                    active_layers: 1,
+                    matrix: [false; KEYS],
+                    bootloader: false,
+                    safe_mode: None,
+                },
+                response: None,
+            }
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        type Error = ();
+
+        fn send(&mut self, packet: &Packet) -> Result<(), Self::Error> {
+            self.response = Some(handle(&mut self.keyboard, packet));
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Result<Packet, Self::Error> {
+            self.response.take().ok_or(())
+        }
+    }
+
+    const BEHAVIORS: [Behavior; 13] = [
+        Behavior::None,
+        Behavior::Transparent,
+        Behavior::Key(Key::SLCK),
+        Behavior::MomentaryLayer(9),
+        Behavior::HoldTap(Key::A, Key::LCTL),
+        Behavior::ModMorph(Key::BKSP, Key::DEL, Mods::SHIFT),
+        Behavior::Repeat,
+        Behavior::AltRepeat,
+        Behavior::MouseButton(MouseButton::Forward),
+        Behavior::MouseMove(Direction::Right),
+        Behavior::MouseScroll(Direction::Up),
+        Behavior::Consumer(Consumer::BriDown),
+        Behavior::System(SystemControl::Wake),
+    ];
+
+    #[test]
+    fn test_key_codes() {
+        for (code, key) in KEY_CODES.iter().enumerate() {
+            assert_eq!(*key as usize, code);
+        }
+    }
+
+    #[test]
+    fn test_behavior_round_trip() {
+        for behavior in BEHAVIORS {
+            let mut buf = [0; 4];
+            encode_behavior(behavior, &mut buf);
+            assert_eq!(decode_behavior(&buf), Ok(behavior));
+        }
+
+        assert_eq!(
+            decode_behavior(&[0x02, KEY_CODES.len() as u8, 0, 0]),
+            Err(DecodeError::InvalidValue)
+        );
+        assert_eq!(
+            decode_behavior(&[0x03, NUM_LAYERS as u8, 0, 0]),
+            Err(DecodeError::InvalidValue)
+        );
+        assert_eq!(
+            decode_behavior(&[0xFF, 0, 0, 0]),
+            Err(DecodeError::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn test_keymap_round_trip() {
+        let mut layers = [(); NUM_LAYERS].map(|_| None);
+        let mut keys = [Behavior::Transparent; KEYS];
+        keys[..BEHAVIORS.len()].copy_from_slice(&BEHAVIORS);
+        layers[0] = Some(Layer { id: 0, keys });
+        layers[3] = Some(Layer {
+            id: 3,
+            keys: [Behavior::Key(Key::A); KEYS],
+        });
+
+        let buf = encode_keymap(&layers);
+        assert_eq!(&buf[..2], &[0b1001, 0]);
+        assert_eq!(decode_keymap(&buf), Ok(layers));
+
+        assert_eq!(decode_keymap(&buf[1..]), Err(DecodeError::InvalidValue));
+
+        let mut bad = buf;
+        bad[1] = 0x80;
+        assert_eq!(decode_keymap(&bad), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let pos = Position {
+            layer: 1,
+            row: 2,
+            col: 3,
+        };
+        let requests = [
+            Request::ProtocolInfo,
+            Request::MatrixSize,
+            Request::GetBehavior(pos),
+            Request::SetBehavior(pos, Behavior::HoldTap(Key::Z, Key::LALT)),
+            Request::GetLayers,
+            Request::SetLayers(0b101),
+            Request::MatrixState,
+            Request::Bootloader,
+            Request::BootStatus,
+        ];
+
+        for request in requests {
+            assert_eq!(Request::decode(&request.encode()), Ok(request));
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut packet = Request::GetLayers.encode();
+        packet[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Request::decode(&packet),
+            Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+
+        // Protocol info is always answered
+        let mut packet = Request::ProtocolInfo.encode();
+        packet[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(Request::decode(&packet), Ok(Request::ProtocolInfo));
+
+        let mut transport = MemoryTransport::new();
+        let mut packet = Request::GetLayers.encode();
+        packet[0] = 0;
+        transport.send(&packet).unwrap();
+        assert_eq!(
+            Response::decode(&transport.receive().unwrap()),
+            Ok((
+                Command::GetLayers,
+                Response::Error(Status::UnsupportedVersion)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_info() {
+        let mut transport = MemoryTransport::new();
+
+        assert_eq!(
+            call(&mut transport, &Request::ProtocolInfo),
+            Ok(Response::ProtocolInfo {
+                version: PROTOCOL_VERSION
+            })
+        );
+        assert_eq!(
+            call(&mut transport, &Request::MatrixSize),
+            Ok(Response::MatrixSize {
+                rows: ROWS as u8,
+                cols: COLS as u8,
+                layers: NUM_LAYERS as u8,
+            })
+        );
+    }
+
+    #[test]
+    fn test_behaviors() {
+        let mut transport = MemoryTransport::new();
+        let pos = Position {
+            layer: 1,
+            row: ROWS as u8 - 1,
+            col: COLS as u8 - 1,
+        };
+
+        assert_eq!(
+            call(&mut transport, &Request::GetBehavior(pos)),
+            Ok(Response::Behavior(Behavior::Transparent))
+        );
+
+        for behavior in BEHAVIORS {
+            assert_eq!(
+                call(&mut transport, &Request::SetBehavior(pos, behavior)),
+                Ok(Response::Done)
+            );
+            assert_eq!(
+                call(&mut transport, &Request::GetBehavior(pos)),
+                Ok(Response::Behavior(behavior))
+            );
+        }
+        assert_eq!(
+            transport.keyboard.layers[1].unwrap()[KEYS - 1],
+            *BEHAVIORS.last().unwrap()
+        );
+
+        // Out of range, and a layer the keymap doesn't define
+        let bad = [
+            Position {
+                col: COLS as u8,
+                ..pos
+            },
+            Position {
+                row: ROWS as u8,
+                ..pos
+            },
+            Position {
+                layer: NUM_LAYERS as u8,
+                ..pos
+            },
+            Position { layer: 2, ..pos },
+        ];
+        for pos in bad {
+            assert_eq!(
+                call(&mut transport, &Request::GetBehavior(pos)),
+                Ok(Response::Error(Status::InvalidPosition))
+            );
+            assert_eq!(
+                call(&mut transport, &Request::SetBehavior(pos, Behavior::Repeat)),
+                Ok(Response::Error(Status::InvalidPosition))
+            );
+        }
+
+        // Undecodable behaviors are rejected without touching the keymap
+        let mut packet = Request::SetBehavior(pos, Behavior::None).encode();
+        packet[5] = 0xFF;
+        transport.send(&packet).unwrap();
+        assert_eq!(
+            Response::decode(&transport.receive().unwrap()),
+            Ok((Command::SetBehavior, Response::Error(Status::InvalidArgs)))
+        );
+    }
+
+    #[test]
+    fn test_layers() {
+        let mut transport = MemoryTransport::new();
+
+        assert_eq!(
+            call(&mut transport, &Request::GetLayers),
+            Ok(Response::Layers(1))
+        );
+        assert_eq!(
+            call(&mut transport, &Request::SetLayers(0b10)),
+            Ok(Response::Done)
+        );
+        assert_eq!(
+            call(&mut transport, &Request::GetLayers),
+            Ok(Response::Layers(0b11))
+        );
+        assert_eq!(
+            call(&mut transport, &Request::SetLayers(1 << NUM_LAYERS)),
+            Ok(Response::Error(Status::InvalidArgs))
+        );
+    }
+
+    #[test]
+    fn test_matrix_state() {
+        let mut transport = MemoryTransport::new();
+        transport.keyboard.matrix[0] = true;
+        transport.keyboard.matrix[9] = true;
+        transport.keyboard.matrix[KEYS - 1] = true;
+
+        assert_eq!(
+            call(&mut transport, &Request::MatrixState),
+            Ok(Response::MatrixState([0x01, 0x02, 0x80]))
+        );
+    }
+
+    #[test]
+    fn test_bootloader() {
+        let mut transport = MemoryTransport::new();
+
+        assert_eq!(
+            call(&mut transport, &Request::Bootloader),
+            Ok(Response::Done)
+        );
+        assert!(transport.keyboard.bootloader);
+    }
+
+    #[test]
+    fn test_boot_status() {
+        let mut transport = MemoryTransport::new();
+
+        assert_eq!(
+            call(&mut transport, &Request::BootStatus),
+            Ok(Response::BootStatus(None))
+        );
+
+        for reason in [
+            SafeModeReason::InvalidKeymap,
+            SafeModeReason::Panicked,
+            SafeModeReason::Bootmagic,
+        ] {
+            transport.keyboard.safe_mode = Some(reason);
+            assert_eq!(
+                call(&mut transport, &Request::BootStatus),
+                Ok(Response::BootStatus(Some(reason)))
+            );
+        }
+
+        let mut packet = Response::BootStatus(None).encode(Command::BootStatus as u8);
+        packet[3] = 0x7F;
+        assert_eq!(Response::decode(&packet), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn test_bad_packets() {
+        let mut transport = MemoryTransport::new();
+
+        let mut packet = [0; REPORT_LEN];
+        packet[0] = PROTOCOL_VERSION;
+        packet[1] = 0x7F;
+        transport.send(&packet).unwrap();
+        let response = transport.receive().unwrap();
+        assert_eq!(response[1..3], [0x7F, Status::UnknownCommand as u8]);
+        assert_eq!(
+            Response::decode(&response),
+            Err(DecodeError::UnknownCommand(0x7F))
+        );
+
+        // A response to something else than what was asked
+        struct Wrong;
+        impl Transport for Wrong {
+            type Error = ();
+
+            fn send(&mut self, _: &Packet) -> Result<(), ()> {
+                Ok(())
+            }
+
+            fn receive(&mut self) -> Result<Packet, ()> {
+                Ok(Response::Done.encode(Command::Bootloader as u8))
+            }
+        }
+        assert_eq!(
+            call(&mut Wrong, &Request::GetLayers),
+            Err(CallError::Mismatch(Command::Bootloader))
+        );
+    }
+}
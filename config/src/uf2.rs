@@ -0,0 +1,77 @@
+//! UF2 images, the format the RP2040's boot drive takes
+//!
+//! Each 512 byte block carries 256 bytes for one flash page along with the address it goes to.
+//! Only the pages in the image are written, so an image can cover just the keymap partition.
+
+/// Family id the RP2040 boot ROM accepts
+pub const RP2040_FAMILY_ID: u32 = 0xE48B_FF56;
+
+const MAGIC_START_0: u32 = 0x0A32_4655;
+const MAGIC_START_1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+const FLAG_FAMILY_ID: u32 = 0x0000_2000;
+const BLOCK_LEN: usize = 512;
+const PAGE_LEN: usize = 256;
+
+/// An image writing `data` to flash starting at `addr`, the last page padded with zeros
+pub fn write_uf2(addr: u32, data: &[u8]) -> Vec<u8> {
+    let pages = data.len().div_ceil(PAGE_LEN);
+    let mut res = Vec::with_capacity(pages * BLOCK_LEN);
+
+    for (i, page) in data.chunks(PAGE_LEN).enumerate() {
+        let mut block = [0; BLOCK_LEN];
+        let header = [
+            MAGIC_START_0,
+            MAGIC_START_1,
+            FLAG_FAMILY_ID,
+            addr + (i * PAGE_LEN) as u32,
+            PAGE_LEN as u32,
+            i as u32,
+            pages as u32,
+            RP2040_FAMILY_ID,
+        ];
+
+        for (word, value) in block.chunks_mut(4).zip(header) {
+            word.copy_from_slice(&value.to_le_bytes());
+        }
+        block[32..32 + page.len()].copy_from_slice(page);
+        block[BLOCK_LEN - 4..].copy_from_slice(&MAGIC_END.to_le_bytes());
+
+        res.extend_from_slice(&block);
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uf2::{RP2040_FAMILY_ID, write_uf2};
+
+    fn word(block: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_write_uf2() {
+        let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let image = write_uf2(0x1010_0000, &data);
+        assert_eq!(image.len(), 3 * 512);
+
+        for (i, block) in image.chunks(512).enumerate() {
+            assert_eq!(word(block, 0), 0x0A32_4655);
+            assert_eq!(word(block, 1), 0x9E5D_5157);
+            assert_eq!(word(block, 3), 0x1010_0000 + 256 * i as u32);
+            assert_eq!(word(block, 4), 256);
+            assert_eq!(word(block, 5), i as u32);
+            assert_eq!(word(block, 6), 3);
+            assert_eq!(word(block, 7), RP2040_FAMILY_ID);
+            assert_eq!(word(block, 127), 0x0AB1_6F30);
+        }
+
+        assert_eq!(&image[32..32 + 256], &data[..256]);
+        // The last page is padded
+        let last = &image[1024 + 32..1024 + 32 + 256];
+        assert_eq!(&last[..88], &data[512..]);
+        assert!(last[88..].iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,112 @@
+//! Writes keymaps back out in the config format, the inverse of the parser
+
+use std::fmt::Write;
+
+use crate::NUM_LAYERS;
+use crate::no_std::{
+    Behavior, COLS, Consumer, Direction, Layer, Mods, MouseButton, ROWS, SystemControl,
+};
+
+/// Name for a layer that only has an id, the keyboard doesn't know the names from the config
+pub fn layer_name(id: u32) -> String {
+    match id {
+        0 => "BASE".to_owned(),
+        id => format!("L{}", id),
+    }
+}
+
+/// Write a config with an empty options section and the given layers, named with `layer_name`
+pub fn write_config(layers: &[Option<Layer>; NUM_LAYERS]) -> String {
+    let mut res = String::from("options: {\n};\n\nlayers: {\n");
+
+    for layer in layers.iter().flatten() {
+        writeln!(res, "    {}: [", layer_name(layer.id)).unwrap();
+
+        for row in 0..ROWS {
+            let keys: Vec<String> = layer.keys[COLS * row..COLS * (row + 1)]
+                .iter()
+                .map(|b| write_behavior(*b))
+                .collect();
+            writeln!(res, "        {}", keys.join(" ")).unwrap();
+        }
+
+        res.push_str("    ],\n");
+    }
+
+    res.push_str("};\n");
+    res
+}
+
+pub fn write_behavior(behavior: Behavior) -> String {
+    match behavior {
+        Behavior::Key(key) => format!("(kp {:?})", key),
+        Behavior::MomentaryLayer(layer) => format!("(ml {})", layer_name(layer)),
+        Behavior::HoldTap(hold, tap) => format!("(ht {:?} {:?})", hold, tap),
+        Behavior::ModMorph(base, morphed, mods) => {
+            format!("(mm {:?} {:?} {})", base, morphed, mods_name(mods))
+        }
+        Behavior::Repeat => "(rep)".to_owned(),
+        Behavior::AltRepeat => "(arep)".to_owned(),
+        Behavior::MouseButton(button) => format!("(mkp {})", mouse_button_name(button)),
+        Behavior::MouseMove(direction) => format!("(mmv {})", direction_name(direction)),
+        Behavior::MouseScroll(direction) => format!("(msc {})", direction_name(direction)),
+        Behavior::Consumer(consumer) => format!("(kp {})", consumer_name(consumer)),
+        Behavior::System(system) => format!("(kp {})", system_name(system)),
+        Behavior::None => "(n)".to_owned(),
+        Behavior::Transparent => "(t)".to_owned(),
+    }
+}
+
+// The config only has single modifiers, so only the first one set is written
+fn mods_name(mods: Mods) -> &'static str {
+    [
+        (Mods::CTRL, "ctrl"),
+        (Mods::SHIFT, "shift"),
+        (Mods::ALT, "alt"),
+        (Mods::GUI, "gui"),
+    ]
+    .into_iter()
+    .find(|(m, _)| !mods.intersection(*m).is_empty())
+    .map_or("ctrl", |(_, name)| name)
+}
+
+fn mouse_button_name(button: MouseButton) -> &'static str {
+    match button {
+        MouseButton::Left => "LCLK",
+        MouseButton::Right => "RCLK",
+        MouseButton::Middle => "MCLK",
+        MouseButton::Back => "BCLK",
+        MouseButton::Forward => "FCLK",
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Up => "UP",
+        Direction::Down => "DN",
+        Direction::Left => "LFT",
+        Direction::Right => "RHT",
+    }
+}
+
+pub fn consumer_name(consumer: Consumer) -> &'static str {
+    match consumer {
+        Consumer::VolUp => "C_VOL_UP",
+        Consumer::VolDown => "C_VOL_DN",
+        Consumer::Mute => "C_MUTE",
+        Consumer::PlayPause => "C_PLAY_PAUSE",
+        Consumer::Next => "C_NEXT",
+        Consumer::Prev => "C_PREV",
+        Consumer::Stop => "C_STOP",
+        Consumer::BriUp => "C_BRI_UP",
+        Consumer::BriDown => "C_BRI_DN",
+    }
+}
+
+pub fn system_name(system: SystemControl) -> &'static str {
+    match system {
+        SystemControl::PowerDown => "SYS_PWR",
+        SystemControl::Sleep => "SYS_SLEEP",
+        SystemControl::Wake => "SYS_WAKE",
+    }
+}
@@ -1,9 +1,16 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 4K - 64K
+    /* Written on its own from a UF2, KEYMAP_ADDR in config/src/partition.rs must match */
+    KEYMAP : ORIGIN = 0x10000000 + 2048K - 4K - 64K, LENGTH = 4K
+    /* Settings and the keymap, kept across resets, see src/storage.rs */
+    STORAGE : ORIGIN = 0x10000000 + 2048K - 64K, LENGTH = 64K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
+__storage_start = ORIGIN(STORAGE);
+__storage_end = ORIGIN(STORAGE) + LENGTH(STORAGE);
+
 EXTERN(BOOT2_FIRMWARE)
 
 SECTIONS {
@@ -0,0 +1,22 @@
+//! Host simulator for the keymap engine, running the firmware's engine against a scripted timeline
+//! of key presses instead of a key matrix
+
+// The portable parts of the firmware, built for the host
+#[path = "../../src/clock.rs"]
+pub mod clock;
+#[path = "../../src/commands.rs"]
+pub mod commands;
+#[path = "../../src/engine.rs"]
+pub mod engine;
+#[path = "../../src/event.rs"]
+pub mod event;
+#[path = "../../src/keymap.rs"]
+pub mod keymap;
+#[path = "../../src/leds.rs"]
+pub mod leds;
+#[path = "../../src/mouse.rs"]
+pub mod mouse;
+
+pub mod output;
+pub mod script;
+pub mod simulator;
@@ -0,0 +1,57 @@
+//! `sim`, runs a keymap on the host against a timeline of key presses and prints the reports the
+//! keyboard would send, or the text they would type
+//!
+//! sim keymap.kbd timeline.txt
+//! echo "t=0 press 1,0; t=120 release 1,0" | sim --text keymap.kbd -
+
+use std::io::Read;
+use std::{env, fs, io, process};
+
+use config::parse_config;
+use config::scanner::scan_input;
+use sim::output::{format_frames, typed_text};
+use sim::script::parse_script;
+use sim::simulator::simulate;
+
+const HELP: &str = "Usage:
+    sim [--text] <keymap> <timeline>    run a keymap file against a timeline, - reads it from stdin
+
+Timelines are steps like `t=0 press 1,0; t=120 release 1,0`, with times in ms and keys as row,col.
+Reports are printed as they would be sent, or with --text, the text they would type.";
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let (text, keymap, timeline) = match args.as_slice() {
+        ["--text", keymap, timeline] => (true, keymap, timeline),
+        [keymap, timeline] => (false, keymap, timeline),
+        _ => fail(HELP),
+    };
+
+    let keymap = fs::read(keymap).unwrap_or_else(|e| fail(format!("{}: {}", keymap, e)));
+    let config = parse_config(&mut scan_input(&mut keymap.into()));
+
+    let timeline = if *timeline == "-" {
+        let mut res = String::new();
+        io::stdin()
+            .read_to_string(&mut res)
+            .unwrap_or_else(|e| fail(format!("stdin: {}", e)));
+        res
+    } else {
+        fs::read_to_string(timeline).unwrap_or_else(|e| fail(format!("{}: {}", timeline, e)))
+    };
+    let events = parse_script(&timeline).unwrap_or_else(|e| fail(e));
+
+    let frames = simulate(&config, &events);
+    if text {
+        print!("{}", typed_text(&frames));
+    } else {
+        print!("{}", format_frames(&frames));
+    }
+}
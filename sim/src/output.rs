@@ -0,0 +1,209 @@
+//! Printing what the simulated keyboard sends, either report by report or as the text a US layout
+//! host would type
+
+use config::no_std::{Key, KeyClass, Mods};
+use config::writer::{consumer_name, system_name};
+
+use crate::mouse::MouseReport;
+use crate::simulator::Frame;
+
+const MOD_NAMES: [(Mods, &str); 4] = [
+    (Mods::CTRL, "ctrl"),
+    (Mods::SHIFT, "shift"),
+    (Mods::ALT, "alt"),
+    (Mods::GUI, "gui"),
+];
+
+/// One line per report, e.g. `t=120 mods=shift keys=A,B`. The mouse, consumer and system parts
+/// are only shown when something is pressed or moving on them.
+pub fn format_frame(frame: &Frame) -> String {
+    let report = &frame.report;
+    let keys: Vec<String> = report.keys().map(|key| format!("{:?}", key)).collect();
+    let mut res = format!(
+        "t={} mods={} keys={}",
+        frame.time,
+        mods_name(report.mods),
+        list(keys)
+    );
+
+    if report.mouse != MouseReport::default() {
+        let m = report.mouse;
+        res += &format!(" mouse={},{},{},{},{}", m.buttons, m.x, m.y, m.wheel, m.pan);
+    }
+    let consumer: Vec<String> = report
+        .consumer
+        .iter()
+        .flatten()
+        .map(|c| consumer_name(*c).to_owned())
+        .collect();
+    if !consumer.is_empty() {
+        res += &format!(" consumer={}", list(consumer));
+    }
+    if let Some(system) = report.system {
+        res += &format!(" system={}", system_name(system));
+    }
+
+    res
+}
+
+/// Every report, one per line
+pub fn format_frames(frames: &[Frame]) -> String {
+    frames
+        .iter()
+        .map(|frame| format_frame(frame) + "\n")
+        .collect()
+}
+
+/// The text typed by the key presses in `frames`. Backspace deletes, keys without a character and
+/// keys held with ctrl, alt or gui are written as `<name>`, e.g. `<ctrl+C>`.
+pub fn typed_text(frames: &[Frame]) -> String {
+    let mut res = String::new();
+    let mut held: Vec<Key> = vec![];
+
+    for frame in frames {
+        let report = &frame.report;
+        let keys: Vec<Key> = report.keys().collect();
+        let shift = !report.mods.intersection(Mods::SHIFT).is_empty();
+        let chord = report.mods.difference(Mods::SHIFT);
+
+        for &key in keys.iter().filter(|key| !held.contains(key)) {
+            match character(key, shift) {
+                _ if !chord.is_empty() => res += &format!("<{}+{:?}>", mods_name(report.mods), key),
+                Some(c) => res.push(c),
+                None if key == Key::BKSP => {
+                    res.pop();
+                }
+                None => res += &format!("<{:?}>", key),
+            }
+        }
+
+        held = keys;
+    }
+
+    res
+}
+
+fn mods_name(mods: Mods) -> String {
+    let names: Vec<String> = MOD_NAMES
+        .iter()
+        .filter(|(m, _)| !mods.intersection(*m).is_empty())
+        .map(|(_, name)| name.to_string())
+        .collect();
+
+    if names.is_empty() {
+        "-".to_owned()
+    } else {
+        names.join("+")
+    }
+}
+
+fn list(items: Vec<String>) -> String {
+    if items.is_empty() {
+        "-".to_owned()
+    } else {
+        items.join(",")
+    }
+}
+
+fn character(key: Key, shift: bool) -> Option<char> {
+    let (plain, shifted) = match key {
+        Key::N0 => ('0', ')'),
+        Key::N1 => ('1', '!'),
+        Key::N2 => ('2', '@'),
+        Key::N3 => ('3', '#'),
+        Key::N4 => ('4', '$'),
+        Key::N5 => ('5', '%'),
+        Key::N6 => ('6', '^'),
+        Key::N7 => ('7', '&'),
+        Key::N8 => ('8', '*'),
+        Key::N9 => ('9', '('),
+        Key::MNS => ('-', '_'),
+        Key::EQL => ('=', '+'),
+        Key::BSLH => ('\\', '|'),
+        Key::FSLH => ('/', '?'),
+        Key::LSBR => ('[', '{'),
+        Key::RSBR => (']', '}'),
+        Key::QUOT => ('\'', '"'),
+        Key::COMM => (',', '<'),
+        Key::DOT => ('.', '>'),
+        Key::SCLN => (';', ':'),
+        // Sent with shift, so there is only one character
+        Key::LPRN => ('(', '('),
+        Key::RPRN => (')', ')'),
+        Key::LCBR => ('{', '{'),
+        Key::RCBR => ('}', '}'),
+        Key::SPC => (' ', ' '),
+        Key::TAB => ('\t', '\t'),
+        Key::RET => ('\n', '\n'),
+        // Letters are named after themselves
+        key if key.class() == KeyClass::Alpha => {
+            let letter = format!("{:?}", key).chars().next()?;
+            (letter.to_ascii_lowercase(), letter)
+        }
+        _ => return None,
+    };
+
+    Some(if shift { shifted } else { plain })
+}
+
+#[cfg(test)]
+mod tests {
+    use config::no_std::{Consumer, Key, Mods};
+
+    use crate::engine::Report;
+    use crate::output::{format_frame, typed_text};
+    use crate::simulator::Frame;
+
+    fn frame(time: u32, mods: Mods, keys: &[Key]) -> Frame {
+        let mut report = Report::default();
+        for &key in keys {
+            report.press(key);
+        }
+        report.mods = report.mods.union(mods);
+        Frame { time, report }
+    }
+
+    #[test]
+    fn test_format_frame() {
+        assert_eq!(
+            format_frame(&frame(0, Mods::NONE, &[])),
+            "t=0 mods=- keys=-"
+        );
+        assert_eq!(
+            format_frame(&frame(
+                120,
+                Mods::SHIFT.union(Mods::CTRL),
+                &[Key::A, Key::N1]
+            )),
+            "t=120 mods=ctrl+shift keys=A,N1"
+        );
+
+        let mut media = frame(5, Mods::NONE, &[]);
+        media.report.consumer[0] = Some(Consumer::Mute);
+        media.report.mouse.buttons = 1;
+        media.report.mouse.x = -3;
+        assert_eq!(
+            format_frame(&media),
+            "t=5 mods=- keys=- mouse=1,-3,0,0,0 consumer=C_MUTE"
+        );
+    }
+
+    #[test]
+    fn test_typed_text() {
+        let frames = [
+            frame(0, Mods::SHIFT, &[Key::H]),
+            frame(1, Mods::NONE, &[]),
+            frame(2, Mods::NONE, &[Key::I]),
+            // Held keys only type once
+            frame(3, Mods::NONE, &[Key::I, Key::N1]),
+            frame(4, Mods::NONE, &[]),
+            frame(5, Mods::NONE, &[Key::BKSP]),
+            frame(6, Mods::SHIFT, &[Key::N1]),
+            frame(7, Mods::NONE, &[Key::LPRN]),
+            frame(8, Mods::CTRL, &[Key::C]),
+            frame(9, Mods::NONE, &[Key::ESC, Key::SPC]),
+        ];
+
+        assert_eq!(typed_text(&frames), "Hi!(<ctrl+C><ESC> ");
+    }
+}
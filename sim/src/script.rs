@@ -0,0 +1,190 @@
+//! Timelines of key presses, e.g. `t=0 press 1,0; t=120 release 1,0`
+//!
+//! Steps are separated by semicolons or new lines and `#` starts a comment. Each step is the time
+//! in ms, `press` or `release`, and the key as `row,col`. Steps at the same time happen in the
+//! order they are written, and time can't go backwards.
+
+use std::fmt;
+
+use config::no_std::{COLS, KEYS, ROWS};
+
+use crate::clock::Instant;
+use crate::event::KeyEvent;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    /// Counted from 1, comments and empty steps aren't counted
+    pub step: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {}: {}", self.step, self.message)
+    }
+}
+
+pub fn parse_script(script: &str) -> Result<Vec<KeyEvent>, ScriptError> {
+    let mut res: Vec<KeyEvent> = vec![];
+    let mut held = [false; KEYS];
+
+    let steps = script
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split(';'))
+        .map(str::trim)
+        .filter(|step| !step.is_empty());
+
+    for (i, step) in steps.enumerate() {
+        let error = |message: String| ScriptError {
+            step: i + 1,
+            message,
+        };
+
+        let event = parse_step(step).map_err(error)?;
+        let pos = event.position as usize;
+
+        let time = event.timestamp.millis();
+        if res
+            .last()
+            .is_some_and(|last| last.timestamp.millis() > time)
+        {
+            return Err(error(format!("t={} is before the step above", time)));
+        }
+        if held[pos] == event.pressed {
+            let state = if event.pressed { "held" } else { "released" };
+            return Err(error(format!(
+                "key {},{} is already {}",
+                pos / COLS,
+                pos % COLS,
+                state
+            )));
+        }
+
+        held[pos] = event.pressed;
+        res.push(event);
+    }
+
+    Ok(res)
+}
+
+fn parse_step(step: &str) -> Result<KeyEvent, String> {
+    let words: Vec<&str> = step.split_whitespace().collect();
+    let [time, action, key] = words.as_slice() else {
+        return Err(format!(
+            "expected `t=<ms> press|release <row>,<col>`, got `{}`",
+            step
+        ));
+    };
+
+    let timestamp = time
+        .strip_prefix("t=")
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(|| format!("invalid time `{}`", time))?;
+
+    let pressed = match *action {
+        "press" => true,
+        "release" => false,
+        other => return Err(format!("expected press or release, got `{}`", other)),
+    };
+
+    let (row, col) = key
+        .split_once(',')
+        .and_then(|(row, col)| Some((row.parse::<usize>().ok()?, col.parse::<usize>().ok()?)))
+        .ok_or_else(|| format!("invalid key `{}`", key))?;
+    if row >= ROWS || col >= COLS {
+        return Err(format!("key {},{} is outside the matrix", row, col));
+    }
+
+    Ok(KeyEvent {
+        position: (COLS * row + col) as u8,
+        pressed,
+        timestamp: Instant::from_millis(timestamp),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use config::no_std::COLS;
+
+    use crate::clock::Instant;
+    use crate::event::KeyEvent;
+    use crate::script::{parse_script, ScriptError};
+
+    fn event(row: usize, col: usize, pressed: bool, timestamp: u32) -> KeyEvent {
+        KeyEvent {
+            position: (COLS * row + col) as u8,
+            pressed,
+            timestamp: Instant::from_millis(timestamp),
+        }
+    }
+
+    #[test]
+    fn test_parse_script() {
+        assert_eq!(
+            parse_script("t=0 press 1,0; t=120 release 1,0"),
+            Ok(vec![event(1, 0, true, 0), event(1, 0, false, 120)])
+        );
+
+        let script = "# Roll from one key to the next
+            t=0 press 0,0
+            t=10 press 0,1;  t=10 release 0,0  # Same time, in order
+
+            t=50 release 0,1;
+        ";
+        assert_eq!(
+            parse_script(script),
+            Ok(vec![
+                event(0, 0, true, 0),
+                event(0, 1, true, 10),
+                event(0, 0, false, 10),
+                event(0, 1, false, 50),
+            ])
+        );
+
+        assert_eq!(parse_script(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_script_errors() {
+        let error = |step: usize, message: &str| {
+            Err(ScriptError {
+                step,
+                message: message.to_owned(),
+            })
+        };
+
+        assert_eq!(
+            parse_script("t=0 press 0,0; t=5 push 0,1"),
+            error(2, "expected press or release, got `push`")
+        );
+        assert_eq!(
+            parse_script("t=x press 0,0"),
+            error(1, "invalid time `t=x`")
+        );
+        assert_eq!(parse_script("t=0 press 0"), error(1, "invalid key `0`"));
+        assert_eq!(
+            parse_script("t=0 press 4,0"),
+            error(1, "key 4,0 is outside the matrix")
+        );
+        assert_eq!(
+            parse_script("t=0 press"),
+            error(
+                1,
+                "expected `t=<ms> press|release <row>,<col>`, got `t=0 press`"
+            )
+        );
+        assert_eq!(
+            parse_script("t=10 press 0,0; t=5 release 0,0"),
+            error(2, "t=5 is before the step above")
+        );
+        assert_eq!(
+            parse_script("t=0 press 0,0; t=5 press 0,0"),
+            error(2, "key 0,0 is already held")
+        );
+        assert_eq!(
+            parse_script("t=0 release 2,3"),
+            error(1, "key 2,3 is already released")
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Runs the engine over a timeline the way the firmware does, ticking every ms and sending a report
+//! whenever it changes
+
+use config::no_std::Config;
+
+use crate::clock::Instant;
+use crate::engine::{Engine, Report};
+use crate::event::KeyEvent;
+
+/// How long the simulation keeps going after the last event, so timeouts get to fire
+pub const TAIL_MS: u32 = 1000;
+
+/// A report as it would be sent to the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub time: u32,
+    pub report: Report,
+}
+
+/// The reports the keyboard would send for `events`, which must be in order
+pub fn simulate(config: &Config, events: &[KeyEvent]) -> Vec<Frame> {
+    let mut engine = Engine::new(config);
+    let mut res = vec![];
+    // The host starts out seeing nothing pressed
+    let mut last = Report::default();
+    let mut events = events.iter().peekable();
+    let end = events.clone().last().map_or(0, |e| e.timestamp.millis()) + TAIL_MS;
+
+    for time in 0..=end {
+        while let Some(event) = events.next_if(|e| e.timestamp.millis() <= time) {
+            engine.handle(*event);
+        }
+
+        let report = engine.tick(Instant::from_millis(time));
+        // Relative mouse movement is sent as long as it's moving, like the firmware does
+        if report != last || report.mouse.is_moving() {
+            res.push(Frame { time, report });
+            last = report;
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use config::no_std::{
+        AutoShift, Behavior, Config, Key, Layer, Mods, KEYS, MAX_ALT_REPEATS,
+        MAX_CONDITIONAL_LAYERS, MAX_LED_LAYERS, MAX_OVERRIDES,
+    };
+    use config::NUM_LAYERS;
+
+    use crate::clock::Instant;
+    use crate::event::KeyEvent;
+    use crate::simulator::{simulate, TAIL_MS};
+
+    fn config() -> Config {
+        let mut keys = [Behavior::None; KEYS];
+        keys[0] = Behavior::Key(Key::A);
+        keys[1] = Behavior::Key(Key::LSFT);
+
+        let mut layers = [(); NUM_LAYERS].map(|_| None);
+        layers[0] = Some(Layer { id: 0, keys });
+
+        Config {
+            layers,
+            overrides: [None; MAX_OVERRIDES],
+            alt_repeats: [None; MAX_ALT_REPEATS],
+            conditional_layers: [None; MAX_CONDITIONAL_LAYERS],
+            led_layers: [None; MAX_LED_LAYERS],
+            options: Default::default(),
+        }
+    }
+
+    fn event(position: u8, pressed: bool, timestamp: u32) -> KeyEvent {
+        KeyEvent {
+            position,
+            pressed,
+            timestamp: Instant::from_millis(timestamp),
+        }
+    }
+
+    #[test]
+    fn test_simulate() {
+        let frames = simulate(
+            &config(),
+            &[
+                event(1, true, 0),
+                event(0, true, 5),
+                event(0, false, 50),
+                event(1, false, 60),
+            ],
+        );
+
+        let summary: Vec<(u32, Mods, Vec<Key>)> = frames
+            .iter()
+            .map(|f| (f.time, f.report.mods, f.report.keys().collect()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0, Mods::SHIFT, vec![]),
+                (5, Mods::SHIFT, vec![Key::A]),
+                (50, Mods::SHIFT, vec![]),
+                (60, Mods::NONE, vec![]),
+            ]
+        );
+
+        assert!(simulate(&config(), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_simulate_timeouts() {
+        // Auto-shift resolves after the last event, while the simulation runs on
+        let mut config = config();
+        config.options.auto_shift = AutoShift {
+            alpha_ms: Some(200),
+            ..AutoShift::default()
+        };
+
+        let frames = simulate(&config, &[event(0, true, 100)]);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].time, 300);
+        assert_eq!(frames[0].report.mods, Mods::SHIFT);
+        assert!(frames[0].time <= 100 + TAIL_MS);
+    }
+}
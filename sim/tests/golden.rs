@@ -0,0 +1,100 @@
+//! Golden tests, running each case in `tests/golden` through the simulator and comparing the
+//! reports with the ones checked in next to it
+//!
+//! A case is a directory holding `keymap.kbd`, `timeline.txt` and `expected.txt`.
+//!
+//! cargo test --test golden                    run every case
+//! cargo test --test golden -- layers          only cases with `layers` in their name
+//! cargo test --test golden -- --bless         write the current reports to `expected.txt`
+
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+use config::parse_config;
+use config::scanner::scan_input;
+use sim::output::format_frames;
+use sim::script::parse_script;
+use sim::simulator::simulate;
+
+fn run(case: &Path) -> String {
+    let keymap = fs::read(case.join("keymap.kbd")).unwrap();
+    let config = parse_config(&mut scan_input(&mut keymap.into()));
+
+    let timeline = fs::read_to_string(case.join("timeline.txt")).unwrap();
+    let events = parse_script(&timeline).unwrap_or_else(|e| panic!("{}: {}", case.display(), e));
+
+    format_frames(&simulate(&config, &events))
+}
+
+// Line by line, the reports are in time order so a shifted line shows up as the point they part
+fn print_diff(expected: &str, actual: &str) {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    for i in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(i), actual.get(i));
+        if e != a {
+            if let Some(e) = e {
+                println!("    - {}", e);
+            }
+            if let Some(a) = a {
+                println!("    + {}", a);
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let bless = args.iter().any(|arg| arg == "--bless");
+    let filters: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut cases: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    cases.sort();
+
+    let mut passed = 0;
+    let mut failed = vec![];
+
+    for case in &cases {
+        let name = case.file_name().unwrap().to_string_lossy().into_owned();
+        if !filters.is_empty() && !filters.iter().any(|f| name.contains(f.as_str())) {
+            continue;
+        }
+
+        let actual = run(case);
+        let expected_path = case.join("expected.txt");
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+
+        if expected == actual {
+            println!("test {} ... ok", name);
+            passed += 1;
+        } else if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            println!("test {} ... blessed", name);
+            passed += 1;
+        } else {
+            println!("test {} ... FAILED", name);
+            print_diff(&expected, &actual);
+            failed.push(name);
+        }
+    }
+
+    println!();
+    if failed.is_empty() {
+        println!("golden: {} passed", passed);
+    } else {
+        println!(
+            "golden: {} passed, {} failed: {}",
+            passed,
+            failed.len(),
+            failed.join(", ")
+        );
+        println!("if the new reports are right, run `cargo test --test golden -- --bless`");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,339 @@
+//! Property tests, running random keymaps against random presses and releases and checking what
+//! has to hold whatever the keymap does:
+//!
+//! - once every key is released the report is empty, and only locked layers and the conditional
+//!   layers they turn on stay active
+//! - no usage is in a report twice
+//! - every usage sent belongs to a held key that could send it, or to a tap
+//! - a release takes away what the key was sending, even when the layers changed in between.
+//!   A tap goes out on the tick of the release and is gone by the next one.
+
+use config::no_std::{
+    AutoShift, Behavior, ConditionalLayer, Config, Consumer, Direction, Key, KeyOverride, Layer,
+    Mods, MouseButton, Options, SystemControl, KEYS, MAX_ALT_REPEATS, MAX_CONDITIONAL_LAYERS,
+    MAX_LED_LAYERS, MAX_OVERRIDES,
+};
+use config::NUM_LAYERS;
+use proptest::prelude::*;
+use proptest::sample::select;
+use sim::clock::{Clock, ManualClock};
+use sim::engine::{Engine, Report};
+use sim::event::KeyEvent;
+
+// Layers the keymaps use, few enough that layer keys often point at one that exists
+const LAYERS: u32 = 4;
+
+// A mix of letters, numbers, symbols, keys sent with an implicit shift, and modifiers
+const KEY_CHOICES: [Key; 16] = [
+    Key::A,
+    Key::B,
+    Key::Y,
+    Key::Z,
+    Key::N1,
+    Key::SPC,
+    Key::COMM,
+    Key::SCLN,
+    Key::LPRN,
+    Key::RCBR,
+    Key::LFT,
+    Key::RHT,
+    Key::LSFT,
+    Key::LCTL,
+    Key::LALT,
+    Key::LGUI,
+];
+const MOD_CHOICES: [Mods; 4] = [Mods::CTRL, Mods::SHIFT, Mods::ALT, Mods::GUI];
+const BUTTONS: [MouseButton; 5] = [
+    MouseButton::Left,
+    MouseButton::Right,
+    MouseButton::Middle,
+    MouseButton::Back,
+    MouseButton::Forward,
+];
+const DIRECTIONS: [Direction; 4] = [
+    Direction::Up,
+    Direction::Down,
+    Direction::Left,
+    Direction::Right,
+];
+const CONSUMERS: [Consumer; 3] = [Consumer::VolUp, Consumer::Mute, Consumer::Next];
+const SYSTEMS: [SystemControl; 2] = [SystemControl::Sleep, SystemControl::Wake];
+
+fn key() -> impl Strategy<Value = Key> {
+    select(&KEY_CHOICES[..])
+}
+
+fn mods() -> impl Strategy<Value = Mods> {
+    select(&MOD_CHOICES[..])
+}
+
+fn behavior() -> impl Strategy<Value = Behavior> {
+    prop_oneof![
+        6 => key().prop_map(Behavior::Key),
+        2 => (0..LAYERS).prop_map(Behavior::MomentaryLayer),
+        1 => (key(), key()).prop_map(|(hold, tap)| Behavior::HoldTap(hold, tap)),
+        1 => (key(), key(), mods())
+            .prop_map(|(base, morphed, mods)| Behavior::ModMorph(base, morphed, mods)),
+        1 => Just(Behavior::Repeat),
+        1 => Just(Behavior::AltRepeat),
+        1 => select(&BUTTONS[..]).prop_map(Behavior::MouseButton),
+        1 => select(&DIRECTIONS[..]).prop_map(Behavior::MouseMove),
+        1 => select(&DIRECTIONS[..]).prop_map(Behavior::MouseScroll),
+        1 => select(&CONSUMERS[..]).prop_map(Behavior::Consumer),
+        1 => select(&SYSTEMS[..]).prop_map(Behavior::System),
+        1 => Just(Behavior::None),
+        2 => Just(Behavior::Transparent),
+    ]
+}
+
+fn layer_keys() -> impl Strategy<Value = [Behavior; KEYS]> {
+    prop::collection::vec(behavior(), KEYS).prop_map(|keys| keys.try_into().unwrap())
+}
+
+fn fill<T: Copy, const N: usize>(items: &[T]) -> [Option<T>; N] {
+    let mut res = [None; N];
+    for (slot, item) in res.iter_mut().zip(items) {
+        *slot = Some(*item);
+    }
+    res
+}
+
+prop_compose! {
+    // The base layer is always there, the others might not be
+    fn keymap()(
+        base in layer_keys(),
+        upper in prop::collection::vec(prop::option::of(layer_keys()), LAYERS as usize - 1),
+        overrides in prop::collection::vec((key(), key(), mods()), 0..3),
+        alt_repeats in prop::collection::vec((key(), key()), 0..3),
+        conditional in prop::collection::vec((1u16..1 << LAYERS, 0..LAYERS), 0..3),
+        auto_shift_ms in prop::option::of(20u32..300),
+    ) -> Config {
+        let mut layers = [(); NUM_LAYERS].map(|_| None);
+        layers[0] = Some(Layer { id: 0, keys: base });
+        for (id, keys) in upper.into_iter().enumerate() {
+            let id = id as u32 + 1;
+            layers[id as usize] = keys.map(|keys| Layer { id, keys });
+        }
+
+        let overrides: Vec<KeyOverride> = overrides
+            .into_iter()
+            .map(|(trigger, replacement, mods)| KeyOverride { trigger, replacement, mods })
+            .collect();
+        let conditional: Vec<ConditionalLayer> = conditional
+            .into_iter()
+            .map(|(if_layers, then_layer)| ConditionalLayer { if_layers, then_layer })
+            .collect();
+
+        Config {
+            options: Options {
+                auto_shift: AutoShift {
+                    alpha_ms: auto_shift_ms,
+                    number_ms: auto_shift_ms,
+                    symbol_ms: auto_shift_ms,
+                },
+                ..Options::default()
+            },
+            layers,
+            overrides: fill::<_, MAX_OVERRIDES>(&overrides),
+            alt_repeats: fill::<_, MAX_ALT_REPEATS>(&alt_repeats),
+            conditional_layers: fill::<_, MAX_CONDITIONAL_LAYERS>(&conditional),
+            led_layers: [None; MAX_LED_LAYERS],
+        }
+    }
+}
+
+// Keys flipping between pressed and released, each after a random wait in ms. A wait of 0 puts
+// two events at the same time.
+fn toggles() -> impl Strategy<Value = Vec<(u8, u32)>> {
+    prop::collection::vec((0..KEYS as u8, prop_oneof![Just(0u32), 0u32..400]), 0..80)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Usage {
+    Key(Key),
+    Consumer(Consumer),
+    System(SystemControl),
+    Button(u8),
+}
+
+fn usages(report: &Report) -> Vec<Usage> {
+    let mut res: Vec<Usage> = report.keys().map(Usage::Key).collect();
+    res.extend(
+        report
+            .consumer
+            .iter()
+            .flatten()
+            .map(|c| Usage::Consumer(*c)),
+    );
+    res.extend(report.system.map(Usage::System));
+    res.extend(
+        (0..8)
+            .map(|bit| 1 << bit)
+            .filter(|bit| report.mouse.buttons & bit != 0)
+            .map(Usage::Button),
+    );
+    res
+}
+
+// Whether the key at `pos` could send `usage` on any layer, whatever was held with it
+fn could_send(config: &Config, pos: usize, usage: Usage) -> bool {
+    let sends_key = |key: Key, sent: Key| {
+        key == sent
+            || config
+                .overrides
+                .iter()
+                .flatten()
+                .any(|o| o.trigger == key && o.replacement == sent)
+    };
+
+    config
+        .layers
+        .iter()
+        .flatten()
+        .any(|layer| match (layer.keys[pos], usage) {
+            (Behavior::Repeat | Behavior::AltRepeat, _) => true,
+            (Behavior::Key(key), Usage::Key(sent)) => sends_key(key, sent),
+            (Behavior::HoldTap(hold, tap), Usage::Key(sent)) => hold == sent || tap == sent,
+            (Behavior::ModMorph(base, morphed, _), Usage::Key(sent)) => {
+                sends_key(base, sent) || morphed == sent
+            }
+            (Behavior::Consumer(c), Usage::Consumer(sent)) => c == sent,
+            (Behavior::System(s), Usage::System(sent)) => s == sent,
+            (Behavior::MouseButton(b), Usage::Button(sent)) => b.bit() == sent,
+            _ => false,
+        })
+}
+
+// Layers that stay on with nothing held
+fn resting_layers(config: &Config, locked: u16) -> u16 {
+    let mut active = 1 | locked;
+    for conditional in config.conditional_layers.iter().flatten() {
+        if active & conditional.if_layers == conditional.if_layers {
+            active |= 1 << conditional.then_layer;
+        }
+    }
+    active
+}
+
+fn check(config: &Config, locked: u16, toggles: &[(u8, u32)]) -> Result<(), TestCaseError> {
+    let mut engine = Engine::new(config);
+    engine.set_locked_layers(locked);
+
+    let mut held = [false; KEYS];
+    // What each key was sending as of the last tick, as the engine resolved it. A press can
+    // resolve another key too, ending its auto-shift wait or turning a hold-tap into a hold.
+    let mut owned: [Vec<Usage>; KEYS] = [(); KEYS].map(|_| vec![]);
+    // Taps sent on the last tick, which have to be gone on this one
+    let mut taps: Vec<Usage> = vec![];
+    let clock = ManualClock::default();
+
+    // Every key left held is released at the end
+    let mut events: Vec<(u8, u32)> = toggles.to_vec();
+    let mut still_held = [false; KEYS];
+    for &(pos, _) in toggles {
+        still_held[pos as usize] ^= true;
+    }
+    events.extend(
+        (0..KEYS as u8)
+            .filter(|&pos| still_held[pos as usize])
+            .map(|pos| (pos, 1)),
+    );
+
+    for (pos, wait) in events {
+        clock.advance(wait);
+        let now = clock.now();
+        let pos_index = pos as usize;
+        held[pos_index] ^= true;
+
+        engine.handle(KeyEvent {
+            position: pos,
+            pressed: held[pos_index],
+            timestamp: now,
+        });
+        // A key released before its auto-shift timeout or tapping term sends its tap on this tick
+        let tap = if held[pos_index] {
+            vec![]
+        } else {
+            usages(&engine.report_of(pos_index))
+        };
+        let report = engine.tick(now);
+
+        let sent = usages(&report);
+        for (i, usage) in sent.iter().enumerate() {
+            prop_assert!(
+                !sent[i + 1..].contains(usage),
+                "{:?} sent twice at t={}",
+                usage,
+                now.millis()
+            );
+        }
+
+        let released = core::mem::take(&mut owned[pos_index]);
+        for (i, slot) in owned.iter_mut().enumerate() {
+            *slot = usages(&engine.report_of(i));
+            for usage in slot.iter() {
+                prop_assert!(
+                    could_send(config, i, *usage),
+                    "{:?} sent by key {}, which can't send it, at t={}",
+                    usage,
+                    i,
+                    now.millis()
+                );
+            }
+        }
+        let by_held = |usage: &Usage| owned.iter().any(|slot| slot.contains(usage));
+
+        for usage in &sent {
+            prop_assert!(
+                by_held(usage) || tap.contains(usage),
+                "{:?} sent by no key at t={}",
+                usage,
+                now.millis()
+            );
+        }
+
+        if !held[pos_index] {
+            prop_assert!(
+                owned[pos_index].is_empty(),
+                "key {} still sending {:?} after its release at t={}",
+                pos,
+                owned[pos_index],
+                now.millis()
+            );
+            for usage in released {
+                prop_assert!(
+                    !sent.contains(&usage) || by_held(&usage) || tap.contains(&usage),
+                    "{:?} still sent after key {} was released at t={}",
+                    usage,
+                    pos,
+                    now.millis()
+                );
+            }
+        }
+
+        for usage in taps {
+            prop_assert!(
+                !sent.contains(&usage) || by_held(&usage) || tap.contains(&usage),
+                "Tap of {:?} still sent at t={}",
+                usage,
+                now.millis()
+            );
+        }
+        taps = tap;
+    }
+
+    // Taps go out on the tick of the release, and are gone by the next one
+    clock.advance(1);
+    prop_assert_eq!(engine.tick(clock.now()), Report::default());
+    prop_assert_eq!(engine.active_layers(), resting_layers(config, locked));
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn test_no_stuck_keys(config in keymap(), locked in 0u16..1 << LAYERS, toggles in toggles()) {
+        check(&config, locked, &toggles)?;
+    }
+}
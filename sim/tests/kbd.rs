@@ -0,0 +1,169 @@
+//! The `kbd` commands run in-process against the firmware's command handler, with the engine
+//! behind it, so what the tool does to a keymap is what the keyboard would do
+
+use config::kbd::{boot_status, layers, lock_layers, pull, push, set, Error};
+use config::no_std::{Behavior, Config, Key, Layer, Mods, KEYS};
+use config::parse_config;
+use config::protocol::{handle, Packet, Position, SafeModeReason, Status, Transport};
+use config::scanner::scan_input;
+use config::writer::write_config;
+use config::NUM_LAYERS;
+use sim::clock::{Clock, ManualClock};
+use sim::commands::Commands;
+use sim::engine::Engine;
+use sim::event::KeyEvent;
+use sim::keymap::default_config;
+
+/// Runs each packet through `Commands` as soon as it is sent, the way the firmware does when a
+/// raw HID report comes in
+struct InProcess {
+    engine: Engine,
+    safe_mode: Option<SafeModeReason>,
+    // Whether any command so far would have the firmware save the keymap
+    changed: bool,
+    response: Option<Packet>,
+}
+
+impl InProcess {
+    /// The default keymap, with transparent layers on top of it up to `layers`
+    fn new(layers: usize) -> Self {
+        let mut config = default_config();
+        for (id, layer) in config.layers.iter_mut().enumerate().take(layers).skip(1) {
+            *layer = Some(Layer {
+                id: id as u32,
+                keys: [Behavior::Transparent; KEYS],
+            });
+        }
+
+        Self {
+            engine: Engine::new(&config),
+            safe_mode: None,
+            changed: false,
+            response: None,
+        }
+    }
+}
+
+impl Transport for InProcess {
+    type Error = ();
+
+    fn send(&mut self, packet: &Packet) -> Result<(), ()> {
+        let mut commands = Commands::new(&mut self.engine, self.safe_mode);
+        self.response = Some(handle(&mut commands, packet));
+        self.changed |= commands.changed;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Packet, ()> {
+        self.response.take().ok_or(())
+    }
+}
+
+fn config(input: &str) -> Config {
+    parse_config(&mut scan_input(&mut input.bytes().collect()))
+}
+
+const KEYMAP: &str = "options: {}; layers: {
+        BASE: [
+            (kp A)      (kp B)          (kp C)  (kp D)   (kp E)     (kp F)
+            (ht LSFT Z) (mm COMM SCLN shift) (rep) (arep) (mkp LCLK) (mmv UP)
+            (msc DN)    (kp C_VOL_UP)   (kp SYS_SLEEP) (n) (t)      (ml NUM)
+            (kp N1)     (kp CAPS)       (n)     (n)      (n)        (n)
+        ],
+        NUM: [
+            (t) (t) (t) (t) (t) (t)
+            (t) (kp N7) (kp N8) (kp N9) (t) (t)
+            (t) (kp N4) (kp N5) (kp N6) (t) (t)
+            (t) (t) (t) (t) (t) (ml BASE)
+        ],
+    };";
+
+#[test]
+fn test_push_pull() {
+    let mut transport = InProcess::new(2);
+    let keymap = config(KEYMAP);
+
+    push(&mut transport, &keymap).unwrap();
+    assert!(transport.changed);
+    assert_eq!(transport.engine.layers(), &keymap.layers);
+    let layers = pull(&mut transport).unwrap();
+    assert_eq!(layers, keymap.layers);
+
+    // What pull writes out reads back as the same keymap
+    assert_eq!(config(&write_config(&layers)).layers, keymap.layers);
+}
+
+#[test]
+fn test_push_missing_layer() {
+    let mut transport = InProcess::new(1);
+
+    assert_eq!(
+        push(&mut transport, &config(KEYMAP)),
+        Err(Error::Device(Status::InvalidPosition))
+    );
+}
+
+#[test]
+fn test_set() {
+    let mut transport = InProcess::new(2);
+    let pos = Position {
+        layer: 1,
+        row: 2,
+        col: 3,
+    };
+    let behavior = Behavior::ModMorph(Key::BKSP, Key::DEL, Mods::SHIFT);
+
+    set(&mut transport, pos, behavior).unwrap();
+    assert!(transport.changed);
+
+    let layers = pull(&mut transport).unwrap();
+    assert_eq!(layers[1].as_ref().unwrap().keys[15], behavior);
+    assert_eq!(layers[2], None);
+
+    // The engine types with it straight away, once its layer is on
+    set(
+        &mut transport,
+        Position {
+            layer: 0,
+            row: 0,
+            col: 0,
+        },
+        Behavior::Key(Key::Z),
+    )
+    .unwrap();
+    let clock = ManualClock::default();
+    transport.engine.handle(KeyEvent {
+        position: 0,
+        pressed: true,
+        timestamp: clock.now(),
+    });
+    assert!(transport.engine.tick(clock.now()).keys().eq([Key::Z]));
+}
+
+#[test]
+fn test_layers() {
+    let mut transport = InProcess::new(3);
+
+    assert_eq!(layers(&mut transport), Ok(0b001));
+    assert!(!transport.changed);
+    lock_layers(&mut transport, 0b100).unwrap();
+    assert!(transport.changed);
+    assert_eq!(transport.engine.locked_layers(), 0b100);
+    assert_eq!(layers(&mut transport), Ok(0b101));
+    assert_eq!(
+        lock_layers(&mut transport, 1 << NUM_LAYERS),
+        Err(Error::Device(Status::InvalidArgs))
+    );
+}
+
+#[test]
+fn test_boot_status() {
+    let mut transport = InProcess::new(1);
+
+    assert_eq!(boot_status(&mut transport), Ok(None));
+    transport.safe_mode = Some(SafeModeReason::Panicked);
+    assert_eq!(
+        boot_status(&mut transport),
+        Ok(Some(SafeModeReason::Panicked))
+    );
+}
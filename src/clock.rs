@@ -0,0 +1,161 @@
+//! Time as the rest of the firmware sees it. Debouncing, auto-shift, mouse acceleration and saving
+//! edits all go by an `Instant` taken from a `Clock` rather than reading a timer, so tests can drive
+//! them with a `ManualClock` and get the same result every run.
+
+use core::cell::Cell;
+use core::ops::Add;
+
+/// A point in time in ms, counted from an arbitrary start such as boot
+///
+/// The count wraps after about 49 days, so instants aren't ordered; compare them through
+/// `millis_since`, which is right as long as the two are less than that apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Instant(u32);
+
+impl Instant {
+    pub const fn from_millis(ms: u32) -> Self {
+        Self(ms)
+    }
+
+    pub const fn millis(self) -> u32 {
+        self.0
+    }
+
+    /// Time from `earlier` to `self` in ms
+    pub const fn millis_since(self, earlier: Instant) -> u32 {
+        self.0.wrapping_sub(earlier.0)
+    }
+}
+
+impl Add<u32> for Instant {
+    type Output = Self;
+
+    fn add(self, ms: u32) -> Self {
+        Self(self.0.wrapping_add(ms))
+    }
+}
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// A clock that only moves when told to, for tests and the simulator
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Cell<Instant>,
+}
+
+impl ManualClock {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            now: Cell::new(start),
+        }
+    }
+
+    pub fn advance(&self, ms: u32) {
+        self.now.set(self.now.get() + ms);
+    }
+
+    pub fn set(&self, now: Instant) {
+        self.now.set(now);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/// Fires once every `period_ms`, for work the main loop does on a schedule. A loop that falls
+/// behind gets one late tick rather than a burst of them.
+#[derive(Debug, Clone)]
+pub struct Ticker {
+    period_ms: u32,
+    next: Instant,
+}
+
+impl Ticker {
+    /// The first tick is `period_ms` after `now`
+    pub fn new(now: Instant, period_ms: u32) -> Self {
+        Self {
+            period_ms,
+            next: now + period_ms,
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> bool {
+        // `next` is still ahead, `now` only looks far past it because the count wraps
+        if now.millis_since(self.next) > u32::MAX / 2 {
+            return false;
+        }
+
+        self.next = if now.millis_since(self.next) >= self.period_ms {
+            now + self.period_ms
+        } else {
+            self.next + self.period_ms
+        };
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, Instant, ManualClock, Ticker};
+
+    #[test]
+    fn test_instant() {
+        let start = Instant::from_millis(100);
+        assert_eq!((start + 20).millis(), 120);
+        assert_eq!((start + 20).millis_since(start), 20);
+
+        // Right across the wrap
+        let late = Instant::from_millis(u32::MAX - 4);
+        assert_eq!(late + 10, Instant::from_millis(5));
+        assert_eq!((late + 10).millis_since(late), 10);
+    }
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(Instant::from_millis(10));
+        assert_eq!(clock.now(), Instant::from_millis(10));
+
+        clock.advance(5);
+        assert_eq!(clock.now(), Instant::from_millis(15));
+        clock.set(Instant::from_millis(3));
+        assert_eq!(clock.now(), Instant::from_millis(3));
+    }
+
+    #[test]
+    fn test_ticker() {
+        let clock = ManualClock::default();
+        let mut ticker = Ticker::new(clock.now(), 10);
+
+        let mut ticks = [false; 31];
+        for tick in ticks.iter_mut() {
+            *tick = ticker.poll(clock.now());
+            clock.advance(1);
+        }
+        let times: [usize; 3] = [10, 20, 30];
+        for (t, &tick) in ticks.iter().enumerate() {
+            assert_eq!(tick, times.contains(&t), "t={}", t);
+        }
+
+        // Behind by more than a period, it ticks once and starts over from there
+        clock.advance(34);
+        assert!(ticker.poll(clock.now()));
+        assert!(!ticker.poll(clock.now()));
+        clock.advance(9);
+        assert!(!ticker.poll(clock.now()));
+        clock.advance(1);
+        assert!(ticker.poll(clock.now()));
+
+        // Across the wrap
+        let clock = ManualClock::new(Instant::from_millis(u32::MAX - 2));
+        let mut ticker = Ticker::new(clock.now(), 5);
+        clock.advance(4);
+        assert!(!ticker.poll(clock.now()));
+        clock.advance(1);
+        assert!(ticker.poll(clock.now()));
+    }
+}
@@ -0,0 +1,59 @@
+//! Raw HID commands, run against the live engine
+
+use config::no_std::{Behavior, KEYS};
+use config::protocol::{Keyboard, SafeModeReason};
+
+use crate::engine::Engine;
+
+pub struct Commands<'a> {
+    engine: &'a mut Engine,
+    safe_mode: Option<SafeModeReason>,
+    // Set once the host asks for the bootloader, the caller resets after sending the response
+    pub bootloader: bool,
+    // Set when the keymap or locked layers changed, so the caller can save them
+    pub changed: bool,
+}
+
+impl<'a> Commands<'a> {
+    pub fn new(engine: &'a mut Engine, safe_mode: Option<SafeModeReason>) -> Self {
+        Self {
+            engine,
+            safe_mode,
+            bootloader: false,
+            changed: false,
+        }
+    }
+}
+
+impl Keyboard for Commands<'_> {
+    fn behavior(&self, layer: usize, pos: usize) -> Option<Behavior> {
+        self.engine.behavior(layer, pos)
+    }
+
+    fn set_behavior(&mut self, layer: usize, pos: usize, behavior: Behavior) -> bool {
+        let res = self.engine.set_behavior(layer, pos, behavior);
+        self.changed |= res;
+        res
+    }
+
+    fn active_layers(&self) -> u16 {
+        self.engine.active_layers()
+    }
+
+    fn set_active_layers(&mut self, layers: u16) {
+        self.engine.set_locked_layers(layers);
+        self.changed = true;
+    }
+
+    fn matrix(&self) -> [bool; KEYS] {
+        *self.engine.matrix()
+    }
+
+    fn reset_to_bootloader(&mut self) {
+        self.bootloader = true;
+    }
+
+    fn safe_mode(&self) -> Option<SafeModeReason> {
+        self.safe_mode
+    }
+}
@@ -0,0 +1,337 @@
+//! Debouncing, filtering the chatter a switch makes as its contacts open and close so one press
+//! isn't seen as several
+//!
+//! Each algorithm takes a raw scan of the matrix with the time it was taken and returns the debounced
+//! state. Eager algorithms report a change straight away and then ignore the key for the debounce
+//! time, deferred ones wait until the key has been stable for that long.
+
+use crate::clock::Instant;
+
+/// Turns raw matrix scans into debounced key states
+pub trait Debouncer<const N: usize> {
+    fn update(&mut self, now: Instant, raw: &[bool; N]) -> [bool; N];
+}
+
+/// Reports each key as soon as it changes, then ignores it until the debounce time has passed
+#[derive(Debug, Clone)]
+pub struct EagerPerKey<const N: usize> {
+    debounce_ms: u32,
+    state: [bool; N],
+    // When each key last changed, while its changes are being ignored
+    locked_at: [Option<Instant>; N],
+}
+
+impl<const N: usize> EagerPerKey<N> {
+    pub fn new(debounce_ms: u32) -> Self {
+        Self {
+            debounce_ms,
+            state: [false; N],
+            locked_at: [None; N],
+        }
+    }
+}
+
+impl<const N: usize> Debouncer<N> for EagerPerKey<N> {
+    fn update(&mut self, now: Instant, raw: &[bool; N]) -> [bool; N] {
+        for (i, &raw) in raw.iter().enumerate() {
+            if let Some(at) = self.locked_at[i] {
+                if now.millis_since(at) < self.debounce_ms {
+                    continue;
+                }
+                self.locked_at[i] = None;
+            }
+
+            if raw != self.state[i] {
+                self.state[i] = raw;
+                self.locked_at[i] = Some(now);
+            }
+        }
+
+        self.state
+    }
+}
+
+/// Reports a key once it has been stable for the debounce time, timed separately for each key
+#[derive(Debug, Clone)]
+pub struct DeferPerKey<const N: usize> {
+    debounce_ms: u32,
+    state: [bool; N],
+    last: [bool; N],
+    changed_at: [Instant; N],
+}
+
+impl<const N: usize> DeferPerKey<N> {
+    pub fn new(debounce_ms: u32) -> Self {
+        Self {
+            debounce_ms,
+            state: [false; N],
+            last: [false; N],
+            changed_at: [Instant::from_millis(0); N],
+        }
+    }
+}
+
+impl<const N: usize> Debouncer<N> for DeferPerKey<N> {
+    fn update(&mut self, now: Instant, raw: &[bool; N]) -> [bool; N] {
+        for (i, &raw) in raw.iter().enumerate() {
+            if raw != self.last[i] {
+                self.last[i] = raw;
+                self.changed_at[i] = now;
+            }
+
+            if now.millis_since(self.changed_at[i]) >= self.debounce_ms {
+                self.state[i] = self.last[i];
+            }
+        }
+
+        self.state
+    }
+}
+
+/// Reports the whole matrix once no key has changed for the debounce time. The cheapest to run,
+/// but a key bouncing holds back changes to every other key.
+#[derive(Debug, Clone)]
+pub struct SymmetricDefer<const N: usize> {
+    debounce_ms: u32,
+    state: [bool; N],
+    last: [bool; N],
+    changed_at: Instant,
+}
+
+impl<const N: usize> SymmetricDefer<N> {
+    pub fn new(debounce_ms: u32) -> Self {
+        Self {
+            debounce_ms,
+            state: [false; N],
+            last: [false; N],
+            changed_at: Instant::from_millis(0),
+        }
+    }
+}
+
+impl<const N: usize> Debouncer<N> for SymmetricDefer<N> {
+    fn update(&mut self, now: Instant, raw: &[bool; N]) -> [bool; N] {
+        if *raw != self.last {
+            self.last = *raw;
+            self.changed_at = now;
+        }
+
+        if now.millis_since(self.changed_at) >= self.debounce_ms {
+            self.state = self.last;
+        }
+
+        self.state
+    }
+}
+
+/// Reports presses straight away and releases once the key has been up for the debounce time.
+/// Presses are as fast as they can be, and bounces while held can't cause a second press.
+#[derive(Debug, Clone)]
+pub struct AsymmetricEagerDefer<const N: usize> {
+    debounce_ms: u32,
+    state: [bool; N],
+    last: [bool; N],
+    changed_at: [Instant; N],
+}
+
+impl<const N: usize> AsymmetricEagerDefer<N> {
+    pub fn new(debounce_ms: u32) -> Self {
+        Self {
+            debounce_ms,
+            state: [false; N],
+            last: [false; N],
+            changed_at: [Instant::from_millis(0); N],
+        }
+    }
+}
+
+impl<const N: usize> Debouncer<N> for AsymmetricEagerDefer<N> {
+    fn update(&mut self, now: Instant, raw: &[bool; N]) -> [bool; N] {
+        for (i, &raw) in raw.iter().enumerate() {
+            if raw != self.last[i] {
+                self.last[i] = raw;
+                self.changed_at[i] = now;
+            }
+
+            if self.last[i] {
+                self.state[i] = true;
+            } else if now.millis_since(self.changed_at[i]) >= self.debounce_ms {
+                self.state[i] = false;
+            }
+        }
+
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, Instant, ManualClock};
+    use crate::debounce::{
+        AsymmetricEagerDefer, Debouncer, DeferPerKey, EagerPerKey, SymmetricDefer,
+    };
+
+    const DEBOUNCE_MS: u32 = 5;
+
+    // One key scanned every ms: it bounces for 3ms on the way down, is held until 20ms, then
+    // bounces for 3ms on the way up
+    const BOUNCY: [bool; 30] = {
+        let mut trace = [false; 30];
+        let mut t = 2;
+        while t < 20 {
+            trace[t] = true;
+            t += 1;
+        }
+        trace[3] = false;
+        trace[21] = true;
+        trace[23] = true;
+        trace
+    };
+
+    // A single 1ms blip from noise
+    const BLIP: [bool; 30] = {
+        let mut trace = [false; 30];
+        trace[10] = true;
+        trace
+    };
+
+    // Runs a one-key trace, returning the debounced state at each ms
+    fn run<const T: usize>(debouncer: &mut impl Debouncer<1>, trace: &[bool; T]) -> [bool; T] {
+        let clock = ManualClock::default();
+        let mut res = [false; T];
+        for (t, raw) in trace.iter().enumerate() {
+            res[t] = debouncer.update(clock.now(), &[*raw])[0];
+            clock.advance(1);
+        }
+        res
+    }
+
+    // Times at which the debounced state changed
+    fn edges<const T: usize>(states: &[bool; T]) -> [Option<u32>; 4] {
+        let mut res = [None; 4];
+        let mut last = false;
+        let mut i = 0;
+        for (t, &state) in states.iter().enumerate() {
+            if state != last {
+                res[i] = Some(t as u32);
+                i += 1;
+                last = state;
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_eager_per_key() {
+        let mut debouncer = EagerPerKey::new(DEBOUNCE_MS);
+        // Down on the first contact, up on the first break after the bounces settle
+        assert_eq!(
+            edges(&run(&mut debouncer, &BOUNCY)),
+            [Some(2), Some(20), None, None]
+        );
+
+        // Noise gets through, held for the debounce time
+        let mut debouncer = EagerPerKey::new(DEBOUNCE_MS);
+        assert_eq!(
+            edges(&run(&mut debouncer, &BLIP)),
+            [Some(10), Some(15), None, None]
+        );
+    }
+
+    #[test]
+    fn test_defer_per_key() {
+        let mut debouncer = DeferPerKey::new(DEBOUNCE_MS);
+        // Down 5ms after the last bounce at 4, up 5ms after the last bounce at 24
+        assert_eq!(
+            edges(&run(&mut debouncer, &BOUNCY)),
+            [Some(9), Some(29), None, None]
+        );
+
+        let mut debouncer = DeferPerKey::new(DEBOUNCE_MS);
+        assert_eq!(edges(&run(&mut debouncer, &BLIP)), [None; 4]);
+    }
+
+    #[test]
+    fn test_symmetric_defer() {
+        let mut debouncer = SymmetricDefer::new(DEBOUNCE_MS);
+        assert_eq!(
+            edges(&run(&mut debouncer, &BOUNCY)),
+            [Some(9), Some(29), None, None]
+        );
+
+        let mut debouncer = SymmetricDefer::new(DEBOUNCE_MS);
+        assert_eq!(edges(&run(&mut debouncer, &BLIP)), [None; 4]);
+
+        // A bouncing key holds back a clean press on another key
+        let mut debouncer = SymmetricDefer::new(DEBOUNCE_MS);
+        let mut pressed_at = None;
+        for t in 0..30 {
+            let raw = [t >= 2, BOUNCY[t as usize]];
+            if pressed_at.is_none() && debouncer.update(Instant::from_millis(t), &raw)[0] {
+                pressed_at = Some(t);
+            }
+        }
+        assert_eq!(pressed_at, Some(9));
+    }
+
+    #[test]
+    fn test_asymmetric_eager_defer() {
+        let mut debouncer = AsymmetricEagerDefer::new(DEBOUNCE_MS);
+        // Down on the first contact, up 5ms after the last bounce
+        assert_eq!(
+            edges(&run(&mut debouncer, &BOUNCY)),
+            [Some(2), Some(29), None, None]
+        );
+
+        let mut debouncer = AsymmetricEagerDefer::new(DEBOUNCE_MS);
+        assert_eq!(
+            edges(&run(&mut debouncer, &BLIP)),
+            [Some(10), Some(16), None, None]
+        );
+    }
+
+    #[test]
+    fn test_per_key() {
+        // Keys are timed separately, a bouncing key doesn't hold back another
+        let mut eager = EagerPerKey::new(DEBOUNCE_MS);
+        let mut defer = DeferPerKey::new(DEBOUNCE_MS);
+        let mut asymmetric = AsymmetricEagerDefer::new(DEBOUNCE_MS);
+
+        let mut pressed_at = [None; 3];
+        for t in 0..30 {
+            let raw = [t >= 2, BOUNCY[t as usize]];
+            let states = [
+                eager.update(Instant::from_millis(t), &raw)[0],
+                defer.update(Instant::from_millis(t), &raw)[0],
+                asymmetric.update(Instant::from_millis(t), &raw)[0],
+            ];
+            for (at, state) in pressed_at.iter_mut().zip(states) {
+                if at.is_none() && state {
+                    *at = Some(t);
+                }
+            }
+        }
+        assert_eq!(pressed_at, [Some(2), Some(7), Some(2)]);
+    }
+
+    #[test]
+    fn test_no_debounce() {
+        // With no debounce time every algorithm passes the raw state through
+        let mut debouncers: (
+            EagerPerKey<1>,
+            DeferPerKey<1>,
+            SymmetricDefer<1>,
+            AsymmetricEagerDefer<1>,
+        ) = (
+            EagerPerKey::new(0),
+            DeferPerKey::new(0),
+            SymmetricDefer::new(0),
+            AsymmetricEagerDefer::new(0),
+        );
+
+        assert_eq!(run(&mut debouncers.0, &BOUNCY), BOUNCY);
+        assert_eq!(run(&mut debouncers.1, &BOUNCY), BOUNCY);
+        assert_eq!(run(&mut debouncers.2, &BOUNCY), BOUNCY);
+        assert_eq!(run(&mut debouncers.3, &BOUNCY), BOUNCY);
+    }
+}
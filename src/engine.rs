@@ -0,0 +1,841 @@
+//! This file turns key events into HID reports according to the keymap
+
+use config::no_std::{
+    AutoShift, Behavior, ConditionalLayer, Config, Consumer, Direction, Key, KeyOverride, Layer,
+    LedLayer, Mods, MouseButton, MouseCurve, SystemControl, KEYS, MAX_ALT_REPEATS,
+    MAX_CONDITIONAL_LAYERS, MAX_LED_LAYERS, MAX_OVERRIDES,
+};
+use config::NUM_LAYERS;
+
+use crate::clock::Instant;
+use crate::event::KeyEvent;
+use crate::leds::HostLeds;
+use crate::mouse::{self, Integrator, MouseReport, Velocity};
+
+/// Consumer keys that can be pressed at once
+pub const CONSUMER_SLOTS: usize = 4;
+/// Tapping term for keymaps that don't set `tapping_term_ms`
+const DEFAULT_TAPPING_TERM_MS: u32 = 200;
+
+/// What a physical key is doing while it is held. Resolved once on press so that layer changes
+/// while the key is down don't change what gets released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Active {
+    Released,
+    Key(Key),
+    Layer(u32),
+    // Replacement key from a mod-morph or override, and the modifiers it suppresses
+    Morph(Key, Mods),
+    // Auto-shift key waiting for release or timeout, with the time it was pressed
+    Pending(Key, Instant),
+    // Auto-shift key held past its timeout
+    Shifted(Key),
+    // Hold-tap waiting for release, timeout or another key, with its hold and tap keys and the
+    // time it was pressed
+    HoldTap(Key, Key, Instant),
+    // Key released before its auto-shift timeout or tapping term, sent in the next report only
+    Tap(Key),
+    // Repeat of an earlier key, with the modifiers it was sent with
+    Repeat(Key, Mods),
+    MouseButton(MouseButton),
+    // Mouse movement and scrolling, with the time they started for acceleration
+    MouseMove(Direction, Instant),
+    MouseScroll(Direction, Instant),
+    Consumer(Consumer),
+    System(SystemControl),
+    // Pressed, but bound to nothing
+    Noop,
+}
+
+/// Everything to send to the host, split by the HID interface it goes out on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub mods: Mods,
+    keys: [Option<Key>; KEYS],
+    pub mouse: MouseReport,
+    pub consumer: [Option<Consumer>; CONSUMER_SLOTS],
+    pub system: Option<SystemControl>,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            mods: Mods::NONE,
+            keys: [None; KEYS],
+            mouse: MouseReport::default(),
+            consumer: [None; CONSUMER_SLOTS],
+            system: None,
+        }
+    }
+}
+
+impl Report {
+    pub fn press(&mut self, key: Key) {
+        self.mods = self.mods.union(key.modifier()).union(key.implicit_mods());
+
+        if !key.modifier().is_empty() || self.keys.contains(&Some(key)) {
+            return;
+        }
+
+        if let Some(slot) = self.keys.iter_mut().find(|k| k.is_none()) {
+            *slot = Some(key);
+        }
+    }
+
+    /// Remove modifiers from the report, used when a behavior replaces a modified key
+    pub fn mask(&mut self, mods: Mods) {
+        self.mods = self.mods.difference(mods);
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.keys.iter().flatten().copied()
+    }
+}
+
+pub struct Engine {
+    layers: [Option<Layer>; NUM_LAYERS],
+    overrides: [Option<KeyOverride>; MAX_OVERRIDES],
+    auto_shift: AutoShift,
+    tapping_term_ms: u32,
+    alt_repeats: [Option<(Key, Key)>; MAX_ALT_REPEATS],
+    // Last key sent and the modifiers sent with it, for the repeat behaviors
+    last: Option<(Key, Mods)>,
+    conditional_layers: [Option<ConditionalLayer>; MAX_CONDITIONAL_LAYERS],
+    led_layers: [Option<LedLayer>; MAX_LED_LAYERS],
+    host_leds: HostLeds,
+    mouse_move: MouseCurve,
+    mouse_scroll: MouseCurve,
+    mouse: Integrator,
+    // Layers switched on over raw HID, on top of the ones the keymap turns on
+    locked_layers: u16,
+    // Bit n is set while layer n is active, the base layer is always active
+    active_layers: u16,
+    matrix: [bool; KEYS],
+    keys: [Active; KEYS],
+}
+
+impl Engine {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            layers: config.layers.clone(),
+            overrides: config.overrides,
+            auto_shift: config.options.auto_shift,
+            tapping_term_ms: config
+                .options
+                .tapping_term_ms
+                .unwrap_or(DEFAULT_TAPPING_TERM_MS),
+            alt_repeats: config.alt_repeats,
+            last: None,
+            conditional_layers: config.conditional_layers,
+            led_layers: config.led_layers,
+            host_leds: HostLeds::default(),
+            mouse_move: config.options.mouse_move,
+            mouse_scroll: config.options.mouse_scroll,
+            mouse: Integrator::default(),
+            locked_layers: 0,
+            active_layers: 1,
+            matrix: [false; KEYS],
+            keys: [Active::Released; KEYS],
+        }
+    }
+
+    /// Apply a press or release, events must come in the order they happened
+    pub fn handle(&mut self, event: KeyEvent) {
+        let pos = event.position as usize;
+        self.matrix[pos] = event.pressed;
+
+        match (event.pressed, self.keys[pos]) {
+            // A tap not yet reported is dropped if the key goes down again first
+            (true, Active::Released | Active::Tap(_)) => self.press(event.timestamp, pos),
+            (false, Active::Released) | (true, _) => {}
+            (false, _) => self.release(pos),
+        }
+    }
+
+    /// Apply whatever is due by `now` and build the report to send
+    pub fn tick(&mut self, now: Instant) -> Report {
+        for pos in 0..KEYS {
+            match self.keys[pos] {
+                Active::Pending(key, since)
+                    if self
+                        .auto_shift
+                        .timeout_ms(key)
+                        .is_some_and(|timeout| now.millis_since(since) >= timeout) =>
+                {
+                    self.keys[pos] = Active::Shifted(key);
+                    self.remember(key, self.held_mods().union(Mods::SHIFT));
+                }
+                Active::HoldTap(hold, _, since)
+                    if now.millis_since(since) >= self.tapping_term_ms =>
+                {
+                    self.keys[pos] = Active::Key(hold);
+                    self.remember(hold, self.held_mods());
+                }
+                _ => {}
+            }
+        }
+
+        let mut report = self.report();
+        report.mouse = self.mouse_report(now);
+
+        // Taps are in this report, the key was already released
+        for active in self.keys.iter_mut() {
+            if let Active::Tap(_) = active {
+                *active = Active::Released;
+            }
+        }
+
+        report
+    }
+
+    /// Take new LED state from the host, which can turn LED layers on or off
+    pub fn set_host_leds(&mut self, leds: HostLeds) {
+        self.host_leds = leds;
+        self.update_layers();
+    }
+
+    /// Behavior of key `pos` on `layer`, `None` if the keymap doesn't define the layer
+    pub fn behavior(&self, layer: usize, pos: usize) -> Option<Behavior> {
+        self.layers[layer].as_ref().map(|l| l.keys[pos])
+    }
+
+    /// Change the keymap, keys already held keep doing what they did when pressed. Returns false
+    /// if the keymap doesn't define the layer.
+    pub fn set_behavior(&mut self, layer: usize, pos: usize, behavior: Behavior) -> bool {
+        match &mut self.layers[layer] {
+            Some(l) => {
+                l.keys[pos] = behavior;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn layers(&self) -> &[Option<Layer>; NUM_LAYERS] {
+        &self.layers
+    }
+
+    pub fn active_layers(&self) -> u16 {
+        self.active_layers
+    }
+
+    pub fn locked_layers(&self) -> u16 {
+        self.locked_layers
+    }
+
+    /// Keep `layers` active until changed again, whatever keys are held
+    pub fn set_locked_layers(&mut self, layers: u16) {
+        self.locked_layers = layers;
+        self.update_layers();
+    }
+
+    /// Keys held according to the events so far
+    pub fn matrix(&self) -> &[bool; KEYS] {
+        &self.matrix
+    }
+
+    /// What key `pos` adds to the report on its own, as it was resolved. A tap shows up here after
+    /// the release until the next tick.
+    pub fn report_of(&self, pos: usize) -> Report {
+        let mut report = Self::report_from(core::slice::from_ref(&self.keys[pos]));
+        if let Active::MouseButton(button) = self.keys[pos] {
+            report.mouse.buttons = button.bit();
+        }
+        report
+    }
+
+    fn press(&mut self, now: Instant, pos: usize) {
+        // A hold-tap is held if another key goes down before it is released, so a mod-tap applies
+        // its modifier to that key
+        for i in 0..KEYS {
+            if let Active::HoldTap(hold, _, _) = self.keys[i] {
+                self.keys[i] = Active::Key(hold);
+                self.remember(hold, self.held_mods());
+            }
+        }
+
+        let held = self.held_mods();
+
+        // Typing another key ends any auto-shift wait, otherwise the keys would be sent out of
+        // order
+        for i in 0..KEYS {
+            if let Active::Pending(key, _) = self.keys[i] {
+                self.keys[i] = Active::Key(key);
+                self.remember(key, held);
+            }
+        }
+
+        self.keys[pos] = match self.lookup(pos) {
+            Behavior::Key(key) => match self.find_override(key, held) {
+                Some(o) => self.morph(o.replacement, o.mods.intersection(held), held),
+                None if self.auto_shift.timeout_ms(key).is_some() => Active::Pending(key, now),
+                None => {
+                    self.remember(key, held);
+                    Active::Key(key)
+                }
+            },
+            Behavior::ModMorph(base, morphed, mods) => {
+                if held.intersection(mods).is_empty() {
+                    self.remember(base, held);
+                    Active::Key(base)
+                } else {
+                    self.morph(morphed, mods.intersection(held), held)
+                }
+            }
+            Behavior::Repeat => match self.last {
+                Some((key, mods)) => Active::Repeat(key, mods),
+                None => Active::Noop,
+            },
+            Behavior::AltRepeat => match self.last {
+                Some((key, mods)) => match self.alternate(key) {
+                    Some(alt) => Active::Repeat(alt, mods),
+                    None => Active::Noop,
+                },
+                None => Active::Noop,
+            },
+            Behavior::MomentaryLayer(layer) => Active::Layer(layer),
+            Behavior::MouseButton(button) => Active::MouseButton(button),
+            Behavior::MouseMove(direction) => Active::MouseMove(direction, now),
+            Behavior::MouseScroll(direction) => Active::MouseScroll(direction, now),
+            Behavior::Consumer(consumer) => Active::Consumer(consumer),
+            Behavior::System(system) => Active::System(system),
+            Behavior::HoldTap(hold, tap) => Active::HoldTap(hold, tap, now),
+            Behavior::None | Behavior::Transparent => Active::Noop,
+        };
+
+        self.update_layers();
+    }
+
+    fn release(&mut self, pos: usize) {
+        self.keys[pos] = match self.keys[pos] {
+            Active::Pending(key, _) | Active::HoldTap(_, key, _) => {
+                self.remember(key, self.held_mods());
+                Active::Tap(key)
+            }
+            _ => Active::Released,
+        };
+
+        self.update_layers();
+    }
+
+    /// Recompute the active layers from held layer keys, locked layers and host LEDs, then apply
+    /// conditional layers in order. A conditional layer can be part of the condition for the ones
+    /// after it.
+    fn update_layers(&mut self) {
+        let held = self.keys.iter().fold(1, |acc, active| match active {
+            Active::Layer(layer) => acc | (1 << layer),
+            _ => acc,
+        });
+        let mut active = held | self.locked_layers;
+
+        for led_layer in self.led_layers.iter().flatten() {
+            if self.host_leds.is_on(led_layer.led) {
+                active |= 1 << led_layer.layer;
+            }
+        }
+
+        for conditional in self.conditional_layers.iter().flatten() {
+            if active & conditional.if_layers == conditional.if_layers {
+                active |= 1 << conditional.then_layer;
+            }
+        }
+
+        self.active_layers = active;
+    }
+
+    fn morph(&mut self, key: Key, masked: Mods, held: Mods) -> Active {
+        self.remember(key, held.difference(masked));
+        Active::Morph(key, masked)
+    }
+
+    /// Keep track of the last key sent for `rep` and `arep`. Modifiers on their own don't count,
+    /// so that e.g. Ctrl then `rep` repeats Ctrl+key. Repeats don't count either, so `arep` after
+    /// `rep` still alternates the original key.
+    fn remember(&mut self, key: Key, mods: Mods) {
+        if key.modifier().is_empty() {
+            self.last = Some((key, mods));
+        }
+    }
+
+    /// Counterpart of a key for `arep`, pairs apply in both directions
+    fn alternate(&self, key: Key) -> Option<Key> {
+        self.alt_repeats.iter().flatten().find_map(|&(a, b)| {
+            if a == key {
+                Some(b)
+            } else if b == key {
+                Some(a)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Find the behavior for a key on the highest active layer, falling through transparent keys
+    fn lookup(&self, pos: usize) -> Behavior {
+        for id in (0..NUM_LAYERS).rev() {
+            if self.active_layers & (1 << id) == 0 {
+                continue;
+            }
+
+            if let Some(layer) = &self.layers[id] {
+                if layer.keys[pos] != Behavior::Transparent {
+                    return layer.keys[pos];
+                }
+            }
+        }
+
+        Behavior::None
+    }
+
+    fn find_override(&self, key: Key, held: Mods) -> Option<KeyOverride> {
+        self.overrides
+            .iter()
+            .flatten()
+            .find(|o| o.trigger == key && !o.mods.intersection(held).is_empty())
+            .copied()
+    }
+
+    /// Modifiers currently held by plain key presses
+    fn held_mods(&self) -> Mods {
+        self.keys
+            .iter()
+            .fold(Mods::NONE, |acc, active| match active {
+                Active::Key(key) => acc.union(key.modifier()),
+                _ => acc,
+            })
+    }
+
+    fn report(&self) -> Report {
+        Self::report_from(&self.keys)
+    }
+
+    // Mouse buttons are left to `mouse_report`
+    fn report_from(keys: &[Active]) -> Report {
+        let mut report = Report::default();
+        let mut masked = Mods::NONE;
+
+        for active in keys.iter() {
+            match *active {
+                Active::Key(key) | Active::Tap(key) => report.press(key),
+                Active::Shifted(key) => {
+                    report.press(key);
+                    report.mods = report.mods.union(Mods::SHIFT);
+                }
+                Active::Repeat(key, mods) => {
+                    report.press(key);
+                    report.mods = report.mods.union(mods);
+                }
+                // Two keys on the same usage are sent once, like keys in the keyboard report
+                Active::Consumer(consumer) if report.consumer.contains(&Some(consumer)) => {}
+                Active::Consumer(consumer) => {
+                    // Extra presses past the report size are dropped
+                    if let Some(slot) = report.consumer.iter_mut().find(|c| c.is_none()) {
+                        *slot = Some(consumer);
+                    }
+                }
+                // The system control report only has room for one usage
+                Active::System(system) => report.system = Some(system),
+                Active::Morph(key, mods) => {
+                    report.press(key);
+                    masked = masked.union(mods);
+                }
+                Active::Released
+                | Active::Layer(_)
+                | Active::Pending(..)
+                | Active::HoldTap(..)
+                | Active::MouseButton(_)
+                | Active::MouseMove(..)
+                | Active::MouseScroll(..)
+                | Active::Noop => {}
+            }
+        }
+
+        report.mask(masked);
+        report
+    }
+
+    fn mouse_report(&mut self, now: Instant) -> MouseReport {
+        let mut buttons = 0;
+        let mut velocity = Velocity::default();
+
+        for active in self.keys.iter() {
+            match *active {
+                Active::MouseButton(button) => buttons |= button.bit(),
+                Active::MouseMove(direction, since) => velocity.add_move(
+                    direction,
+                    mouse::speed(&self.mouse_move, now.millis_since(since)),
+                ),
+                Active::MouseScroll(direction, since) => velocity.add_scroll(
+                    direction,
+                    mouse::speed(&self.mouse_scroll, now.millis_since(since)),
+                ),
+                _ => {}
+            }
+        }
+
+        let [x, y, wheel, pan] = self.mouse.step(now, velocity);
+
+        MouseReport {
+            buttons,
+            x,
+            y,
+            wheel,
+            pan,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::no_std::{
+        AutoShift, Behavior, ConditionalLayer, Config, Key, KeyOverride, Layer, Led, LedLayer,
+        Mods, KEYS,
+    };
+
+    use crate::clock::{Clock, ManualClock};
+    use crate::engine::{Engine, Report};
+    use crate::event::KeyEvent;
+    use crate::keymap::default_config;
+    use crate::leds::HostLeds;
+
+    /// The engine with a clock in ms, pressing and releasing keys one event at a time
+    struct Board {
+        engine: Engine,
+        clock: ManualClock,
+    }
+
+    impl Board {
+        fn new(config: &Config) -> Self {
+            Self {
+                engine: Engine::new(config),
+                clock: ManualClock::default(),
+            }
+        }
+
+        fn press(&mut self, pos: usize) -> Report {
+            self.event(pos, true)
+        }
+
+        fn release(&mut self, pos: usize) -> Report {
+            self.event(pos, false)
+        }
+
+        fn event(&mut self, pos: usize, pressed: bool) -> Report {
+            self.engine.handle(KeyEvent {
+                position: pos as u8,
+                pressed,
+                timestamp: self.clock.now(),
+            });
+            self.engine.tick(self.clock.now())
+        }
+
+        /// Tick again `ms` later with nothing changed
+        fn wait(&mut self, ms: u32) -> Report {
+            self.clock.advance(ms);
+            self.engine.tick(self.clock.now())
+        }
+    }
+
+    /// The default keymap with `keys` at the start of the base layer
+    fn config(keys: &[Behavior]) -> Config {
+        let mut config = default_config();
+        let base = config.layers[0].as_mut().unwrap();
+        base.keys[..keys.len()].copy_from_slice(keys);
+        config
+    }
+
+    fn assert_report(report: Report, mods: Mods, keys: &[Key]) {
+        assert_eq!(report.mods, mods);
+        assert!(report.keys().eq(keys.iter().copied()), "{:?}", report);
+    }
+
+    #[test]
+    fn test_mod_morph() {
+        let mm = Behavior::ModMorph(Key::BKSP, Key::DEL, Mods::SHIFT);
+        let mut board = Board::new(&config(&[
+            Behavior::Key(Key::LSFT),
+            Behavior::Key(Key::LCTL),
+            mm,
+        ]));
+
+        assert_report(board.press(2), Mods::NONE, &[Key::BKSP]);
+        board.release(2);
+
+        // Shift is masked while the morphed key is held, and comes back after
+        assert_report(board.press(0), Mods::SHIFT, &[]);
+        assert_report(board.press(2), Mods::NONE, &[Key::DEL]);
+        assert_report(board.release(2), Mods::SHIFT, &[]);
+
+        // Only the modifiers that triggered the morph are masked
+        board.press(1);
+        assert_report(board.press(2), Mods::CTRL, &[Key::DEL]);
+
+        // Decided on press, letting go of Shift doesn't turn it back into Backspace
+        assert_report(board.release(0), Mods::CTRL, &[Key::DEL]);
+        board.release(2);
+        board.release(1);
+
+        // Other modifiers don't trigger it
+        board.press(1);
+        assert_report(board.press(2), Mods::CTRL, &[Key::BKSP]);
+    }
+
+    #[test]
+    fn test_override() {
+        let mut config = config(&[
+            Behavior::Key(Key::LSFT),
+            Behavior::Key(Key::LCTL),
+            Behavior::Key(Key::COMM),
+        ]);
+        config.overrides[0] = Some(KeyOverride {
+            trigger: Key::COMM,
+            replacement: Key::SCLN,
+            mods: Mods::SHIFT.union(Mods::CTRL),
+        });
+        let mut board = Board::new(&config);
+
+        assert_report(board.press(2), Mods::NONE, &[Key::COMM]);
+        board.release(2);
+
+        // Any one of the override's modifiers is enough, and only that one is masked
+        board.press(0);
+        assert_report(board.press(2), Mods::NONE, &[Key::SCLN]);
+        assert_report(board.release(2), Mods::SHIFT, &[]);
+
+        // With both held, both are masked
+        board.press(1);
+        assert_report(board.press(2), Mods::NONE, &[Key::SCLN]);
+        assert_report(board.release(2), Mods::SHIFT.union(Mods::CTRL), &[]);
+        board.release(0);
+        board.release(1);
+
+        // Keys other than the trigger are left alone
+        config.layers[0].as_mut().unwrap().keys[3] = Behavior::Key(Key::DOT);
+        let mut board = Board::new(&config);
+        board.press(0);
+        assert_report(board.press(3), Mods::SHIFT, &[Key::DOT]);
+    }
+
+    fn auto_shift_config() -> Config {
+        let mut config = config(&[
+            Behavior::Key(Key::A),
+            Behavior::Key(Key::N1),
+            Behavior::Key(Key::COMM),
+            Behavior::Key(Key::BKSP),
+            Behavior::Key(Key::B),
+        ]);
+        config.options.auto_shift = AutoShift {
+            alpha_ms: Some(150),
+            number_ms: Some(250),
+            symbol_ms: None,
+        };
+        config
+    }
+
+    #[test]
+    fn test_auto_shift() {
+        let mut board = Board::new(&auto_shift_config());
+
+        // Released before the timeout, the key is tapped once on release
+        assert_report(board.press(0), Mods::NONE, &[]);
+        assert_report(board.wait(149), Mods::NONE, &[]);
+        assert_report(board.release(0), Mods::NONE, &[Key::A]);
+        assert_report(board.wait(1), Mods::NONE, &[]);
+
+        // Held up to the timeout, it is sent shifted until released
+        board.press(0);
+        assert_report(board.wait(150), Mods::SHIFT, &[Key::A]);
+        assert_report(board.wait(100), Mods::SHIFT, &[Key::A]);
+        assert_report(board.release(0), Mods::NONE, &[]);
+
+        // Each class has its own timeout
+        board.press(1);
+        assert_report(board.wait(150), Mods::NONE, &[]);
+        assert_report(board.wait(100), Mods::SHIFT, &[Key::N1]);
+        board.release(1);
+
+        // Classes without a timeout, and keys outside the classes, are sent straight away
+        assert_report(board.press(2), Mods::NONE, &[Key::COMM]);
+        board.release(2);
+        assert_report(board.press(3), Mods::NONE, &[Key::BKSP]);
+    }
+
+    #[test]
+    fn test_auto_shift_interrupt() {
+        let mut board = Board::new(&auto_shift_config());
+
+        // Pressing another key sends the waiting one unshifted, ahead of the new one
+        board.press(0);
+        assert_report(board.press(4), Mods::NONE, &[Key::A]);
+        assert_report(board.release(0), Mods::NONE, &[]);
+
+        // The new key still waits for its own timeout
+        assert_report(board.wait(149), Mods::NONE, &[]);
+        assert_report(board.wait(1), Mods::SHIFT, &[Key::B]);
+        assert_report(board.release(4), Mods::NONE, &[]);
+    }
+
+    #[test]
+    fn test_hold_tap() {
+        let mut config = config(&[
+            Behavior::HoldTap(Key::LCTL, Key::ESC),
+            Behavior::Key(Key::A),
+        ]);
+        config.options.tapping_term_ms = Some(150);
+        let mut board = Board::new(&config);
+
+        // Released within the tapping term, the tap key is sent once
+        assert_report(board.press(0), Mods::NONE, &[]);
+        assert_report(board.wait(149), Mods::NONE, &[]);
+        assert_report(board.release(0), Mods::NONE, &[Key::ESC]);
+        assert_report(board.wait(1), Mods::NONE, &[]);
+
+        // Held for the tapping term, it is the hold key until released
+        board.press(0);
+        assert_report(board.wait(150), Mods::CTRL, &[]);
+        assert_report(board.release(0), Mods::NONE, &[]);
+
+        // Another key going down first makes it a hold straight away
+        board.press(0);
+        board.wait(20);
+        assert_report(board.press(1), Mods::CTRL, &[Key::A]);
+        assert_report(board.release(1), Mods::CTRL, &[]);
+        assert_report(board.release(0), Mods::NONE, &[]);
+
+        // Without a tapping term in the keymap, the default applies
+        config.options.tapping_term_ms = None;
+        let mut board = Board::new(&config);
+        board.press(0);
+        assert_report(board.wait(199), Mods::NONE, &[]);
+        assert_report(board.wait(1), Mods::CTRL, &[]);
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut config = config(&[
+            Behavior::Key(Key::LCTL),
+            Behavior::Key(Key::A),
+            Behavior::Repeat,
+            Behavior::AltRepeat,
+            Behavior::Key(Key::C),
+        ]);
+        config.alt_repeats[0] = Some((Key::A, Key::B));
+        let mut board = Board::new(&config);
+
+        // Nothing to repeat yet
+        assert_report(board.press(2), Mods::NONE, &[]);
+        board.release(2);
+        assert_report(board.press(3), Mods::NONE, &[]);
+        board.release(3);
+
+        // The modifiers the key was sent with are repeated too
+        board.press(0);
+        assert_report(board.press(1), Mods::CTRL, &[Key::A]);
+        board.release(1);
+        board.release(0);
+        assert_report(board.press(2), Mods::CTRL, &[Key::A]);
+        board.release(2);
+
+        // A modifier on its own isn't remembered
+        board.press(0);
+        board.release(0);
+        assert_report(board.press(3), Mods::CTRL, &[Key::B]);
+        board.release(3);
+
+        // Neither are repeats, so they keep going from the original key
+        assert_report(board.press(3), Mods::CTRL, &[Key::B]);
+        board.release(3);
+        assert_report(board.press(2), Mods::CTRL, &[Key::A]);
+        board.release(2);
+
+        // Keys without an alternate have nothing for arep to send
+        board.press(4);
+        board.release(4);
+        assert_report(board.press(2), Mods::NONE, &[Key::C]);
+        board.release(2);
+        assert_report(board.press(3), Mods::NONE, &[]);
+    }
+
+    /// Layers 1 and 2 on the first two keys, and what the third key is on layers 1 to 4
+    fn layered_config() -> Config {
+        let mut config = config(&[
+            Behavior::MomentaryLayer(1),
+            Behavior::MomentaryLayer(2),
+            Behavior::Key(Key::A),
+        ]);
+        for (id, key) in [(1, Key::B), (2, Key::C), (3, Key::D), (4, Key::E)] {
+            let mut keys = [Behavior::Transparent; KEYS];
+            keys[2] = Behavior::Key(key);
+            config.layers[id] = Some(Layer {
+                id: id as u32,
+                keys,
+            });
+        }
+        config
+    }
+
+    #[test]
+    fn test_conditional_layers() {
+        let mut config = layered_config();
+        config.conditional_layers[0] = Some(ConditionalLayer {
+            if_layers: 0b110,
+            then_layer: 3,
+        });
+        config.conditional_layers[1] = Some(ConditionalLayer {
+            if_layers: 0b1000,
+            then_layer: 4,
+        });
+        let mut board = Board::new(&config);
+
+        // One of the two layers isn't enough
+        board.press(0);
+        assert_report(board.press(2), Mods::NONE, &[Key::B]);
+        board.release(2);
+
+        // Both turn on layer 3, which in turn turns on layer 4
+        board.press(1);
+        assert_report(board.press(2), Mods::NONE, &[Key::E]);
+
+        // Both go again once a layer key is let go of, the held key keeps what it pressed
+        assert_report(board.release(0), Mods::NONE, &[Key::E]);
+        board.release(2);
+        assert_report(board.press(2), Mods::NONE, &[Key::C]);
+        board.release(2);
+        board.release(1);
+
+        // Conditions are applied in order, one can't use a layer turned on by a later one
+        config.conditional_layers.swap(0, 1);
+        let mut board = Board::new(&config);
+        board.press(0);
+        board.press(1);
+        assert_report(board.press(2), Mods::NONE, &[Key::D]);
+    }
+
+    #[test]
+    fn test_led_layers() {
+        let mut config = layered_config();
+        config.led_layers[0] = Some(LedLayer {
+            led: Led::NumLock,
+            layer: 1,
+        });
+        let mut board = Board::new(&config);
+
+        // Other LEDs don't turn it on
+        board
+            .engine
+            .set_host_leds(HostLeds::from_report(Led::CapsLock.bit()));
+        assert_report(board.press(2), Mods::NONE, &[Key::A]);
+        board.release(2);
+
+        board
+            .engine
+            .set_host_leds(HostLeds::from_report(Led::NumLock.bit()));
+        assert_report(board.press(2), Mods::NONE, &[Key::B]);
+
+        // Turning it off drops the layer, the held key keeps what it pressed until released
+        board.engine.set_host_leds(HostLeds::default());
+        assert_report(board.wait(1), Mods::NONE, &[Key::B]);
+        assert_report(board.release(2), Mods::NONE, &[]);
+        assert_report(board.press(2), Mods::NONE, &[Key::A]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Key events, the presses and releases found by comparing matrix scans, queued in the order they
+//! happened so the engine sees them in that order along with when they happened
+
+use crate::clock::Instant;
+
+/// A key going down or up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Index into the matrix, `COLS * row + col`
+    pub position: u8,
+    pub pressed: bool,
+    /// When the scan that found it was taken
+    pub timestamp: Instant,
+}
+
+impl KeyEvent {
+    const EMPTY: Self = Self {
+        position: 0,
+        pressed: false,
+        timestamp: Instant::from_millis(0),
+    };
+}
+
+/// First in, first out queue of events with room for `N`
+#[derive(Debug, Clone)]
+pub struct EventQueue<const N: usize> {
+    events: [KeyEvent; N],
+    // Index of the oldest event
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> EventQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            events: [KeyEvent::EMPTY; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Add an event at the back, handing it back if the queue is full
+    pub fn push(&mut self, event: KeyEvent) -> Result<(), KeyEvent> {
+        if self.len == N {
+            return Err(event);
+        }
+
+        self.events[(self.head + self.len) % N] = event;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Take the oldest event
+    pub fn pop(&mut self) -> Option<KeyEvent> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(event)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<const N: usize> Default for EventQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Turns matrix scans into events, by comparing each scan with the state already queued
+#[derive(Debug, Clone)]
+pub struct EventSource<const N: usize> {
+    state: [bool; N],
+}
+
+impl<const N: usize> EventSource<N> {
+    pub const fn new() -> Self {
+        Self { state: [false; N] }
+    }
+
+    /// Queue an event for each key that changed, in matrix order. Keys that don't fit in the queue
+    /// keep their old state, so a later scan picks them up instead of losing them.
+    pub fn scan<const Q: usize>(
+        &mut self,
+        now: Instant,
+        matrix: &[bool; N],
+        queue: &mut EventQueue<Q>,
+    ) {
+        for (pos, &pressed) in matrix.iter().enumerate() {
+            if pressed == self.state[pos] {
+                continue;
+            }
+
+            let event = KeyEvent {
+                position: pos as u8,
+                pressed,
+                timestamp: now,
+            };
+            if queue.push(event).is_err() {
+                break;
+            }
+            self.state[pos] = pressed;
+        }
+    }
+}
+
+impl<const N: usize> Default for EventSource<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::Instant;
+    use crate::event::{EventQueue, EventSource, KeyEvent};
+
+    fn event(position: u8, pressed: bool, timestamp: u32) -> KeyEvent {
+        KeyEvent {
+            position,
+            pressed,
+            timestamp: at(timestamp),
+        }
+    }
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut queue = EventQueue::<3>::new();
+        assert_eq!(queue.pop(), None);
+
+        queue.push(event(0, true, 1)).unwrap();
+        queue.push(event(1, true, 2)).unwrap();
+        queue.push(event(0, false, 3)).unwrap();
+        assert_eq!(queue.push(event(1, false, 4)), Err(event(1, false, 4)));
+        assert_eq!(queue.len(), 3);
+
+        assert_eq!(queue.pop(), Some(event(0, true, 1)));
+        // Wraps around into the freed slot
+        queue.push(event(1, false, 4)).unwrap();
+        assert_eq!(queue.pop(), Some(event(1, true, 2)));
+        assert_eq!(queue.pop(), Some(event(0, false, 3)));
+        assert_eq!(queue.pop(), Some(event(1, false, 4)));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut source = EventSource::<4>::new();
+        let mut queue = EventQueue::<8>::new();
+
+        source.scan(at(10), &[false, true, false, true], &mut queue);
+        source.scan(at(11), &[false, true, false, true], &mut queue);
+        source.scan(at(12), &[true, false, false, true], &mut queue);
+
+        assert_eq!(queue.pop(), Some(event(1, true, 10)));
+        assert_eq!(queue.pop(), Some(event(3, true, 10)));
+        assert_eq!(queue.pop(), Some(event(0, true, 12)));
+        assert_eq!(queue.pop(), Some(event(1, false, 12)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_scan_full() {
+        let mut source = EventSource::<4>::new();
+        let mut queue = EventQueue::<2>::new();
+
+        // Only two of the three presses fit, the third waits for the next scan
+        source.scan(at(10), &[true, true, true, false], &mut queue);
+        assert_eq!(queue.pop(), Some(event(0, true, 10)));
+        assert_eq!(queue.pop(), Some(event(1, true, 10)));
+        assert_eq!(queue.pop(), None);
+
+        source.scan(at(11), &[true, true, true, false], &mut queue);
+        assert_eq!(queue.pop(), Some(event(2, true, 11)));
+        assert_eq!(queue.pop(), None);
+    }
+}
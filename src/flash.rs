@@ -0,0 +1,194 @@
+//! `NorFlash` over the storage region of the RP2040's flash, through the boot ROM's routines
+//!
+//! The flash can't be read while it's being written, and the code normally runs straight out of
+//! it, so the ROM calls are made from a function in RAM with interrupts off. Only one core may be
+//! running while that happens, so when core1 is in use it is paused over the SIO FIFO first and
+//! waits in RAM until the flash is back.
+
+use core::ptr::addr_of;
+
+use config::partition::{KEYMAP_ADDR, PARTITION_LEN};
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+use rp2040_hal::{pac, rom_data, sio::SioFifo};
+
+const XIP_BASE: u32 = 0x1000_0000;
+const PAGE_SIZE: usize = 256;
+const SECTOR_SIZE: usize = 4096;
+// 64K block erase command, the ROM falls back to sector erases where a block doesn't fit
+const BLOCK_SIZE: u32 = 65536;
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+const BOOT2_WORDS: usize = 64;
+// Sent over the SIO FIFO to pause core1 for a write or erase, and to let it go again
+const LOCKOUT_PAUSE: u32 = 0x4C4F_434B;
+const LOCKOUT_PAUSED: u32 = 0x5741_4954;
+const LOCKOUT_RESUME: u32 = 0x474F_4F4E;
+
+extern "C" {
+    // From memory.x
+    static __storage_start: u8;
+    static __storage_end: u8;
+}
+
+/// The keymap partition, read in place
+pub fn keymap_partition() -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(KEYMAP_ADDR as *const u8, PARTITION_LEN) }
+}
+
+// Looked up before XIP goes away, the lookup reads the ROM tables through code in flash
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+pub struct Flash {
+    // Offset of the region from the start of flash
+    base: u32,
+    len: usize,
+    // The second stage bootloader, run again afterwards to put XIP back in its fast mode
+    boot2: [u32; BOOT2_WORDS],
+    // Core0's end of the FIFO to core1, once core1 is running
+    core1: Option<SioFifo>,
+}
+
+impl Flash {
+    /// The region reserved for storage in memory.x
+    pub fn new() -> Self {
+        let start = addr_of!(__storage_start) as u32;
+        let end = addr_of!(__storage_end) as u32;
+
+        let mut boot2 = [0; BOOT2_WORDS];
+        for (i, word) in boot2.iter_mut().enumerate() {
+            *word = unsafe { core::ptr::read_volatile((XIP_BASE as *const u32).add(i)) };
+        }
+
+        Self {
+            base: start - XIP_BASE,
+            len: (end - start) as usize,
+            boot2,
+            core1: None,
+        }
+    }
+
+    /// Pause core1 around every write and erase from now on, over core0's end of the SIO FIFO.
+    /// Core1 has to call `lockout_point` regularly and leave the FIFO to it.
+    pub fn lock_out_core1(&mut self, fifo: SioFifo) {
+        self.core1 = Some(fifo);
+    }
+
+    // `data` is null for an erase
+    fn run(&mut self, offset: u32, data: *const u8, len: usize) {
+        let rom = RomFunctions {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        };
+        let boot2 = self.boot2.as_ptr() as usize + 1;
+
+        // Core1 only answers once it is spinning in RAM
+        if let Some(fifo) = &mut self.core1 {
+            fifo.write_blocking(LOCKOUT_PAUSE);
+            while fifo.read_blocking() != LOCKOUT_PAUSED {}
+        }
+
+        cortex_m::interrupt::free(|_| unsafe {
+            in_ram(&rom, boot2, self.base + offset, data, len);
+        });
+
+        if let Some(fifo) = &mut self.core1 {
+            fifo.write_blocking(LOCKOUT_RESUME);
+        }
+    }
+}
+
+impl Default for Flash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Nothing in here may touch flash, which rules out anything the compiler might turn into a call
+// or a table lookup
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn in_ram(rom: &RomFunctions, boot2: usize, addr: u32, data: *const u8, len: usize) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+
+    if data.is_null() {
+        (rom.flash_range_erase)(addr, len, BLOCK_SIZE, BLOCK_ERASE_CMD);
+    } else {
+        (rom.flash_range_program)(addr, data, len);
+    }
+
+    (rom.flash_flush_cache)();
+    let boot2: unsafe extern "C" fn() = core::mem::transmute(boot2);
+    boot2();
+}
+
+/// Where core1 stops while core0 writes flash, called from core1's loop with its end of the SIO
+/// FIFO. Returns straight away unless a write or erase is waiting on it.
+pub fn lockout_point(fifo: &mut SioFifo) {
+    if fifo.read() == Some(LOCKOUT_PAUSE) {
+        unsafe { paused() };
+    }
+}
+
+// Goes straight to the FIFO registers, like `in_ram` nothing in here may touch flash
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn paused() {
+    let sio = &*pac::SIO::ptr();
+    while sio.fifo_st().read().rdy().bit_is_clear() {}
+    sio.fifo_wr().write(|w| w.bits(LOCKOUT_PAUSED));
+
+    loop {
+        while sio.fifo_st().read().vld().bit_is_clear() {}
+        if sio.fifo_rd().read().bits() == LOCKOUT_RESUME {
+            break;
+        }
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+
+        let src = (XIP_BASE + self.base + offset) as *const u8;
+        unsafe { core::ptr::copy_nonoverlapping(src, bytes.as_mut_ptr(), bytes.len()) };
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.len
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = PAGE_SIZE;
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.run(from, core::ptr::null(), (to - from) as usize);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.run(offset, bytes.as_ptr(), bytes.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Mapping from config keys to USB HID usages
+
+use config::no_std::{Consumer, Key, SystemControl};
+use usbd_human_interface_device::device::mouse::WheelMouseReport;
+use usbd_human_interface_device::page::{self, Keyboard};
+
+use crate::engine::Report;
+use crate::mouse::MouseReport;
+use crate::report::ReportBuilder;
+use crate::system_control::{SYSTEM_POWER_DOWN, SYSTEM_SLEEP, SYSTEM_WAKE_UP};
+
+const DISPLAY_BRIGHTNESS_INCREMENT: u16 = 0x6F;
+const DISPLAY_BRIGHTNESS_DECREMENT: u16 = 0x70;
+
+/// Keyboard report for an engine report. `Mods` uses the bit layout of the HID modifier byte, so
+/// the modifiers go in as they are.
+pub fn keyboard_report(report: &Report) -> ReportBuilder {
+    let mut builder = ReportBuilder::new();
+    builder.mods(report.mods.0);
+
+    for key in report.keys() {
+        builder.press(usage(key) as u8);
+    }
+
+    builder
+}
+
+pub fn wheel_mouse_report(report: &MouseReport) -> WheelMouseReport {
+    WheelMouseReport {
+        buttons: report.buttons,
+        x: report.x,
+        y: report.y,
+        vertical_wheel: report.wheel,
+        horizontal_wheel: report.pan,
+    }
+}
+
+/// The consumer control report as raw bytes, four little endian usages with 0 for an empty slot.
+/// Raw because `page::Consumer` is missing some usages, the brightness keys among them.
+pub fn consumer_report(report: &Report) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (chunk, consumer) in bytes.chunks_exact_mut(2).zip(report.consumer) {
+        let usage = consumer.map_or(page::Consumer::Unassigned as u16, consumer_usage);
+        chunk.copy_from_slice(&usage.to_le_bytes());
+    }
+    bytes
+}
+
+/// Usage on the Consumer page
+pub fn consumer_usage(consumer: Consumer) -> u16 {
+    match consumer {
+        Consumer::VolUp => page::Consumer::VolumeIncrement as u16,
+        Consumer::VolDown => page::Consumer::VolumeDecrement as u16,
+        Consumer::Mute => page::Consumer::Mute as u16,
+        Consumer::PlayPause => page::Consumer::PlayPause as u16,
+        Consumer::Next => page::Consumer::ScanNextTrack as u16,
+        Consumer::Prev => page::Consumer::ScanPreviousTrack as u16,
+        Consumer::Stop => page::Consumer::Stop as u16,
+        Consumer::BriUp => DISPLAY_BRIGHTNESS_INCREMENT,
+        Consumer::BriDown => DISPLAY_BRIGHTNESS_DECREMENT,
+    }
+}
+
+/// Usage for the system control report, 0 when nothing is pressed
+pub fn system_usage(system: Option<SystemControl>) -> u16 {
+    match system {
+        Some(SystemControl::PowerDown) => SYSTEM_POWER_DOWN,
+        Some(SystemControl::Sleep) => SYSTEM_SLEEP,
+        Some(SystemControl::Wake) => SYSTEM_WAKE_UP,
+        None => 0,
+    }
+}
+
+/// Convert a config key to the HID usage sent to the host
+pub fn usage(key: Key) -> Keyboard {
+    match key {
+        Key::A => Keyboard::A,
+        Key::B => Keyboard::B,
+        Key::C => Keyboard::C,
+        Key::D => Keyboard::D,
+        Key::E => Keyboard::E,
+        Key::F => Keyboard::F,
+        Key::G => Keyboard::G,
+        Key::H => Keyboard::H,
+        Key::I => Keyboard::I,
+        Key::J => Keyboard::J,
+        Key::K => Keyboard::K,
+        Key::L => Keyboard::L,
+        Key::M => Keyboard::M,
+        Key::N => Keyboard::N,
+        Key::O => Keyboard::O,
+        Key::P => Keyboard::P,
+        Key::Q => Keyboard::Q,
+        Key::R => Keyboard::R,
+        Key::S => Keyboard::S,
+        Key::T => Keyboard::T,
+        Key::U => Keyboard::U,
+        Key::V => Keyboard::V,
+        Key::W => Keyboard::W,
+        Key::X => Keyboard::X,
+        Key::Y => Keyboard::Y,
+        Key::Z => Keyboard::Z,
+        Key::ESC => Keyboard::Escape,
+        Key::LCTL => Keyboard::LeftControl,
+        Key::LSFT => Keyboard::LeftShift,
+        Key::LGUI => Keyboard::LeftGUI,
+        Key::LALT => Keyboard::LeftAlt,
+        Key::BKSP => Keyboard::DeleteBackspace,
+        Key::TAB => Keyboard::Tab,
+        Key::SPC => Keyboard::Space,
+        Key::N0 => Keyboard::Keyboard0,
+        Key::N1 => Keyboard::Keyboard1,
+        Key::N2 => Keyboard::Keyboard2,
+        Key::N3 => Keyboard::Keyboard3,
+        Key::N4 => Keyboard::Keyboard4,
+        Key::N5 => Keyboard::Keyboard5,
+        Key::N6 => Keyboard::Keyboard6,
+        Key::N7 => Keyboard::Keyboard7,
+        Key::N8 => Keyboard::Keyboard8,
+        Key::N9 => Keyboard::Keyboard9,
+        Key::RET => Keyboard::ReturnEnter,
+        Key::DEL => Keyboard::DeleteForward,
+        Key::MNS => Keyboard::Minus,
+        Key::EQL => Keyboard::Equal,
+        Key::BSLH => Keyboard::Backslash,
+        Key::FSLH => Keyboard::ForwardSlash,
+        // Shifted characters are sent as their base key, see `Key::implicit_mods`
+        Key::LPRN => Keyboard::Keyboard9,
+        Key::RPRN => Keyboard::Keyboard0,
+        Key::LSBR => Keyboard::LeftBrace,
+        Key::RSBR => Keyboard::RightBrace,
+        Key::LCBR => Keyboard::LeftBrace,
+        Key::RCBR => Keyboard::RightBrace,
+        Key::QUOT => Keyboard::Apostrophe,
+        Key::UP => Keyboard::UpArrow,
+        Key::LFT => Keyboard::LeftArrow,
+        Key::RHT => Keyboard::RightArrow,
+        Key::DN => Keyboard::DownArrow,
+        Key::COMM => Keyboard::Comma,
+        Key::DOT => Keyboard::Dot,
+        Key::SCLN => Keyboard::Semicolon,
+        Key::CAPS => Keyboard::CapsLock,
+        Key::NLCK => Keyboard::KeypadNumLockAndClear,
+        Key::SLCK => Keyboard::ScrollLock,
+    }
+}
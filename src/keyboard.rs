@@ -0,0 +1,99 @@
+//! Boot compatible NKRO keyboard device, sending whichever report format the host picked with
+//! SET_PROTOCOL
+
+use defmt::unwrap;
+use fugit::ExtU32;
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usbd_human_interface_device::descriptor::{HidProtocol, InterfaceProtocol};
+use usbd_human_interface_device::interface::{
+    InBytes32, Interface, InterfaceBuilder, InterfaceConfig, OutBytes8, ReportSingle,
+    UsbAllocatable,
+};
+use usbd_human_interface_device::usb_class::prelude::*;
+use usbd_human_interface_device::UsbHidError;
+
+use crate::report::{Protocol, ReportBuilder, KEYBOARD_DESCRIPTOR, NKRO_REPORT_LEN};
+
+pub struct Keyboard<'a, B: UsbBus> {
+    interface: Interface<'a, B, InBytes32, OutBytes8, ReportSingle>,
+    // Last report sent, so unchanged reports aren't sent again
+    last: Option<([u8; NKRO_REPORT_LEN], usize)>,
+}
+
+impl<B: UsbBus> Keyboard<'_, B> {
+    /// Protocol last set by the host. Hosts that never send SET_PROTOCOL get the report protocol.
+    pub fn protocol(&self) -> Protocol {
+        match self.interface.protocol() {
+            HidProtocol::Boot => Protocol::Boot,
+            HidProtocol::Report => Protocol::Report,
+        }
+    }
+
+    pub fn write_report(&mut self, builder: &ReportBuilder) -> Result<(), UsbHidError> {
+        let mut data = [0; NKRO_REPORT_LEN];
+        let len = builder.write(self.protocol(), &mut data);
+
+        if self.last == Some((data, len)) {
+            return Err(UsbHidError::Duplicate);
+        }
+
+        self.interface
+            .write_report(&data[..len])
+            .map_err(UsbHidError::from)?;
+        self.last = Some((data, len));
+        Ok(())
+    }
+
+    /// Read the LED output report if the host sent one
+    pub fn read_report(&mut self) -> Result<u8, UsbHidError> {
+        let mut data = [0; 1];
+        self.interface
+            .read_report(&mut data)
+            .map(|_| data[0])
+            .map_err(UsbHidError::from)
+    }
+}
+
+impl<'a, B: UsbBus> DeviceClass<'a> for Keyboard<'a, B> {
+    type I = Interface<'a, B, InBytes32, OutBytes8, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+    }
+
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        Ok(())
+    }
+}
+
+pub struct KeyboardConfig<'a> {
+    interface: InterfaceConfig<'a, InBytes32, OutBytes8, ReportSingle>,
+}
+
+impl Default for KeyboardConfig<'_> {
+    fn default() -> Self {
+        Self {
+            interface: unwrap!(unwrap!(unwrap!(InterfaceBuilder::new(KEYBOARD_DESCRIPTOR))
+                .boot_device(InterfaceProtocol::Keyboard)
+                .description("Keyboard")
+                .in_endpoint(1.millis()))
+            .with_out_endpoint(100.millis()))
+            .build(),
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbAllocatable<'a, B> for KeyboardConfig<'a> {
+    type Allocated = Keyboard<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        Keyboard {
+            interface: Interface::new(usb_alloc, self.interface),
+            last: None,
+        }
+    }
+}
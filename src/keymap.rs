@@ -0,0 +1,62 @@
+//! The keymap compiled into the firmware
+
+use config::no_std::{
+    Behavior, Config, Key, Layer, Options, COLS, KEYS, MAX_ALT_REPEATS, MAX_CONDITIONAL_LAYERS,
+    MAX_LED_LAYERS, MAX_OVERRIDES, ROWS,
+};
+use config::NUM_LAYERS;
+
+pub const BASE_KEYS: [[Key; COLS]; ROWS] = [
+    [Key::A, Key::B, Key::C, Key::D, Key::E, Key::F],
+    [Key::G, Key::H, Key::I, Key::J, Key::K, Key::L],
+    [Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R],
+    [Key::S, Key::T, Key::U, Key::V, Key::W, Key::X],
+];
+
+/// Plain QWERTY for safe mode, kept simple so it works whatever the keymap had
+pub const SAFE_MODE_KEYS: [[Key; COLS]; ROWS] = [
+    [Key::TAB, Key::Q, Key::W, Key::E, Key::R, Key::T],
+    [Key::ESC, Key::A, Key::S, Key::D, Key::F, Key::G],
+    [Key::LSFT, Key::Z, Key::X, Key::C, Key::V, Key::B],
+    [
+        Key::LCTL,
+        Key::LGUI,
+        Key::LALT,
+        Key::SPC,
+        Key::BKSP,
+        Key::RET,
+    ],
+];
+
+pub fn default_config() -> Config {
+    let mut layers = [(); NUM_LAYERS].map(|_| None);
+    layers[0] = Some(base_layer(&BASE_KEYS));
+
+    Config {
+        options: Options::default(),
+        layers,
+        overrides: [None; MAX_OVERRIDES],
+        alt_repeats: [None; MAX_ALT_REPEATS],
+        conditional_layers: [None; MAX_CONDITIONAL_LAYERS],
+        led_layers: [None; MAX_LED_LAYERS],
+    }
+}
+
+/// The layers used in safe mode, a single layer of `SAFE_MODE_KEYS`
+pub fn safe_mode_layers() -> [Option<Layer>; NUM_LAYERS] {
+    let mut layers = [(); NUM_LAYERS].map(|_| None);
+    layers[0] = Some(base_layer(&SAFE_MODE_KEYS));
+    layers
+}
+
+fn base_layer(rows: &[[Key; COLS]; ROWS]) -> Layer {
+    let mut keys = [Behavior::None; KEYS];
+
+    for (r, row) in rows.iter().enumerate() {
+        for (c, key) in row.iter().enumerate() {
+            keys[COLS * r + c] = Behavior::Key(*key);
+        }
+    }
+
+    Layer { id: 0, keys }
+}
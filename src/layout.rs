@@ -1,9 +1,7 @@
-use core::cell::{Cell, RefCell};
+//! This file handles the layout of a keyboard's keys in rows and columns
 
 use usbd_human_interface_device::page::Keyboard;
 
-/// This file handles the layout of a keyboard's keys in rows and columns
-
 const ROWS: usize = 4;
 const COLS: usize = 6;
 
@@ -21,6 +19,7 @@ pub enum Behavior {
 
 #[derive(Clone, Copy)]
 pub struct Layer {
+    #[allow(dead_code)]
     behaviors: [[Behavior; COLS]; ROWS],
 }
 
@@ -43,8 +42,7 @@ impl State {
     pub fn pop_layer(&mut self) -> Option<Layer> {
         let res = self.layers[self.end_ptr];
         self.layers[self.end_ptr] = None;
-        self.end_ptr.checked_sub(1).unwrap_or(0);
+        self.end_ptr = self.end_ptr.saturating_sub(1);
         res
     }
 }
-
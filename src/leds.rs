@@ -0,0 +1,105 @@
+//! Lock LED state set by the host through the keyboard's LED output report
+
+use config::no_std::Led;
+use embedded_hal::digital::{OutputPin, PinState};
+
+const LED_MASK: u8 = 0x1F;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostLeds(u8);
+
+impl HostLeds {
+    /// Decode the LED output report, padding bits are ignored
+    pub fn from_report(report: u8) -> Self {
+        Self(report & LED_MASK)
+    }
+
+    pub fn is_on(self, led: Led) -> bool {
+        self.0 & led.bit() != 0
+    }
+}
+
+/// An LED on the board that follows one of the host LEDs
+pub struct Indicator<P: OutputPin> {
+    pin: P,
+    led: Led,
+}
+
+impl<P: OutputPin> Indicator<P> {
+    pub fn new(pin: P, led: Led) -> Self {
+        Self { pin, led }
+    }
+
+    pub fn update(&mut self, leds: HostLeds) {
+        self.pin
+            .set_state(PinState::from(leds.is_on(self.led)))
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use config::no_std::Led;
+    use embedded_hal::digital::{ErrorType, OutputPin};
+
+    use crate::leds::{HostLeds, Indicator};
+
+    /// A pin that remembers what it was last set to
+    #[derive(Default)]
+    struct MockPin {
+        high: bool,
+    }
+
+    impl ErrorType for MockPin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for MockPin {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.high = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.high = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_from_report() {
+        let leds = HostLeds::from_report(0b1110_0010);
+
+        assert!(leds.is_on(Led::CapsLock));
+        assert!(!leds.is_on(Led::NumLock));
+        assert!(!leds.is_on(Led::ScrollLock));
+        assert_eq!(leds, HostLeds::from_report(0b0000_0010));
+
+        let leds = HostLeds::from_report(0b0001_0101);
+        assert!(leds.is_on(Led::NumLock));
+        assert!(leds.is_on(Led::ScrollLock));
+        assert!(leds.is_on(Led::Kana));
+        assert!(!leds.is_on(Led::Compose));
+    }
+
+    #[test]
+    fn test_indicator() {
+        let mut indicator = Indicator::new(MockPin::default(), Led::CapsLock);
+
+        indicator.update(HostLeds::from_report(
+            Led::CapsLock.bit() | Led::NumLock.bit(),
+        ));
+        assert!(indicator.pin.high);
+
+        // Only follows its own LED
+        indicator.update(HostLeds::from_report(Led::NumLock.bit()));
+        assert!(!indicator.pin.high);
+
+        indicator.update(HostLeds::from_report(Led::CapsLock.bit()));
+        assert!(indicator.pin.high);
+        indicator.update(HostLeds::default());
+        assert!(!indicator.pin.high);
+    }
+}
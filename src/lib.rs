@@ -1,3 +1,26 @@
 #![no_std]
 
+pub mod clock;
+pub mod commands;
+pub mod debounce;
+pub mod engine;
+pub mod event;
+pub mod flash;
+pub mod hid;
+pub mod keyboard;
+pub mod keymap;
 pub mod layout;
+pub mod leds;
+pub mod matrix;
+pub mod mouse;
+#[cfg(feature = "pio-scan")]
+pub mod pio_matrix;
+pub mod raw_hid;
+pub mod report;
+pub mod safe_mode;
+pub mod sio;
+pub mod spsc;
+pub mod storage;
+pub mod system_control;
+pub mod timer;
+pub mod usb;
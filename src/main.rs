@@ -1,220 +1,723 @@
-//! Blinks the LED on a Pico board
+//! Keyboard firmware for a Pico board
 //!
-//! This will blink an LED attached to GP25, which is the pin the Pico uses for the on-board LED.
+//! The LED attached to GP25, which is the pin the Pico uses for the on-board LED, shows Caps Lock.
+//!
+//! Built on RTIC, so nothing waits in a loop for its turn. A timer interrupt scans the matrix every
+//! ms and USB is serviced from its own interrupt as soon as the host asks for something. Key events
+//! go over a channel to the engine task, which hands the reports it builds over another channel to
+//! the task writing them to USB.
 #![no_std]
 #![no_main]
 
-use rp2040_boot2;
 #[link_section = ".boot2"]
 #[used]
 pub static BOOT_LOADER: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
 
+#[cfg(not(any(feature = "pio-scan", feature = "gpio-snapshot")))]
+use rp2040_hal::gpio::SioInput;
 use rp2040_hal::{
-    self as hal, entry,
-    gpio::{DynPinId, FunctionSio, Pin, Pins, PullDown, SioInput, SioOutput},
-    usb::UsbBus,
-    Timer,
+    self as hal,
+    gpio::{DynPinId, FunctionSio, Pin, PullDown, SioOutput},
+    pac,
 };
 
-use defmt::*;
+use defmt::{error, info, warn, Debug2Format, Display2Format};
 use defmt_rtt as _;
-use embedded_hal::digital::{InputPin, OutputPin};
-use fugit::ExtU32;
-use panic_probe as _;
 
-use cortex_m::prelude::*;
-
-use hal::{
-    clocks::{init_clocks_and_plls, Clock},
-    pac,
-    sio::Sio,
-    watchdog::Watchdog,
+use config::no_std::{COLS, KEYS, ROWS};
+#[cfg(any(feature = "dual-core", feature = "scan-bench"))]
+use rp2040_project_template::clock::{Clock, Ticker};
+#[cfg(feature = "dual-core")]
+use rp2040_project_template::flash::lockout_point;
+#[cfg(not(any(feature = "pio-scan", feature = "gpio-snapshot")))]
+use rp2040_project_template::matrix::MatrixScanner;
+#[cfg(all(feature = "gpio-snapshot", not(feature = "pio-scan")))]
+use rp2040_project_template::matrix::SnapshotScanner;
+#[cfg(feature = "pio-scan")]
+use rp2040_project_template::pio_matrix::{PioBuffers, PioScanner};
+#[cfg(all(feature = "gpio-snapshot", not(feature = "pio-scan")))]
+use rp2040_project_template::sio::SioBank;
+#[cfg(feature = "dual-core")]
+use rp2040_project_template::spsc::{Consumer, Producer, Spsc};
+use rp2040_project_template::{
+    clock::Instant,
+    debounce::{Debouncer, DeferPerKey},
+    event::{EventQueue, EventSource, KeyEvent},
+    matrix::Scanner,
 };
-use usb_device::device::{StringDescriptors, UsbDeviceBuilder, UsbVidPid};
-use usb_device::bus::UsbBusAllocator;
-
-use usbd_human_interface_device::page::Keyboard;
-use usbd_human_interface_device::prelude::UsbHidClassBuilder;
-use usbd_human_interface_device::UsbHidError;
-
-#[entry]
-fn main() -> ! {
-    info!("Program start");
-    let mut pac = pac::Peripherals::take().unwrap();
-    let mut watchdog = Watchdog::new(pac.WATCHDOG);
-    let sio = Sio::new(pac.SIO);
-
-    // External high-speed crystal on the pico board is 12Mhz
-    // TODO the elite pi is 133MHz, revisit whether to increase this
-    let external_xtal_freq_hz = 12_000_000u32;
-    let clocks = init_clocks_and_plls(
-        external_xtal_freq_hz,
-        pac.XOSC,
-        pac.CLOCKS,
-        pac.PLL_SYS,
-        pac.PLL_USB,
-        &mut pac.RESETS,
-        &mut watchdog,
-    )
-    .ok()
-    .unwrap();
-
-    let timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
-
-    let pins = Pins::new(
-        pac.IO_BANK0,
-        pac.PADS_BANK0,
-        sio.gpio_bank0,
-        &mut pac.RESETS,
-    );
-
-    let usb_bus = UsbBusAllocator::new(UsbBus::new(
-        pac.USBCTRL_REGS,
-        pac.USBCTRL_DPRAM,
-        clocks.usb_clock,
-        true,
-        &mut pac.RESETS,
-    ));
-
-    let mut keyboard = UsbHidClassBuilder::new()
-        .add_device(
-            usbd_human_interface_device::device::keyboard::NKROBootKeyboardConfig::default(),
+
+// How long after the last change over raw HID the keymap is saved
+const SAVE_DELAY_MS: u32 = 1000;
+// Scanned often enough for the debounce time to mean something
+const SCAN_PERIOD_US: u32 = 1000;
+// Room for every key changing in the same scan
+const EVENT_QUEUE_LEN: usize = KEYS;
+// Room for a full queue of events and the tick after them, with some to spare if the engine task
+// falls behind
+const INPUT_CHANNEL_LEN: usize = 32;
+const REPORT_CHANNEL_LEN: usize = 4;
+// Room for several scans' worth of events from core1 while core0 is busy, a power of two
+#[cfg(feature = "dual-core")]
+const CORE1_QUEUE_LEN: usize = 64;
+// Left in a watchdog scratch register by a panic, so the next boot comes up in safe mode
+const PANIC_MARKER: u32 = 0x5AFE_B007;
+
+#[cfg(not(any(feature = "pio-scan", feature = "gpio-snapshot")))]
+type InputPin = Pin<DynPinId, FunctionSio<SioInput>, PullDown>;
+type OutputPin = Pin<DynPinId, FunctionSio<SioOutput>, PullDown>;
+
+// The diodes point from the columns to the rows
+#[cfg(not(any(feature = "pio-scan", feature = "gpio-snapshot")))]
+type Matrix = MatrixScanner<InputPin, OutputPin, hal::Timer, ROWS, COLS>;
+#[cfg(all(feature = "gpio-snapshot", not(feature = "pio-scan")))]
+type Matrix = SnapshotScanner<SioBank, hal::Timer, ROWS, COLS>;
+#[cfg(feature = "pio-scan")]
+type Matrix = PioScanner<ROWS, COLS>;
+
+// The matrix pins as masks over the GPIOs, for scanning a register at a time
+#[cfg(all(feature = "gpio-snapshot", not(feature = "pio-scan")))]
+const ROW_MASK: u32 = 0b1111 << 4;
+#[cfg(all(feature = "gpio-snapshot", not(feature = "pio-scan")))]
+const COL_MASK: u32 = 1 << 20 | 1 << 22 | 0b1111 << 26;
+
+// Where the timer interrupt takes key events from, the scan it runs itself or the queue core1 fills
+#[cfg(not(feature = "dual-core"))]
+type Events = Scan;
+#[cfg(feature = "dual-core")]
+type Events = Consumer<'static, KeyEvent, CORE1_QUEUE_LEN>;
+
+#[cfg(feature = "dual-core")]
+static CORE1_STACK: hal::multicore::Stack<4096> = hal::multicore::Stack::new();
+
+/// What the scan hands to the engine task
+#[derive(Debug, Clone, Copy)]
+enum Input {
+    Key(KeyEvent),
+    /// A scan finished, anything due by then is applied and a report built
+    Tick(Instant),
+}
+
+/// The matrix scan and what it keeps from one scan to the next, on whichever core it runs
+struct Scan {
+    scanner: Matrix,
+    debouncer: DeferPerKey<KEYS>,
+    events: EventSource<KEYS>,
+    queue: EventQueue<EVENT_QUEUE_LEN>,
+}
+
+impl Scan {
+    /// Scan once, queueing an event for each key that changed
+    fn run(&mut self, now: Instant) {
+        let raw = scan(&mut self.scanner);
+        let matrix = self.debouncer.update(now, &raw);
+        self.events.scan(now, &matrix, &mut self.queue);
+    }
+
+    fn pop(&mut self) -> Option<KeyEvent> {
+        self.queue.pop()
+    }
+}
+
+/// Reset after a panic, the board has no reset button and the next boot goes into safe mode
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    error!("{}", Display2Format(info));
+    unsafe {
+        (*pac::WATCHDOG::ptr())
+            .scratch0()
+            .write(|w| w.bits(PANIC_MARKER))
+    };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+#[rtic::app(device = rp2040_hal::pac, dispatchers = [TIMER_IRQ_1])]
+mod app {
+    use fugit::ExtU32;
+    #[cfg(feature = "dual-core")]
+    use rp2040_hal::multicore::Multicore;
+    use rp2040_hal::{
+        clocks::init_clocks_and_plls,
+        gpio::Pins,
+        sio::Sio,
+        timer::{Alarm, Alarm0},
+        usb::UsbBus,
+        watchdog::Watchdog,
+    };
+    #[cfg(feature = "pio-scan")]
+    use rp2040_hal::{clocks::Clock as _, dma::DMAExt, gpio::FunctionPio0};
+    use rtic_sync::channel::{Receiver, Sender};
+    use rtic_sync::make_channel;
+    use usb_device::bus::UsbBusAllocator;
+    use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid};
+
+    use config::no_std::{Config, Led};
+    use config::partition::{decode_partition, PartitionError};
+    use config::protocol::{self, SafeModeReason};
+    use rp2040_project_template::{
+        clock::Clock,
+        commands::Commands,
+        engine::{Engine, Report},
+        flash::{keymap_partition, Flash},
+        keymap::{default_config, safe_mode_layers},
+        leds::{HostLeds, Indicator},
+        raw_hid::RAW_REPORT_LEN,
+        safe_mode::{boot_message, early_reason, BootmagicHeld},
+        storage::{Settings, Storage, StorageError},
+        usb::{Composite, Plan},
+    };
+
+    use super::*;
+
+    #[shared]
+    struct Shared {
+        engine: Engine,
+        usb: Composite<'static, UsbBus>,
+        // Set by raw HID edits, which are saved once they settle so pushing a whole keymap doesn't
+        // write flash for every key
+        changed_at: Option<Instant>,
+        // Set by the bootloader command, the reset waits for the next scan so the response can go
+        // out first
+        reset_to_bootloader: bool,
+    }
+
+    #[local]
+    struct Local {
+        timer: hal::Timer,
+        alarm: Alarm0,
+        watchdog: Watchdog,
+        events: Events,
+        inputs: Sender<'static, Input, INPUT_CHANNEL_LEN>,
+        usb_dev: UsbDevice<'static, UsbBus>,
+        usb_timer: hal::Timer,
+        // Wakes the report task after each USB interrupt, when a busy interface might have room
+        usb_ready: Sender<'static, (), 1>,
+        caps_lock: Indicator<OutputPin>,
+        safe_mode: Option<SafeModeReason>,
+        // The console gets the boot message each time it's opened
+        console_open: bool,
+        storage: Option<Storage<Flash>>,
+        keymap_crc: u32,
+    }
+
+    #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBus>> = None])]
+    // A benchmark build never gets past the scan
+    #[cfg_attr(
+        feature = "scan-bench",
+        allow(unreachable_code, unused_variables, unused_mut)
+    )]
+    fn init(ctx: init::Context) -> (Shared, Local) {
+        info!("Program start");
+        let mut pac = ctx.device;
+        // Set by the panic handler before it resets, the watchdog's scratch registers survive that
+        let panicked = pac.WATCHDOG.scratch0().read().bits() == PANIC_MARKER;
+        pac.WATCHDOG.scratch0().write(|w| unsafe { w.bits(0) });
+        let mut watchdog = Watchdog::new(pac.WATCHDOG);
+        let sio = Sio::new(pac.SIO);
+        #[cfg(feature = "dual-core")]
+        let mut fifo = sio.fifo;
+
+        // External high-speed crystal on the pico board is 12Mhz
+        // TODO the elite pi is 133MHz, revisit whether to increase this
+        let external_xtal_freq_hz = 12_000_000u32;
+        let clocks = init_clocks_and_plls(
+            external_xtal_freq_hz,
+            pac.XOSC,
+            pac.CLOCKS,
+            pac.PLL_SYS,
+            pac.PLL_USB,
+            &mut pac.RESETS,
+            &mut watchdog,
         )
-        .build(&usb_bus);
-
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x0001))
-        .strings(&[StringDescriptors::default()
-            .manufacturer("Dylan Bulfin")
-            .product("Boot keyboard")
-            .serial_number("TEST")])
-        .unwrap()
-        .build();
-
-    let mut tick_count_down = timer.count_down();
-    tick_count_down.start(1.millis());
-
-    let mut scan_count_down = timer.count_down();
-    scan_count_down.start(10.millis());
-
-    let mut row_pins: [Pin<DynPinId, FunctionSio<SioInput>, PullDown>; 4] = [
-        pins.gpio4.into_pull_down_input().into_dyn_pin(),
-        pins.gpio5.into_pull_down_input().into_dyn_pin(),
-        pins.gpio6.into_pull_down_input().into_dyn_pin(),
-        pins.gpio7.into_pull_down_input().into_dyn_pin(),
-    ];
-
-    let mut r_col_pins: [Pin<DynPinId, FunctionSio<SioOutput>, PullDown>; 6] = [
-        pins.gpio20.into_push_pull_output().into_dyn_pin(),
-        pins.gpio22.into_push_pull_output().into_dyn_pin(),
-        pins.gpio26.into_push_pull_output().into_dyn_pin(),
-        pins.gpio27.into_push_pull_output().into_dyn_pin(),
-        pins.gpio28.into_push_pull_output().into_dyn_pin(),
-        pins.gpio29.into_push_pull_output().into_dyn_pin(),
-    ];
+        .ok()
+        .unwrap();
 
-    loop {
-        if tick_count_down.wait().is_ok() {
-            match keyboard.tick() {
-                Err(UsbHidError::WouldBlock) | Ok(_) => {}
-                Err(e) => core::panic!("Failed to process keyboard tick: {:?}", e),
+        let mut timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+
+        let pins = Pins::new(
+            pac.IO_BANK0,
+            pac.PADS_BANK0,
+            sio.gpio_bank0,
+            &mut pac.RESETS,
+        );
+
+        let usb_bus: &'static UsbBusAllocator<UsbBus> =
+            ctx.local.usb_bus.insert(UsbBusAllocator::new(UsbBus::new(
+                pac.USBCTRL_REGS,
+                pac.USBCTRL_DPRAM,
+                clocks.usb_clock,
+                true,
+                &mut pac.RESETS,
+            )));
+
+        #[cfg(not(any(feature = "pio-scan", feature = "gpio-snapshot")))]
+        let mut scanner: Matrix = {
+            let row_pins: [InputPin; 4] = [
+                pins.gpio4.into_pull_down_input().into_dyn_pin(),
+                pins.gpio5.into_pull_down_input().into_dyn_pin(),
+                pins.gpio6.into_pull_down_input().into_dyn_pin(),
+                pins.gpio7.into_pull_down_input().into_dyn_pin(),
+            ];
+
+            let col_pins: [OutputPin; 6] = [
+                pins.gpio20.into_push_pull_output().into_dyn_pin(),
+                pins.gpio22.into_push_pull_output().into_dyn_pin(),
+                pins.gpio26.into_push_pull_output().into_dyn_pin(),
+                pins.gpio27.into_push_pull_output().into_dyn_pin(),
+                pins.gpio28.into_push_pull_output().into_dyn_pin(),
+                pins.gpio29.into_push_pull_output().into_dyn_pin(),
+            ];
+
+            MatrixScanner::col2row(row_pins, col_pins, timer)
+        };
+
+        // The same pins, driven and read through SIO a register at a time. The pin types only matter
+        // for setting them up.
+        #[cfg(all(feature = "gpio-snapshot", not(feature = "pio-scan")))]
+        let mut scanner: Matrix = {
+            pins.gpio4.into_pull_down_input();
+            pins.gpio5.into_pull_down_input();
+            pins.gpio6.into_pull_down_input();
+            pins.gpio7.into_pull_down_input();
+            pins.gpio20.into_push_pull_output();
+            pins.gpio22.into_push_pull_output();
+            pins.gpio26.into_push_pull_output();
+            pins.gpio27.into_push_pull_output();
+            pins.gpio28.into_push_pull_output();
+            pins.gpio29.into_push_pull_output();
+
+            SnapshotScanner::col2row(SioBank::new(), ROW_MASK, COL_MASK, timer)
+        };
+
+        // The same pins, handed over to PIO0. The pin types only matter for setting them up.
+        #[cfg(feature = "pio-scan")]
+        let mut scanner: Matrix = {
+            pins.gpio4
+                .into_pull_down_input()
+                .into_function::<FunctionPio0>();
+            pins.gpio5
+                .into_pull_down_input()
+                .into_function::<FunctionPio0>();
+            pins.gpio6
+                .into_pull_down_input()
+                .into_function::<FunctionPio0>();
+            pins.gpio7
+                .into_pull_down_input()
+                .into_function::<FunctionPio0>();
+            pins.gpio20.into_function::<FunctionPio0>();
+            pins.gpio22.into_function::<FunctionPio0>();
+            pins.gpio26.into_function::<FunctionPio0>();
+            pins.gpio27.into_function::<FunctionPio0>();
+            pins.gpio28.into_function::<FunctionPio0>();
+            pins.gpio29.into_function::<FunctionPio0>();
+
+            let dma = pac.DMA.split(&mut pac.RESETS);
+            let buffers = cortex_m::singleton!(: PioBuffers = PioBuffers::new()).unwrap();
+            PioScanner::col2row(
+                pac.PIO0,
+                &mut pac.RESETS,
+                dma.ch0,
+                dma.ch1,
+                buffers,
+                [4, 5, 6, 7],
+                [20, 22, 26, 27, 28, 29],
+                clocks.system_clock.freq().to_Hz(),
+            )
+        };
+
+        // Nothing else runs while the scan is measured
+        #[cfg(feature = "scan-bench")]
+        bench(&mut scanner, timer);
+
+        let mut config = default_config();
+
+        // The keymap partition replaces the compiled-in config when it holds a valid one. It comes
+        // first since it can move the bootmagic keys.
+        let mut invalid_partition = false;
+        let keymap_crc = match decode_partition(keymap_partition()) {
+            Ok((partition, crc)) => {
+                config = partition;
+                crc
+            }
+            Err(PartitionError::Missing) => 0,
+            Err(e) => {
+                warn!("Invalid keymap partition: {:?}", Debug2Format(&e));
+                invalid_partition = true;
+                0
             }
+        };
 
-            watchdog.feed();
+        // The bootmagic keys are read before anything else is loaded
+        let matrix = scan(&mut scanner);
+        let held = BootmagicHeld::from_matrix(&config.options.bootmagic, &matrix);
+        let mut safe_mode = early_reason(panicked, held);
+        if invalid_partition {
+            safe_mode.get_or_insert(SafeModeReason::InvalidKeymap);
         }
 
-        if usb_dev.poll(&mut [&mut keyboard]) {
-            keyboard.device().read_report();
-        }
+        // Keymap and settings changed over raw HID, the firmware still works without them
+        let mut storage = match Storage::mount(Flash::new()) {
+            Ok(storage) => Some(storage),
+            Err(e) => {
+                warn!("Failed to mount storage: {:?}", Debug2Format(&e));
+                None
+            }
+        };
 
-        if scan_count_down.wait().is_ok() {
-            let keys = do_matrix_scan(&mut row_pins, &mut r_col_pins, timer);
-            // let keys = [Keyboard::Z; 1];
+        if held.clear {
+            if let Some(Err(e)) = storage.as_mut().map(|storage| storage.clear()) {
+                warn!("Failed to clear storage: {:?}", Debug2Format(&e));
+            }
+        }
 
-            match keyboard.device().write_report(keys) {
-                Err(UsbHidError::WouldBlock) => {}
-                Err(UsbHidError::Duplicate) => {}
-                Ok(_) => {}
-                Err(e) => {
-                    core::panic!("Failed to write keyboard report: {:?}", e)
+        // Edits made on top of a different keymap are dropped along with their settings
+        let mut settings = Settings {
+            keymap_crc,
+            ..Settings::default()
+        };
+        if let Some(storage) = &mut storage {
+            if let Ok(Some(stored)) = storage.load_settings() {
+                if stored.keymap_crc == keymap_crc {
+                    settings = stored;
+                    match storage.load_keymap() {
+                        Ok(Some(layers)) => config.layers = layers,
+                        Ok(None) => {}
+                        Err(StorageError::Invalid) => {
+                            warn!("Invalid keymap in storage");
+                            safe_mode.get_or_insert(SafeModeReason::InvalidKeymap);
+                        }
+                        Err(e) => warn!("Failed to load keymap: {:?}", Debug2Format(&e)),
+                    }
                 }
             }
         }
-    }
-}
 
-const BASE_KEYS: [[Keyboard; 6]; 4] = [
-    [
-        Keyboard::A,
-        Keyboard::B,
-        Keyboard::C,
-        Keyboard::D,
-        Keyboard::E,
-        Keyboard::F,
-    ],
-    [
-        Keyboard::G,
-        Keyboard::H,
-        Keyboard::I,
-        Keyboard::J,
-        Keyboard::K,
-        Keyboard::L,
-    ],
-    [
-        Keyboard::M,
-        Keyboard::N,
-        Keyboard::O,
-        Keyboard::P,
-        Keyboard::Q,
-        Keyboard::R,
-    ],
-    [
-        Keyboard::S,
-        Keyboard::T,
-        Keyboard::U,
-        Keyboard::V,
-        Keyboard::W,
-        Keyboard::X,
-    ],
-];
-
-fn do_matrix_scan(
-    row_pins: &mut [Pin<DynPinId, FunctionSio<SioInput>, PullDown>; 4],
-    col_pins: &mut [Pin<DynPinId, FunctionSio<SioOutput>, PullDown>; 6],
-    timer: Timer,
-) -> [Keyboard; 24] {
-    let mut res: [Keyboard; 24] = [Keyboard::NoEventIndicated; 24];
-
-    for cpin in col_pins.iter_mut() {
-        cpin.set_low().ok();
+        if let Some(reason) = safe_mode {
+            warn!("{}", boot_message(Some(reason)));
+            // Nothing from the partition either, its other sections could depend on its layers
+            config = Config {
+                layers: safe_mode_layers(),
+                ..default_config()
+            };
+            settings = Settings {
+                keymap_crc,
+                ..Settings::default()
+            };
+        }
+
+        let plan = Plan::new(&config.options.usb);
+        let usb = Composite::new(usb_bus, &plan);
+
+        let usb_dev_builder = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x1209, 0x0001))
+            .strings(&[StringDescriptors::default()
+                .manufacturer("Dylan Bulfin")
+                .product("Boot keyboard")
+                .serial_number("TEST")])
+            .unwrap();
+        let usb_dev = if plan.needs_iad() {
+            usb_dev_builder.composite_with_iads().build()
+        } else {
+            usb_dev_builder.build()
+        };
+
+        let mut engine = Engine::new(&config);
+        engine.set_locked_layers(settings.locked_layers);
+
+        let (inputs, input_receiver) = make_channel!(Input, INPUT_CHANNEL_LEN);
+        let (reports, report_receiver) = make_channel!(Report, REPORT_CHANNEL_LEN);
+        let (usb_ready, usb_ready_receiver) = make_channel!((), 1);
+        process::spawn(input_receiver, reports).ok();
+        send_reports::spawn(report_receiver, usb_ready_receiver).ok();
+
+        let scan = Scan {
+            scanner,
+            debouncer: DeferPerKey::new(config.options.debounce_ms),
+            events: EventSource::new(),
+            queue: EventQueue::new(),
+        };
+        #[cfg(not(feature = "dual-core"))]
+        let events = scan;
+        #[cfg(feature = "dual-core")]
+        let events = {
+            let queue =
+                cortex_m::singleton!(: Spsc<KeyEvent, CORE1_QUEUE_LEN> = Spsc::new()).unwrap();
+            let (producer, consumer) = queue.split();
+            let mut multicore = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut fifo);
+            let core1 = &mut multicore.cores()[1];
+            core1
+                .spawn(CORE1_STACK.take().unwrap(), move || {
+                    core1_scan(scan, timer, producer)
+                })
+                .unwrap();
+            // Flash can't be written while core1 runs from it
+            if let Some(storage) = &mut storage {
+                storage.flash_mut().lock_out_core1(fifo);
+            }
+            consumer
+        };
+
+        let mut alarm = timer.alarm_0().unwrap();
+        alarm.schedule(SCAN_PERIOD_US.micros()).unwrap();
+        alarm.enable_interrupt();
+
+        (
+            Shared {
+                engine,
+                usb,
+                changed_at: None,
+                reset_to_bootloader: false,
+            },
+            Local {
+                timer,
+                alarm,
+                watchdog,
+                events,
+                inputs,
+                usb_dev,
+                usb_timer: timer,
+                usb_ready,
+                caps_lock: Indicator::new(
+                    pins.gpio25.into_push_pull_output().into_dyn_pin(),
+                    Led::CapsLock,
+                ),
+                safe_mode,
+                console_open: false,
+                storage,
+                keymap_crc,
+            },
+        )
     }
 
-    for (c, cpin) in col_pins.iter_mut().enumerate() {
-        {
-            // Wait for debounce
-            let mut bounce_timer = timer.count_down();
-            bounce_timer.start(10.micros());
-            while let Err(_) = bounce_timer.wait() {}
+    /// Scans the matrix and hands what changed to the engine task, along with the 1ms housekeeping
+    #[task(
+        binds = TIMER_IRQ_0,
+        priority = 2,
+        shared = [usb, reset_to_bootloader],
+        local = [timer, alarm, watchdog, events, inputs]
+    )]
+    fn matrix_scan(mut ctx: matrix_scan::Context) {
+        let alarm = ctx.local.alarm;
+        alarm.clear_interrupt();
+        alarm.schedule(SCAN_PERIOD_US.micros()).unwrap();
+
+        if ctx.shared.reset_to_bootloader.lock(|reset| *reset) {
+            hal::rom_data::reset_to_usb_boot(0, 0);
+        }
+        if let Err(e) = ctx.shared.usb.lock(|usb| usb.tick()) {
+            core::panic!("Failed to process HID tick: {:?}", e)
         }
+        ctx.local.watchdog.feed();
 
-        // Set High
-        cpin.set_high().ok();
+        let now = ctx.local.timer.now();
+        let events = ctx.local.events;
+        // With both cores in use, core1 has already scanned
+        #[cfg(not(feature = "dual-core"))]
+        events.run(now);
 
-        {
-            // Wait for debounce
-            let mut bounce_timer = timer.count_down();
-            bounce_timer.start(10.micros());
-            while let Err(_) = bounce_timer.wait() {}
+        // Events that don't fit stay queued for the next scan, and past that the event source
+        // holds back the keys they're for
+        let inputs = ctx.local.inputs;
+        while !inputs.is_full() {
+            let Some(event) = events.pop() else {
+                break;
+            };
+            inputs.try_send(Input::Key(event)).ok();
         }
+        inputs.try_send(Input::Tick(now)).ok();
+    }
+
+    /// Services the host as soon as it asks for something
+    #[task(
+        binds = USBCTRL_IRQ,
+        priority = 3,
+        shared = [usb, engine, changed_at, reset_to_bootloader],
+        local = [usb_dev, usb_timer, usb_ready, caps_lock, safe_mode, console_open]
+    )]
+    fn usb_irq(ctx: usb_irq::Context) {
+        let safe_mode = *ctx.local.safe_mode;
+        let now = ctx.local.usb_timer.now();
+        let shared = ctx.shared;
+
+        (
+            shared.usb,
+            shared.engine,
+            shared.changed_at,
+            shared.reset_to_bootloader,
+        )
+            .lock(|usb, engine, changed_at, reset_to_bootloader| {
+                if usb.poll(ctx.local.usb_dev) {
+                    if let Ok(report) = usb.keyboard.device().read_report() {
+                        let leds = HostLeds::from_report(report);
+                        engine.set_host_leds(leds);
+                        ctx.local.caps_lock.update(leds);
+                    }
+
+                    if let Some(raw_hid) = &mut usb.raw_hid {
+                        let mut packet = [0; RAW_REPORT_LEN];
+                        if let Ok(RAW_REPORT_LEN) = raw_hid.device().read_report(&mut packet) {
+                            let mut commands = Commands::new(engine, safe_mode);
+                            let response = protocol::handle(&mut commands, &packet);
+                            *reset_to_bootloader |= commands.bootloader;
+                            // Nothing is saved in safe mode, which would replace the keymap with
+                            // the safe one
+                            if commands.changed && safe_mode.is_none() {
+                                *changed_at = Some(now);
+                            }
 
-        for (r, rpin) in row_pins.iter_mut().enumerate() {
-            if rpin.is_high().unwrap_or(false) {
-                // Key is active
-                res[6 * r + c] = BASE_KEYS[r][c];
+                            if let Err(e) = raw_hid.device().write_report(&response) {
+                                warn!("Failed to write raw HID response: {:?}", e)
+                            }
+                        }
+                    }
+                }
+
+                if let Some(serial) = &mut usb.serial {
+                    let mut input = [0; 16];
+                    let read = matches!(serial.read(&mut input), Ok(len) if len > 0);
+                    let console_open = &mut *ctx.local.console_open;
+                    let opened = serial.dtr() && !*console_open;
+                    *console_open = serial.dtr();
+
+                    if read || opened {
+                        serial.write(boot_message(safe_mode).as_bytes()).ok();
+                    }
+                }
+            });
+
+        ctx.local.usb_ready.try_send(()).ok();
+    }
+
+    /// Runs the engine over the key events, handing on each report that differs from the last
+    #[task(priority = 1, shared = [engine, changed_at], local = [storage, keymap_crc])]
+    async fn process(
+        mut ctx: process::Context,
+        mut inputs: Receiver<'static, Input, INPUT_CHANNEL_LEN>,
+        mut reports: Sender<'static, Report, REPORT_CHANNEL_LEN>,
+    ) {
+        // The last report handed on
+        let mut last: Option<Report> = None;
+
+        while let Ok(input) = inputs.recv().await {
+            let now = match input {
+                Input::Key(event) => {
+                    ctx.shared.engine.lock(|engine| engine.handle(event));
+                    continue;
+                }
+                Input::Tick(now) => now,
+            };
+
+            let report = ctx.shared.engine.lock(|engine| engine.tick(now));
+            // The mouse moves on every report though. A full channel means USB is behind, and the
+            // report goes out on a later tick instead.
+            let changed = last != Some(report) || report.mouse.is_moving();
+            if changed && reports.try_send(report).is_ok() {
+                last = Some(report);
+            }
+
+            let due = ctx.shared.changed_at.lock(|changed_at| {
+                let due = changed_at.is_some_and(|since| now.millis_since(since) >= SAVE_DELAY_MS);
+                if due {
+                    *changed_at = None;
+                }
+                due
+            });
+            if !due {
+                continue;
             }
+            let Some(storage) = ctx.local.storage.as_mut() else {
+                continue;
+            };
+
+            let settings = Settings {
+                locked_layers: ctx.shared.engine.lock(|engine| engine.locked_layers()),
+                keymap_crc: *ctx.local.keymap_crc,
+            };
+            let saved = ctx.shared.engine.lock(|engine| {
+                storage
+                    .save_keymap(engine.layers())
+                    .and_then(|_| storage.save_settings(settings))
+            });
+            if let Err(e) = saved {
+                warn!("Failed to save keymap: {:?}", Debug2Format(&e));
+            }
+        }
+    }
+
+    /// Writes reports to every interface. A report some interface was too busy for is written
+    /// again once USB has been serviced, unless a newer one has come in by then.
+    #[task(priority = 1, shared = [usb])]
+    async fn send_reports(
+        mut ctx: send_reports::Context,
+        mut reports: Receiver<'static, Report, REPORT_CHANNEL_LEN>,
+        mut usb_ready: Receiver<'static, (), 1>,
+    ) {
+        while let Ok(mut report) = reports.recv().await {
+            loop {
+                match ctx.shared.usb.lock(|usb| usb.write_report(&mut report)) {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(e) => core::panic!("Failed to write HID report: {:?}", e),
+                }
+
+                usb_ready.recv().await.ok();
+                if let Ok(newer) = reports.try_recv() {
+                    report = newer;
+                }
+            }
+        }
+    }
+}
+
+/// Scans every scan period on core1, whatever USB is doing on core0. What doesn't fit in `events`
+/// waits in the scan's own queue, the same as when the timer interrupt scans.
+#[cfg(feature = "dual-core")]
+fn core1_scan(
+    mut scan: Scan,
+    timer: hal::Timer,
+    mut events: Producer<'static, KeyEvent, CORE1_QUEUE_LEN>,
+) -> ! {
+    // Core1's end of the FIFO, the two cores see different ends through the same registers
+    let mut fifo = hal::Sio::new(unsafe { pac::Peripherals::steal() }.SIO).fifo;
+    let mut ticker = Ticker::new(timer.now(), SCAN_PERIOD_US / 1000);
+
+    loop {
+        lockout_point(&mut fifo);
+
+        let now = timer.now();
+        if !ticker.poll(now) {
+            continue;
         }
 
-        cpin.set_low().ok();
+        scan.run(now);
+        while !events.is_full() {
+            let Some(event) = scan.pop() else {
+                break;
+            };
+            events.push(event).ok();
+        }
     }
+}
+
+/// Scans as fast as it can, logging how many scans it got through each second. Never returns, the
+/// keyboard doesn't run in a benchmark build.
+#[cfg(feature = "scan-bench")]
+fn bench(scanner: &mut Matrix, timer: hal::Timer) -> ! {
+    let mut ticker = Ticker::new(timer.now(), 1000);
+    let mut scans: u32 = 0;
 
-    res
+    loop {
+        core::hint::black_box(scan(scanner));
+        scans += 1;
+
+        if ticker.poll(timer.now()) {
+            info!("{} scans/s", scans);
+            scans = 0;
+        }
+    }
+}
+
+fn scan(scanner: &mut Matrix) -> [bool; KEYS] {
+    match scanner.scan() {
+        Ok(keys) => keys.to_keys(),
+        Err(e) => core::panic!("Failed to scan the matrix: {:?}", e),
+    }
 }
@@ -0,0 +1,684 @@
+//! Scanning the key matrix, over any pins that implement the embedded-hal traits
+//!
+//! Each key sits on a row and a column with a diode in series. One side of the diodes is driven
+//! high a line at a time while the other side is read through pull-downs, so a pressed key shows up
+//! as a high input on the line its diode conducts towards.
+
+use core::convert::Infallible;
+use core::fmt::Debug;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{Error, ErrorKind, InputPin, OutputPin};
+
+// How long the inputs get to settle after a line is driven
+pub(crate) const SETTLE_NS: u32 = 1000;
+// Where the inputs start in a sample from the PIO scan, the line it drove is below them
+const SAMPLE_INPUT_SHIFT: u32 = 16;
+
+/// Reads which keys are pressed, however it gets there
+pub trait Scanner<const ROWS: usize, const COLS: usize> {
+    type Error: Debug;
+
+    fn scan(&mut self) -> Result<KeyBitmap<ROWS, COLS>, Self::Error>;
+}
+
+/// Which key is pressed, one bit per column in a word per row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyBitmap<const ROWS: usize, const COLS: usize> {
+    rows: [u32; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> KeyBitmap<ROWS, COLS> {
+    pub fn new() -> Self {
+        const { assert!(COLS <= u32::BITS as usize) };
+        Self { rows: [0; ROWS] }
+    }
+
+    pub fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.rows[row] & (1 << col) != 0
+    }
+
+    pub fn set(&mut self, row: usize, col: usize, pressed: bool) {
+        if pressed {
+            self.rows[row] |= 1 << col;
+        } else {
+            self.rows[row] &= !(1 << col);
+        }
+    }
+
+    /// Set the whole of a row at once, one bit per column
+    pub fn set_row(&mut self, row: usize, cols: u32) {
+        self.rows[row] = cols;
+    }
+
+    pub fn rows(&self) -> &[u32; ROWS] {
+        &self.rows
+    }
+
+    /// Key states indexed by `COLS * row + col`, the order the keymap uses
+    pub fn to_keys<const N: usize>(&self) -> [bool; N] {
+        const { assert!(N == ROWS * COLS) };
+
+        let mut res = [false; N];
+        for (i, key) in res.iter_mut().enumerate() {
+            *key = self.is_pressed(i / COLS, i % COLS);
+        }
+        res
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Default for KeyBitmap<ROWS, COLS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The direction of the diodes, which decides which lines are driven and which are read
+enum Lines<I, O, const ROWS: usize, const COLS: usize> {
+    /// Current flows from the columns to the rows, columns are driven and rows read
+    Col2Row { rows: [I; ROWS], cols: [O; COLS] },
+    /// Current flows from the rows to the columns, rows are driven and columns read
+    Row2Col { rows: [O; ROWS], cols: [I; COLS] },
+}
+
+pub struct MatrixScanner<I, O, D, const ROWS: usize, const COLS: usize> {
+    lines: Lines<I, O, ROWS, COLS>,
+    delay: D,
+}
+
+impl<I, O, D, const ROWS: usize, const COLS: usize> MatrixScanner<I, O, D, ROWS, COLS>
+where
+    I: InputPin,
+    O: OutputPin,
+    D: DelayNs,
+{
+    /// A matrix with the diodes pointing from the columns to the rows
+    pub fn col2row(rows: [I; ROWS], cols: [O; COLS], delay: D) -> Self {
+        Self {
+            lines: Lines::Col2Row { rows, cols },
+            delay,
+        }
+    }
+
+    /// A matrix with the diodes pointing from the rows to the columns
+    pub fn row2col(rows: [O; ROWS], cols: [I; COLS], delay: D) -> Self {
+        Self {
+            lines: Lines::Row2Col { rows, cols },
+            delay,
+        }
+    }
+}
+
+impl<I, O, D, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
+    for MatrixScanner<I, O, D, ROWS, COLS>
+where
+    I: InputPin,
+    O: OutputPin,
+    D: DelayNs,
+{
+    type Error = ErrorKind;
+
+    fn scan(&mut self) -> Result<KeyBitmap<ROWS, COLS>, ErrorKind> {
+        let mut res = KeyBitmap::new();
+
+        match &mut self.lines {
+            Lines::Col2Row { rows, cols } => {
+                scan_lines(cols, rows, &mut self.delay, |col, row| {
+                    res.set(row, col, true)
+                })?
+            }
+            Lines::Row2Col { rows, cols } => {
+                scan_lines(rows, cols, &mut self.delay, |row, col| {
+                    res.set(row, col, true)
+                })?
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+// Drives each output in turn, calling `pressed` with the output and input of every high input
+fn scan_lines<I: InputPin, O: OutputPin>(
+    outputs: &mut [O],
+    inputs: &mut [I],
+    delay: &mut impl DelayNs,
+    mut pressed: impl FnMut(usize, usize),
+) -> Result<(), ErrorKind> {
+    for output in outputs.iter_mut() {
+        output.set_low().map_err(|e| e.kind())?;
+    }
+
+    for (o, output) in outputs.iter_mut().enumerate() {
+        output.set_high().map_err(|e| e.kind())?;
+        // Only long enough for the pins, switch bounce is left to the debouncer
+        delay.delay_ns(SETTLE_NS);
+
+        for (i, input) in inputs.iter_mut().enumerate() {
+            if input.is_high().map_err(|e| e.kind())? {
+                pressed(o, i);
+            }
+        }
+
+        output.set_low().map_err(|e| e.kind())?;
+    }
+
+    Ok(())
+}
+
+/// A bank of pins read and driven a whole register at a time, with a bit per pin
+pub trait PinBank {
+    fn read(&mut self) -> u32;
+    fn set_high(&mut self, pins: u32);
+    fn set_low(&mut self, pins: u32);
+}
+
+/// Scans a matrix with the lines given as masks over a `PinBank`, reading every input in one go
+/// after each line is driven rather than one pin at a time. Lines are numbered from the lowest pin.
+pub struct SnapshotScanner<B, D, const ROWS: usize, const COLS: usize> {
+    bank: B,
+    delay: D,
+    rows: u32,
+    cols: u32,
+    col2row: bool,
+}
+
+impl<B, D, const ROWS: usize, const COLS: usize> SnapshotScanner<B, D, ROWS, COLS>
+where
+    B: PinBank,
+    D: DelayNs,
+{
+    /// A matrix with the diodes pointing from the columns to the rows
+    pub fn col2row(bank: B, rows: u32, cols: u32, delay: D) -> Self {
+        Self::new(bank, rows, cols, delay, true)
+    }
+
+    /// A matrix with the diodes pointing from the rows to the columns
+    pub fn row2col(bank: B, rows: u32, cols: u32, delay: D) -> Self {
+        Self::new(bank, rows, cols, delay, false)
+    }
+
+    fn new(bank: B, rows: u32, cols: u32, delay: D, col2row: bool) -> Self {
+        assert_eq!(rows.count_ones() as usize, ROWS, "wrong number of row pins");
+        assert_eq!(
+            cols.count_ones() as usize,
+            COLS,
+            "wrong number of column pins"
+        );
+        assert_eq!(rows & cols, 0, "rows and columns share a pin");
+
+        Self {
+            bank,
+            delay,
+            rows,
+            cols,
+            col2row,
+        }
+    }
+}
+
+impl<B, D, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
+    for SnapshotScanner<B, D, ROWS, COLS>
+where
+    B: PinBank,
+    D: DelayNs,
+{
+    type Error = Infallible;
+
+    fn scan(&mut self) -> Result<KeyBitmap<ROWS, COLS>, Infallible> {
+        let mut res = KeyBitmap::new();
+        let (outputs, inputs) = if self.col2row {
+            (self.cols, self.rows)
+        } else {
+            (self.rows, self.cols)
+        };
+
+        self.bank.set_low(outputs);
+        for (o, output) in pins(outputs).enumerate() {
+            self.bank.set_high(output);
+            // Only long enough for the pins, switch bounce is left to the debouncer
+            self.delay.delay_ns(SETTLE_NS);
+            let read = gather(self.bank.read(), inputs);
+            self.bank.set_low(output);
+
+            if self.col2row {
+                for row in (0..ROWS).filter(|row| read & (1 << row) != 0) {
+                    res.set(row, o, true);
+                }
+            } else {
+                res.set_row(o, read);
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+// Each pin in `mask` on its own, from the lowest
+fn pins(mask: u32) -> impl Iterator<Item = u32> {
+    (0..u32::BITS)
+        .map(|bit| 1 << bit)
+        .filter(move |pin| mask & pin != 0)
+}
+
+// The bits of `value` under `mask`, packed down from bit 0 in order
+fn gather(value: u32, mask: u32) -> u32 {
+    // Pins next to each other only need a shift
+    let shift = mask.trailing_zeros() % u32::BITS;
+    let shifted = mask >> shift;
+    if shifted & shifted.wrapping_add(1) == 0 {
+        return (value & mask) >> shift;
+    }
+
+    pins(mask)
+        .enumerate()
+        .filter(|&(_, pin)| value & pin != 0)
+        .fold(0, |res, (i, _)| res | 1 << i)
+}
+
+/// Turns the samples a PIO scan leaves in its ring buffer back into a bitmap
+///
+/// The PIO drives each line in turn and pushes a word for it, with the inputs it read in the top
+/// half and the line it drove in the bottom half. Both are pin masks from the first pin of their
+/// set, so the lines have to be within 16 pins of it.
+#[derive(Debug, Clone)]
+pub struct SampleDecoder<const ROWS: usize, const COLS: usize> {
+    rows: [u32; ROWS],
+    cols: [u32; COLS],
+    col2row: bool,
+}
+
+impl<const ROWS: usize, const COLS: usize> SampleDecoder<ROWS, COLS> {
+    /// Lines given as pin offsets, with the diodes pointing from the columns to the rows
+    pub fn col2row(rows: [u8; ROWS], cols: [u8; COLS]) -> Self {
+        Self::new(rows, cols, true)
+    }
+
+    /// Lines given as pin offsets, with the diodes pointing from the rows to the columns
+    pub fn row2col(rows: [u8; ROWS], cols: [u8; COLS]) -> Self {
+        Self::new(rows, cols, false)
+    }
+
+    fn new(rows: [u8; ROWS], cols: [u8; COLS], col2row: bool) -> Self {
+        let mask = |offset: u8| {
+            assert!(
+                (offset as u32) < SAMPLE_INPUT_SHIFT,
+                "pin offset out of range"
+            );
+            1 << offset
+        };
+
+        Self {
+            rows: rows.map(mask),
+            cols: cols.map(mask),
+            col2row,
+        }
+    }
+
+    /// The masks the PIO drives, one per scanned line
+    pub fn driven(&self) -> &[u32] {
+        if self.col2row {
+            &self.cols
+        } else {
+            &self.rows
+        }
+    }
+
+    /// The latest sample of every line, looking back from just before `next`, which is where the
+    /// DMA writes next. `None` while some line has no sample yet.
+    pub fn decode(&self, samples: &[u32], next: usize) -> Option<KeyBitmap<ROWS, COLS>> {
+        let (driven, read) = if self.col2row {
+            (&self.cols[..], &self.rows[..])
+        } else {
+            (&self.rows[..], &self.cols[..])
+        };
+        let all = ((1u64 << driven.len()) - 1) as u32;
+
+        let mut res = KeyBitmap::new();
+        let mut seen = 0u32;
+        for age in 1..=samples.len() {
+            let sample = samples[(next + samples.len() - age) % samples.len()];
+            // Anything else is an older sample of a line already seen, or not written yet
+            let Some(d) = driven
+                .iter()
+                .position(|&mask| mask == sample & ((1 << SAMPLE_INPUT_SHIFT) - 1))
+            else {
+                continue;
+            };
+            if seen & (1 << d) != 0 {
+                continue;
+            }
+            seen |= 1 << d;
+
+            let inputs = sample >> SAMPLE_INPUT_SHIFT;
+            for (r, &mask) in read.iter().enumerate() {
+                if inputs & mask == 0 {
+                    continue;
+                }
+                if self.col2row {
+                    res.set(r, d, true);
+                } else {
+                    res.set(d, r, true);
+                }
+            }
+
+            if seen == all {
+                return Some(res);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::{Cell, RefCell};
+    use core::convert::Infallible;
+
+    use embedded_hal::delay::DelayNs;
+    use embedded_hal::digital::{ErrorType, InputPin, OutputPin};
+
+    use crate::matrix::{
+        gather, KeyBitmap, MatrixScanner, PinBank, SampleDecoder, Scanner, SnapshotScanner,
+    };
+
+    const ROWS: usize = 2;
+    const COLS: usize = 3;
+
+    // Keys are switches in series with a diode, current only flows through a pressed key from its
+    // anode side to its cathode side
+    struct Board {
+        pressed: [[bool; COLS]; ROWS],
+        col2row: bool,
+        rows: RefCell<[bool; ROWS]>,
+        cols: RefCell<[bool; COLS]>,
+        settled: Cell<u32>,
+    }
+
+    impl Board {
+        fn new(col2row: bool, keys: &[(usize, usize)]) -> Self {
+            let mut pressed = [[false; COLS]; ROWS];
+            for &(row, col) in keys {
+                pressed[row][col] = true;
+            }
+
+            Self {
+                pressed,
+                col2row,
+                rows: RefCell::new([false; ROWS]),
+                cols: RefCell::new([false; COLS]),
+                settled: Cell::new(0),
+            }
+        }
+
+        // An undriven line is pulled low, and only conducts towards the cathode side
+        fn read_row(&self, row: usize) -> bool {
+            self.col2row && (0..COLS).any(|col| self.pressed[row][col] && self.cols.borrow()[col])
+        }
+
+        fn read_col(&self, col: usize) -> bool {
+            !self.col2row && (0..ROWS).any(|row| self.pressed[row][col] && self.rows.borrow()[row])
+        }
+    }
+
+    enum Line {
+        Row(usize),
+        Col(usize),
+    }
+
+    struct Pin<'a> {
+        board: &'a Board,
+        line: Line,
+    }
+
+    impl ErrorType for Pin<'_> {
+        type Error = Infallible;
+    }
+
+    impl InputPin for Pin<'_> {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            Ok(match self.line {
+                Line::Row(row) => self.board.read_row(row),
+                Line::Col(col) => self.board.read_col(col),
+            })
+        }
+
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            self.is_high().map(|high| !high)
+        }
+    }
+
+    impl OutputPin for Pin<'_> {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.drive(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.drive(true);
+            Ok(())
+        }
+    }
+
+    impl Pin<'_> {
+        fn drive(&mut self, high: bool) {
+            match self.line {
+                Line::Row(row) => self.board.rows.borrow_mut()[row] = high,
+                Line::Col(col) => self.board.cols.borrow_mut()[col] = high,
+            }
+        }
+    }
+
+    struct Delay<'a>(&'a Board);
+
+    impl DelayNs for Delay<'_> {
+        fn delay_ns(&mut self, _: u32) {
+            self.0.settled.set(self.0.settled.get() + 1);
+        }
+    }
+
+    fn rows(board: &Board) -> [Pin<'_>; ROWS] {
+        core::array::from_fn(|row| Pin {
+            board,
+            line: Line::Row(row),
+        })
+    }
+
+    fn cols(board: &Board) -> [Pin<'_>; COLS] {
+        core::array::from_fn(|col| Pin {
+            board,
+            line: Line::Col(col),
+        })
+    }
+
+    fn bitmap(keys: &[(usize, usize)]) -> KeyBitmap<ROWS, COLS> {
+        let mut res = KeyBitmap::new();
+        for &(row, col) in keys {
+            res.set(row, col, true);
+        }
+        res
+    }
+
+    #[test]
+    fn test_key_bitmap() {
+        let mut keys = bitmap(&[(0, 1), (1, 2)]);
+        assert_eq!(keys.rows(), &[0b010, 0b100]);
+        assert!(keys.is_pressed(1, 2));
+
+        keys.set(1, 2, false);
+        assert!(!keys.is_pressed(1, 2));
+        assert_eq!(
+            keys.to_keys::<6>(),
+            [false, true, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn test_col2row() {
+        // Keys sharing a row and a column
+        let pressed = [(0, 0), (0, 2), (1, 2)];
+        let board = Board::new(true, &pressed);
+        let mut scanner = MatrixScanner::col2row(rows(&board), cols(&board), Delay(&board));
+
+        assert_eq!(scanner.scan(), Ok(bitmap(&pressed)));
+        // Settled once per driven column
+        assert_eq!(board.settled.get(), COLS as u32);
+        // Nothing is left driven
+        assert_eq!(*board.cols.borrow(), [false; COLS]);
+    }
+
+    #[test]
+    fn test_row2col() {
+        let pressed = [(0, 0), (0, 2), (1, 2)];
+        let board = Board::new(false, &pressed);
+        let mut scanner = MatrixScanner::row2col(rows(&board), cols(&board), Delay(&board));
+
+        assert_eq!(scanner.scan(), Ok(bitmap(&pressed)));
+        assert_eq!(board.settled.get(), ROWS as u32);
+        assert_eq!(*board.rows.borrow(), [false; ROWS]);
+    }
+
+    #[test]
+    fn test_wrong_direction() {
+        // The diodes block the current, so nothing reads as pressed
+        let board = Board::new(false, &[(0, 0), (1, 1)]);
+        let mut scanner = MatrixScanner::col2row(rows(&board), cols(&board), Delay(&board));
+
+        assert_eq!(scanner.scan(), Ok(KeyBitmap::new()));
+    }
+
+    // The board's lines spread over a bank of pins, the rows next to each other and the columns
+    // not, with pins in between that aren't part of the matrix
+    const ROW_PINS: [u32; ROWS] = [1 << 1, 1 << 2];
+    const COL_PINS: [u32; COLS] = [1 << 0, 1 << 4, 1 << 7];
+    const ROW_MASK: u32 = 0b110;
+    const COL_MASK: u32 = 0b1001_0001;
+
+    struct Bank<'a>(&'a Board);
+
+    impl PinBank for Bank<'_> {
+        fn read(&mut self) -> u32 {
+            let rows = (0..ROWS).filter(|&row| self.0.read_row(row));
+            let cols = (0..COLS).filter(|&col| self.0.read_col(col));
+            // An unrelated pin that happens to be high
+            rows.map(|row| ROW_PINS[row])
+                .chain(cols.map(|col| COL_PINS[col]))
+                .fold(1 << 3, |res, pin| res | pin)
+        }
+
+        fn set_high(&mut self, pins: u32) {
+            self.drive(pins, true);
+        }
+
+        fn set_low(&mut self, pins: u32) {
+            self.drive(pins, false);
+        }
+    }
+
+    impl Bank<'_> {
+        fn drive(&mut self, pins: u32, high: bool) {
+            for (row, &pin) in ROW_PINS.iter().enumerate() {
+                if pins & pin != 0 {
+                    self.0.rows.borrow_mut()[row] = high;
+                }
+            }
+            for (col, &pin) in COL_PINS.iter().enumerate() {
+                if pins & pin != 0 {
+                    self.0.cols.borrow_mut()[col] = high;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_gather() {
+        assert_eq!(gather(0b1010_1100, 0b0011_1100), 0b1011);
+        assert_eq!(gather(0b1010_1100, 0b1010_0101), 0b1110);
+        assert_eq!(gather(u32::MAX, u32::MAX), u32::MAX);
+        assert_eq!(gather(1 << 31, 1 << 31), 1);
+    }
+
+    #[test]
+    fn test_snapshot_col2row() {
+        let pressed = [(0, 0), (0, 2), (1, 2)];
+        let board = Board::new(true, &pressed);
+        let mut scanner = SnapshotScanner::col2row(Bank(&board), ROW_MASK, COL_MASK, Delay(&board));
+
+        assert_eq!(scanner.scan(), Ok(bitmap(&pressed)));
+        assert_eq!(board.settled.get(), COLS as u32);
+        assert_eq!(*board.cols.borrow(), [false; COLS]);
+    }
+
+    #[test]
+    fn test_snapshot_row2col() {
+        let pressed = [(0, 1), (1, 0), (1, 2)];
+        let board = Board::new(false, &pressed);
+        let mut scanner = SnapshotScanner::row2col(Bank(&board), ROW_MASK, COL_MASK, Delay(&board));
+
+        assert_eq!(scanner.scan(), Ok(bitmap(&pressed)));
+        assert_eq!(board.settled.get(), ROWS as u32);
+        assert_eq!(*board.rows.borrow(), [false; ROWS]);
+    }
+
+    // What the PIO pushes after driving `driven` and reading `inputs`, as pin masks
+    fn sample(driven: u32, inputs: u32) -> u32 {
+        inputs << 16 | driven
+    }
+
+    #[test]
+    fn test_decode_samples() {
+        // Columns spread out over the pins, as they are on the board
+        let decoder = SampleDecoder::<ROWS, COLS>::col2row([0, 1], [0, 2, 6]);
+        assert_eq!(decoder.driven(), [0b1, 0b100, 0b100_0000]);
+
+        // Not every column has been sampled yet, the rest of the ring hasn't been written
+        let mut ring = [0; 8];
+        ring[0] = sample(0b1, 0b01);
+        ring[1] = sample(0b100, 0b00);
+        assert_eq!(decoder.decode(&ring, 2), None);
+
+        ring[2] = sample(0b100_0000, 0b11);
+        assert_eq!(
+            decoder.decode(&ring, 3),
+            Some(bitmap(&[(0, 0), (0, 2), (1, 2)]))
+        );
+
+        // The newest sample of a column wins, looking back across the end of the ring
+        let ring = [
+            sample(0b100, 0b10),
+            sample(0b100_0000, 0b00),
+            sample(0b1, 0b11),
+            sample(0b100, 0b00),
+            sample(0b100_0000, 0b00),
+            sample(0b1, 0b00),
+            sample(0b100, 0b00),
+            sample(0b100_0000, 0b01),
+        ];
+        assert_eq!(decoder.decode(&ring, 1), Some(bitmap(&[(1, 1), (0, 2)])));
+        assert_eq!(
+            decoder.decode(&ring, 3),
+            Some(bitmap(&[(0, 0), (1, 0), (1, 1)]))
+        );
+
+        // Inputs past the rows are ignored
+        let ring = [
+            sample(0b1, 0b1100),
+            sample(0b100, 0b0),
+            sample(0b100_0000, 0b0),
+        ];
+        assert_eq!(decoder.decode(&ring, 0), Some(KeyBitmap::new()));
+    }
+
+    #[test]
+    fn test_decode_row2col() {
+        let decoder = SampleDecoder::<ROWS, COLS>::row2col([3, 4], [0, 1, 2]);
+        assert_eq!(decoder.driven(), [0b1000, 0b1_0000]);
+
+        let ring = [sample(0b1000, 0b101), sample(0b1_0000, 0b010)];
+        assert_eq!(
+            decoder.decode(&ring, 0),
+            Some(bitmap(&[(0, 0), (0, 2), (1, 1)]))
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Mouse keys, turning held directions into movement with acceleration
+
+use config::no_std::{Direction, MouseCurve};
+
+use crate::clock::Instant;
+
+/// Mouse buttons and movement to send to the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl MouseReport {
+    pub fn is_moving(&self) -> bool {
+        self.x != 0 || self.y != 0 || self.wheel != 0 || self.pan != 0
+    }
+}
+
+/// Speed in units per second after a direction has been held for `held_ms`
+pub fn speed(curve: &MouseCurve, held_ms: u32) -> u32 {
+    if curve.time_to_max_ms == 0 || held_ms >= curve.time_to_max_ms {
+        return curve.max_speed;
+    }
+
+    // Progress along the curve, fixed point with 16 fractional bits
+    let progress = ((held_ms as u64) << 16) / curve.time_to_max_ms as u64;
+    let scaled = (0..curve.exponent).fold(1u64 << 16, |acc, _| (acc * progress) >> 16);
+    let range = curve.max_speed.saturating_sub(curve.start_speed) as u64;
+
+    curve.start_speed + ((range * scaled) >> 16) as u32
+}
+
+/// Per-axis velocity in units per second, in report order: x, y, wheel, pan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Velocity(pub [i32; 4]);
+
+impl Velocity {
+    pub fn add_move(&mut self, direction: Direction, speed: u32) {
+        // HID y grows downwards
+        match direction {
+            Direction::Up => self.0[1] -= speed as i32,
+            Direction::Down => self.0[1] += speed as i32,
+            Direction::Left => self.0[0] -= speed as i32,
+            Direction::Right => self.0[0] += speed as i32,
+        }
+    }
+
+    pub fn add_scroll(&mut self, direction: Direction, speed: u32) {
+        // The wheel grows upwards
+        match direction {
+            Direction::Up => self.0[2] += speed as i32,
+            Direction::Down => self.0[2] -= speed as i32,
+            Direction::Left => self.0[3] -= speed as i32,
+            Direction::Right => self.0[3] += speed as i32,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 4]
+    }
+}
+
+/// Turns velocities into movement per report. Fractions of a unit are carried over to the next
+/// report so slow speeds still move at the right rate.
+#[derive(Debug, Default)]
+pub struct Integrator {
+    last: Option<Instant>,
+    // In units * ms / s
+    remainder: [i32; 4],
+}
+
+impl Integrator {
+    pub fn step(&mut self, now: Instant, velocity: Velocity) -> [i8; 4] {
+        if velocity.is_zero() {
+            // Don't count idle time towards the next movement
+            *self = Self::default();
+            return [0; 4];
+        }
+
+        let elapsed = self.last.map_or(0, |last| now.millis_since(last)) as i32;
+        self.last = Some(now);
+
+        let mut res = [0; 4];
+
+        for (axis, out) in res.iter_mut().enumerate() {
+            let total = self.remainder[axis] + velocity.0[axis] * elapsed;
+            let units = total / 1000;
+
+            *out = units.clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+            // Anything past what fits in one report is dropped rather than building up
+            self.remainder[axis] = if units == *out as i32 {
+                total - units * 1000
+            } else {
+                0
+            };
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::no_std::{Direction, MouseCurve};
+
+    use crate::clock::Instant;
+    use crate::mouse::{speed, Integrator, Velocity};
+
+    const LINEAR: MouseCurve = MouseCurve {
+        start_speed: 100,
+        max_speed: 1100,
+        time_to_max_ms: 1000,
+        exponent: 1,
+    };
+
+    #[test]
+    fn test_speed() {
+        let quadratic = MouseCurve {
+            exponent: 2,
+            ..LINEAR
+        };
+
+        assert_eq!(speed(&LINEAR, 0), 100);
+        assert_eq!(speed(&LINEAR, 500), 600);
+        assert_eq!(speed(&LINEAR, 1000), 1100);
+        assert_eq!(speed(&LINEAR, 5000), 1100);
+
+        assert_eq!(speed(&quadratic, 0), 100);
+        assert_eq!(speed(&quadratic, 500), 350);
+        assert_eq!(speed(&quadratic, 1000), 1100);
+
+        let flat = MouseCurve {
+            time_to_max_ms: 0,
+            ..LINEAR
+        };
+        assert_eq!(speed(&flat, 0), 1100);
+    }
+
+    #[test]
+    fn test_velocity() {
+        let mut v = Velocity::default();
+        v.add_move(Direction::Up, 10);
+        v.add_move(Direction::Right, 20);
+        v.add_scroll(Direction::Up, 3);
+
+        assert_eq!(v.0, [20, -10, 3, 0]);
+    }
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn test_integrator() {
+        let mut integrator = Integrator::default();
+        let mut v = Velocity::default();
+        v.add_move(Direction::Right, 150);
+
+        // Nothing has elapsed on the first step
+        assert_eq!(integrator.step(at(0), v), [0; 4]);
+        // 1.5 units, the half carries over
+        assert_eq!(integrator.step(at(10), v), [1, 0, 0, 0]);
+        assert_eq!(integrator.step(at(20), v), [2, 0, 0, 0]);
+
+        // Saturates instead of wrapping
+        let mut fast = Velocity::default();
+        fast.add_move(Direction::Up, 100_000);
+        assert_eq!(integrator.step(at(30), fast), [0, -128, 0, 0]);
+
+        // Stopping resets the timing
+        assert_eq!(integrator.step(at(40), Velocity::default()), [0; 4]);
+        assert_eq!(integrator.step(at(1000), v), [0; 4]);
+    }
+}
@@ -0,0 +1,215 @@
+//! Scanning the key matrix with PIO0, leaving the CPU out of it
+//!
+//! A state machine drives each line in turn, waits for the inputs to settle and pushes what it
+//! read. One DMA channel feeds it the lines to drive from a ring of strobe masks and another copies
+//! its samples into a ring buffer, both wrapping around their rings, so the matrix is scanned over
+//! and over without anything having to wait. A scan only decodes the latest samples.
+
+use core::convert::Infallible;
+
+use rp2040_hal::dma::{Channel, SingleChannel, CH0, CH1};
+use rp2040_hal::pac;
+use rp2040_hal::pio::{
+    PIOBuilder, PIOExt, PinDir, Running, ShiftDirection, StateMachine, PIO, SM0,
+};
+
+use crate::matrix::{KeyBitmap, SampleDecoder, Scanner, SETTLE_NS};
+
+// Long enough that every line has a sample in the ring, whichever order they are driven in
+const SAMPLES_LEN: usize = 64;
+const STROBES_LEN: usize = 32;
+// The DMA's ring sizes, as a power of two in bytes
+const SAMPLES_RING_BITS: u8 = 8;
+const STROBES_RING_BITS: u8 = 7;
+// Cycles the program spends waiting for the inputs to settle
+const SETTLE_CYCLES: u64 = 32;
+// The DMA requests for PIO0's first state machine
+const DREQ_PIO0_TX0: u8 = 0;
+const DREQ_PIO0_RX0: u8 = 4;
+
+/// Where the DMA channels read the lines to drive from and write the samples to. Each ring is
+/// aligned to its size, which the DMA needs to wrap around it.
+pub struct PioBuffers {
+    samples: Samples,
+    strobes: Strobes,
+}
+
+#[repr(C, align(256))]
+struct Samples([u32; SAMPLES_LEN]);
+
+#[repr(C, align(128))]
+struct Strobes([u32; STROBES_LEN]);
+
+impl PioBuffers {
+    pub const fn new() -> Self {
+        Self {
+            samples: Samples([0; SAMPLES_LEN]),
+            strobes: Strobes([0; STROBES_LEN]),
+        }
+    }
+}
+
+impl Default for PioBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct PioScanner<const ROWS: usize, const COLS: usize> {
+    decoder: SampleDecoder<ROWS, COLS>,
+    samples: &'static [u32; SAMPLES_LEN],
+    // Kept running for as long as the scanner is around
+    _sm: StateMachine<(pac::PIO0, SM0), Running>,
+    _pio: PIO<pac::PIO0>,
+    rx_dma: Channel<CH0>,
+    tx_dma: Channel<CH1>,
+}
+
+impl<const ROWS: usize, const COLS: usize> PioScanner<ROWS, COLS> {
+    /// A matrix with the diodes pointing from the columns to the rows, given as GPIO numbers. The
+    /// pins have to be set to the PIO0 function already, with pull-downs on the rows, and each
+    /// set has to fit in 16 pins.
+    #[allow(clippy::too_many_arguments)]
+    pub fn col2row(
+        pio: pac::PIO0,
+        resets: &mut pac::RESETS,
+        rx_dma: Channel<CH0>,
+        tx_dma: Channel<CH1>,
+        buffers: &'static mut PioBuffers,
+        rows: [u8; ROWS],
+        cols: [u8; COLS],
+        sys_hz: u32,
+    ) -> Self {
+        // Each column comes round at least once every two passes through the strobes
+        const { assert!(COLS > 0 && 2 * COLS <= SAMPLES_LEN && COLS <= STROBES_LEN) };
+
+        let row_base = rows.iter().copied().min().unwrap_or(0);
+        let col_base = cols.iter().copied().min().unwrap_or(0);
+        let col_span = cols.iter().copied().max().unwrap_or(0) - col_base + 1;
+        let decoder = SampleDecoder::col2row(
+            rows.map(|pin| pin - row_base),
+            cols.map(|pin| pin - col_base),
+        );
+
+        for (strobe, &mask) in buffers
+            .strobes
+            .0
+            .iter_mut()
+            .zip(decoder.driven().iter().cycle())
+        {
+            *strobe = mask;
+        }
+
+        let program = pio_proc::pio_asm!(
+            ".wrap_target",
+            "    pull block", // The mask of the next line to drive
+            "    mov y, osr",
+            "    out pins, 32",
+            "    set x, 31",
+            "settle:",
+            "    jmp x-- settle",
+            "    in pins, 16", // What was read, tagged with the line that was driven
+            "    in y, 16",
+            "    push block",
+            "    mov pins, null",
+            ".wrap",
+        );
+
+        // The settle loop takes SETTLE_NS, in 8.8 fixed point and no faster than the system clock
+        let divisor = (sys_hz as u64 * SETTLE_NS as u64 * 256 / (SETTLE_CYCLES * 1_000_000_000))
+            .clamp(256, u16::MAX as u64 * 256);
+
+        let (mut pio, sm0, _, _, _) = pio.split(resets);
+        let installed = pio.install(&program.program).unwrap();
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(installed)
+            .out_pins(col_base, col_span)
+            .in_pin_base(row_base)
+            .in_shift_direction(ShiftDirection::Left)
+            .clock_divisor_fixed_point((divisor >> 8) as u16, divisor as u8)
+            .build(sm0);
+        sm.set_pindirs(cols.iter().map(|&pin| (pin, PinDir::Output)));
+
+        let samples = &buffers.samples.0;
+        // The channels run for as long as their counts go, `scan` starts them again if they end
+        start(
+            &rx_dma,
+            rx.fifo_address() as u32,
+            samples.as_ptr() as u32,
+            DREQ_PIO0_RX0,
+            SAMPLES_RING_BITS,
+            true,
+        );
+        start(
+            &tx_dma,
+            buffers.strobes.0.as_ptr() as u32,
+            tx.fifo_address() as u32,
+            DREQ_PIO0_TX0,
+            STROBES_RING_BITS,
+            false,
+        );
+        let sm = sm.start();
+
+        let scanner = Self {
+            decoder,
+            samples,
+            _sm: sm,
+            _pio: pio,
+            rx_dma,
+            tx_dma,
+        };
+        // So the first scan, which reads the bootmagic keys, sees the whole matrix
+        while scanner.latest().is_none() {}
+        scanner
+    }
+
+    fn latest(&self) -> Option<KeyBitmap<ROWS, COLS>> {
+        let next = (self.rx_dma.ch().ch_write_addr().read().bits() - self.samples.as_ptr() as u32)
+            as usize
+            / 4;
+
+        // The DMA keeps writing while this copies, the samples are tagged so a newer one turning
+        // up makes no difference
+        let mut samples = [0; SAMPLES_LEN];
+        for (sample, word) in samples.iter_mut().zip(self.samples.iter()) {
+            *sample = unsafe { core::ptr::read_volatile(word) };
+        }
+
+        self.decoder.decode(&samples, next % SAMPLES_LEN)
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS> for PioScanner<ROWS, COLS> {
+    type Error = Infallible;
+
+    fn scan(&mut self) -> Result<KeyBitmap<ROWS, COLS>, Infallible> {
+        // A count lasts over an hour of scanning, the last samples stay in the ring until then
+        for ch in [self.rx_dma.ch(), self.tx_dma.ch()] {
+            if ch.ch_ctrl_trig().read().busy().bit_is_clear() {
+                ch.ch_al1_trans_count_trig()
+                    .write(|w| unsafe { w.bits(u32::MAX) });
+            }
+        }
+
+        Ok(self.latest().unwrap_or_default())
+    }
+}
+
+// Starts a channel copying words between the PIO and one of the rings, with `ring_bits` the ring's
+// size. The ring is on the write side when `to_ring` is set and on the read side otherwise.
+fn start<CH: SingleChannel>(ch: &CH, from: u32, to: u32, dreq: u8, ring_bits: u8, to_ring: bool) {
+    let regs = ch.ch();
+    regs.ch_read_addr().write(|w| unsafe { w.bits(from) });
+    regs.ch_write_addr().write(|w| unsafe { w.bits(to) });
+    regs.ch_trans_count().write(|w| unsafe { w.bits(u32::MAX) });
+    regs.ch_ctrl_trig().write(|w| unsafe {
+        w.data_size().size_word();
+        w.incr_read().bit(!to_ring);
+        w.incr_write().bit(to_ring);
+        w.ring_size().bits(ring_bits);
+        w.ring_sel().bit(to_ring);
+        w.treq_sel().bits(dreq);
+        // Chained to itself, which is how the datasheet says to chain to nothing
+        w.chain_to().bits(ch.id());
+        w.en().set_bit()
+    });
+}
@@ -0,0 +1,94 @@
+//! Vendor defined HID interface for talking to host tools, using the same usage page as QMK so
+//! existing tools can find it
+
+use config::protocol;
+use defmt::unwrap;
+use fugit::ExtU32;
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usbd_human_interface_device::interface::{
+    InBytes32, Interface, InterfaceBuilder, InterfaceConfig, OutBytes32, ReportSingle,
+    UsbAllocatable,
+};
+use usbd_human_interface_device::usb_class::prelude::*;
+use usbd_human_interface_device::UsbHidError;
+
+/// Size of every raw HID report, in both directions
+pub const RAW_REPORT_LEN: usize = protocol::REPORT_LEN;
+
+#[rustfmt::skip]
+pub const RAW_HID_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,       // Usage (0x61)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x62,       //   Usage (0x62)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20,       //   Report Count (32)
+    0x75, 0x08,       //   Report Size (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x09, 0x63,       //   Usage (0x63)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20,       //   Report Count (32)
+    0x75, 0x08,       //   Report Size (8)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0xC0,             // End Collection
+];
+
+pub struct RawHid<'a, B: UsbBus> {
+    interface: Interface<'a, B, InBytes32, OutBytes32, ReportSingle>,
+}
+
+impl<B: UsbBus> RawHid<'_, B> {
+    pub fn write_report(&mut self, data: &[u8; RAW_REPORT_LEN]) -> Result<(), UsbHidError> {
+        self.interface
+            .write_report(data)
+            .map(|_| ())
+            .map_err(UsbHidError::from)
+    }
+
+    /// Read a report from the host if one is waiting, returns the number of bytes read
+    pub fn read_report(&mut self, data: &mut [u8; RAW_REPORT_LEN]) -> Result<usize, UsbHidError> {
+        self.interface.read_report(data).map_err(UsbHidError::from)
+    }
+}
+
+impl<'a, B: UsbBus> DeviceClass<'a> for RawHid<'a, B> {
+    type I = Interface<'a, B, InBytes32, OutBytes32, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        Ok(())
+    }
+}
+
+pub struct RawHidConfig<'a> {
+    interface: InterfaceConfig<'a, InBytes32, OutBytes32, ReportSingle>,
+}
+
+impl Default for RawHidConfig<'_> {
+    fn default() -> Self {
+        Self {
+            interface: unwrap!(unwrap!(unwrap!(InterfaceBuilder::new(RAW_HID_DESCRIPTOR))
+                .description("Raw HID")
+                .in_endpoint(1.millis()))
+            .with_out_endpoint(1.millis()))
+            .build(),
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbAllocatable<'a, B> for RawHidConfig<'a> {
+    type Allocated = RawHid<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        RawHid {
+            interface: Interface::new(usb_alloc, self.interface),
+        }
+    }
+}
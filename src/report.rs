@@ -0,0 +1,237 @@
+//! Keyboard report building, for both the 6KRO boot protocol and the NKRO report protocol
+
+/// Keys that fit in a boot protocol report
+pub const BOOT_KEYS: usize = 6;
+/// Usages covered by the NKRO bitmap, everything up to and including International 9 (0x87)
+pub const NKRO_USAGES: usize = 0x88;
+pub const BOOT_REPORT_LEN: usize = 2 + BOOT_KEYS;
+/// The NKRO report starts with a boot report so the same descriptor covers both protocols
+pub const NKRO_REPORT_LEN: usize = BOOT_REPORT_LEN + NKRO_USAGES / 8;
+
+const ERROR_ROLL_OVER: u8 = 0x01;
+const FIRST_MODIFIER: u8 = 0xE0;
+const LAST_MODIFIER: u8 = 0xE7;
+
+/// Boot compatible header followed by the NKRO bitmap. The boot key slots are marked constant,
+/// so report protocol hosts only read the bitmap.
+#[rustfmt::skip]
+pub const KEYBOARD_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x06,       // Usage (Keyboard)
+    0xA1, 0x01,       // Collection (Application)
+    0x05, 0x07,       //   Usage Page (Keyboard)
+    0x19, 0xE0,       //   Usage Minimum (Left Control)
+    0x29, 0xE7,       //   Usage Maximum (Right GUI)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x08,       //   Report Count (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute), modifiers
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x01,       //   Input (Constant), reserved
+    0x05, 0x08,       //   Usage Page (LEDs)
+    0x19, 0x01,       //   Usage Minimum (Num Lock)
+    0x29, 0x05,       //   Usage Maximum (Kana)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x05,       //   Report Count (5)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute), LEDs
+    0x75, 0x03,       //   Report Size (3)
+    0x95, 0x01,       //   Report Count (1)
+    0x91, 0x01,       //   Output (Constant), LED padding
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x06,       //   Report Count (6)
+    0x81, 0x01,       //   Input (Constant), boot keys
+    0x05, 0x07,       //   Usage Page (Keyboard)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x29, 0x87,       //   Usage Maximum (International 9)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x96, 0x88, 0x00, //   Report Count (136)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute), NKRO bitmap
+    0xC0,             // End Collection
+];
+
+/// Which report format the host asked for with SET_PROTOCOL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Boot,
+    Report,
+}
+
+/// Collects pressed keyboard usages, with modifiers kept apart from the other keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportBuilder {
+    mods: u8,
+    keys: [u8; NKRO_USAGES / 8],
+    // Keys in the order they were pressed, for the boot report
+    boot_keys: [u8; BOOT_KEYS],
+    count: usize,
+}
+
+impl Default for ReportBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReportBuilder {
+    pub const fn new() -> Self {
+        Self {
+            mods: 0,
+            keys: [0; NKRO_USAGES / 8],
+            boot_keys: [0; BOOT_KEYS],
+            count: 0,
+        }
+    }
+
+    /// Add modifiers, as bits of the HID modifier byte
+    pub fn mods(&mut self, mods: u8) {
+        self.mods |= mods;
+    }
+
+    /// Add a keyboard page usage. Modifier usages go to the modifier byte, usages past the end of
+    /// the bitmap are dropped.
+    pub fn press(&mut self, usage: u8) {
+        match usage {
+            0 => {}
+            FIRST_MODIFIER..=LAST_MODIFIER => self.mods |= 1 << (usage - FIRST_MODIFIER),
+            _ if (usage as usize) < NKRO_USAGES => {
+                let (byte, bit) = (usage as usize / 8, usage % 8);
+                if self.keys[byte] & (1 << bit) != 0 {
+                    return;
+                }
+
+                self.keys[byte] |= 1 << bit;
+                if self.count < BOOT_KEYS {
+                    self.boot_keys[self.count] = usage;
+                }
+                self.count += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// The 8 byte boot report. With more than 6 keys down every slot is ErrorRollOver, as the spec
+    /// requires, so the host keeps its previous state instead of seeing a wrong subset.
+    pub fn boot_report(&self) -> [u8; BOOT_REPORT_LEN] {
+        let mut res = [0; BOOT_REPORT_LEN];
+        res[0] = self.mods;
+
+        if self.count > BOOT_KEYS {
+            res[2..].fill(ERROR_ROLL_OVER);
+        } else {
+            res[2..].copy_from_slice(&self.boot_keys);
+        }
+
+        res
+    }
+
+    pub fn nkro_report(&self) -> [u8; NKRO_REPORT_LEN] {
+        let mut res = [0; NKRO_REPORT_LEN];
+        res[..BOOT_REPORT_LEN].copy_from_slice(&self.boot_report());
+        res[BOOT_REPORT_LEN..].copy_from_slice(&self.keys);
+        res
+    }
+
+    /// Write the report for `protocol` into `buf`, returning its length
+    pub fn write(&self, protocol: Protocol, buf: &mut [u8; NKRO_REPORT_LEN]) -> usize {
+        match protocol {
+            Protocol::Boot => {
+                buf[..BOOT_REPORT_LEN].copy_from_slice(&self.boot_report());
+                BOOT_REPORT_LEN
+            }
+            Protocol::Report => {
+                *buf = self.nkro_report();
+                NKRO_REPORT_LEN
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::report::{
+        Protocol, ReportBuilder, BOOT_REPORT_LEN, KEYBOARD_DESCRIPTOR, NKRO_REPORT_LEN,
+    };
+    use crate::usb::report_lengths;
+
+    // Keyboard page usages
+    const A: u8 = 0x04;
+    const B: u8 = 0x05;
+    const C: u8 = 0x06;
+    const LEFT_SHIFT: u8 = 0xE1;
+    const RIGHT_ALT: u8 = 0xE6;
+
+    #[test]
+    fn test_boot_report() {
+        let mut builder = ReportBuilder::new();
+        builder.press(B);
+        builder.press(A);
+        builder.press(LEFT_SHIFT);
+        builder.press(B);
+
+        assert_eq!(builder.boot_report(), [0x02, 0, B, A, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let mut builder = ReportBuilder::new();
+        builder.mods(0x01);
+        builder.press(RIGHT_ALT);
+
+        assert_eq!(builder.boot_report(), [0x41, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(builder.nkro_report()[BOOT_REPORT_LEN..], [0; 17]);
+    }
+
+    #[test]
+    fn test_roll_over() {
+        let mut builder = ReportBuilder::new();
+        builder.mods(0x02);
+        for usage in A..A + 7 {
+            builder.press(usage);
+        }
+
+        assert_eq!(builder.boot_report(), [0x02, 0, 1, 1, 1, 1, 1, 1]);
+
+        // NKRO still has every key
+        let nkro = builder.nkro_report();
+        assert_eq!(nkro[BOOT_REPORT_LEN], 0b1111_0000);
+        assert_eq!(nkro[BOOT_REPORT_LEN + 1], 0b0000_0111);
+    }
+
+    #[test]
+    fn test_nkro_report() {
+        let mut builder = ReportBuilder::new();
+        builder.press(C);
+        // Past the end of the bitmap
+        builder.press(0xA0);
+
+        let nkro = builder.nkro_report();
+        assert_eq!(nkro[..BOOT_REPORT_LEN], [0, 0, C, 0, 0, 0, 0, 0]);
+        assert_eq!(nkro[BOOT_REPORT_LEN], 1 << C);
+        assert_eq!(nkro[BOOT_REPORT_LEN + 1..], [0; 16]);
+    }
+
+    #[test]
+    fn test_protocol() {
+        let mut builder = ReportBuilder::new();
+        builder.press(A);
+
+        let mut buf = [0xFF; NKRO_REPORT_LEN];
+        assert_eq!(builder.write(Protocol::Boot, &mut buf), BOOT_REPORT_LEN);
+        assert_eq!(buf[..BOOT_REPORT_LEN], builder.boot_report());
+
+        assert_eq!(builder.write(Protocol::Report, &mut buf), NKRO_REPORT_LEN);
+        assert_eq!(buf, builder.nkro_report());
+    }
+
+    #[test]
+    fn test_descriptor_matches_report() {
+        assert_eq!(
+            report_lengths(KEYBOARD_DESCRIPTOR),
+            Ok((NKRO_REPORT_LEN, 1))
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Safe mode, booting with a minimal built-in keymap when the normal one can't be trusted, so a bad
+//! keymap can never leave a board without a working keyboard
+
+use config::no_std::{Bootmagic, KEYS};
+use config::protocol::SafeModeReason;
+
+/// What the bootmagic keys held at power on ask for
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BootmagicHeld {
+    pub safe_mode: bool,
+    pub clear: bool,
+}
+
+impl BootmagicHeld {
+    pub fn from_matrix(bootmagic: &Bootmagic, matrix: &[bool; KEYS]) -> Self {
+        let held = |key: Option<u8>| key.is_some_and(|key| matrix[key as usize]);
+
+        Self {
+            safe_mode: held(bootmagic.safe_mode),
+            clear: held(bootmagic.clear),
+        }
+    }
+}
+
+/// Safe mode decided before any keymap is loaded, a keymap that fails validation is checked later
+pub fn early_reason(panicked: bool, held: BootmagicHeld) -> Option<SafeModeReason> {
+    if panicked {
+        Some(SafeModeReason::Panicked)
+    } else if held.safe_mode {
+        Some(SafeModeReason::Bootmagic)
+    } else {
+        None
+    }
+}
+
+/// A line for the console on how the board booted
+pub fn boot_message(reason: Option<SafeModeReason>) -> &'static str {
+    match reason {
+        None => "Booted normally\r\n",
+        Some(SafeModeReason::InvalidKeymap) => {
+            "Safe mode: the keymap failed validation, using the built-in QWERTY keymap\r\n"
+        }
+        Some(SafeModeReason::Panicked) => {
+            "Safe mode: the firmware panicked on the last boot, using the built-in QWERTY keymap\r\n"
+        }
+        Some(SafeModeReason::Bootmagic) => {
+            "Safe mode: the bootmagic key was held, using the built-in QWERTY keymap\r\n"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::no_std::{Bootmagic, KEYS};
+    use config::protocol::SafeModeReason;
+
+    use crate::safe_mode::{early_reason, BootmagicHeld};
+
+    #[test]
+    fn test_bootmagic() {
+        let bootmagic = Bootmagic {
+            safe_mode: Some(0),
+            clear: Some(5),
+        };
+        let mut matrix = [false; KEYS];
+
+        assert_eq!(
+            BootmagicHeld::from_matrix(&bootmagic, &matrix),
+            BootmagicHeld::default()
+        );
+
+        matrix[5] = true;
+        assert_eq!(
+            BootmagicHeld::from_matrix(&bootmagic, &matrix),
+            BootmagicHeld {
+                safe_mode: false,
+                clear: true,
+            }
+        );
+
+        matrix[0] = true;
+        let held = BootmagicHeld::from_matrix(&bootmagic, &matrix);
+        assert!(held.safe_mode && held.clear);
+
+        // Nothing configured, nothing held
+        let none = Bootmagic {
+            safe_mode: None,
+            clear: None,
+        };
+        assert_eq!(
+            BootmagicHeld::from_matrix(&none, &[true; KEYS]),
+            BootmagicHeld::default()
+        );
+    }
+
+    #[test]
+    fn test_early_reason() {
+        let held = BootmagicHeld {
+            safe_mode: true,
+            clear: false,
+        };
+
+        assert_eq!(early_reason(false, BootmagicHeld::default()), None);
+        assert_eq!(early_reason(false, held), Some(SafeModeReason::Bootmagic));
+        assert_eq!(early_reason(true, held), Some(SafeModeReason::Panicked));
+    }
+}
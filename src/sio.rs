@@ -0,0 +1,41 @@
+//! The RP2040's bank 0 GPIOs as a `PinBank`, read and driven through SIO a register at a time
+
+use rp2040_hal::pac;
+
+use crate::matrix::PinBank;
+
+/// Reads `GPIO_IN` and drives through `GPIO_OUT_SET` and `GPIO_OUT_CLR`, which leave the other
+/// pins alone. Only touches the pins it is told to, which have to be set up as SIO inputs and
+/// outputs already.
+pub struct SioBank(());
+
+impl SioBank {
+    pub fn new() -> Self {
+        Self(())
+    }
+}
+
+impl Default for SioBank {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PinBank for SioBank {
+    fn read(&mut self) -> u32 {
+        sio().gpio_in().read().bits()
+    }
+
+    fn set_high(&mut self, pins: u32) {
+        sio().gpio_out_set().write(|w| unsafe { w.bits(pins) });
+    }
+
+    fn set_low(&mut self, pins: u32) {
+        sio().gpio_out_clr().write(|w| unsafe { w.bits(pins) });
+    }
+}
+
+// The set and clear registers are atomic, so sharing SIO with the HAL's pins is safe
+fn sio() -> &'static pac::sio::RegisterBlock {
+    unsafe { &*pac::SIO::ptr() }
+}
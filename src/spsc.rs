@@ -0,0 +1,176 @@
+//! Lock-free single producer, single consumer queue, for handing values from one core to the
+//! other
+//!
+//! Only atomic loads and stores are used, which the RP2040's cores have, unlike compare and swap.
+//! The producer is the only one to move `tail` and the consumer the only one to move `head`, so
+//! each side only has to see the other's writes in order.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Queue with room for `N` values, split into its two ends to use it
+pub struct Spsc<T, const N: usize> {
+    buffer: [UnsafeCell<MaybeUninit<T>>; N],
+    // Counts of values popped and pushed, wrapping. The index into `buffer` is the count mod `N`,
+    // which carries on across the wrap because `N` is a power of two.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// Each slot is only touched by one end at a time, as handed over through `head` and `tail`
+unsafe impl<T: Send, const N: usize> Sync for Spsc<T, N> {}
+
+impl<T: Copy, const N: usize> Spsc<T, N> {
+    pub const fn new() -> Self {
+        const {
+            assert!(
+                N > 0 && N.is_power_of_two(),
+                "length must be a power of two"
+            )
+        };
+
+        Self {
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// The two ends, which can go to different cores. Borrowing the queue mutably means there is
+    /// only ever one of each.
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+
+    fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for Spsc<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a Spsc<T, N>,
+}
+
+impl<T: Copy, const N: usize> Producer<'_, T, N> {
+    /// Add `value` at the back, giving it back when the queue is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let queue = self.queue;
+        let tail = queue.tail.load(Ordering::Relaxed);
+        // The consumer has to be done reading a slot before it is written again
+        if tail.wrapping_sub(queue.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+
+        unsafe { (*queue.buffer[tail % N].get()).write(value) };
+        // Publishes the write above along with the new tail
+        queue.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.len() == N
+    }
+}
+
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a Spsc<T, N>,
+}
+
+impl<T: Copy, const N: usize> Consumer<'_, T, N> {
+    /// Take the value at the front
+    pub fn pop(&mut self) -> Option<T> {
+        let queue = self.queue;
+        let head = queue.head.load(Ordering::Relaxed);
+        if head == queue.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { (*queue.buffer[head % N].get()).assume_init_read() };
+        // Hands the slot back to the producer once it has been read
+        queue.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+
+    use crate::spsc::Spsc;
+
+    #[test]
+    fn test_spsc() {
+        let mut queue = Spsc::<u32, 4>::new();
+        let (mut producer, mut consumer) = queue.split();
+        assert_eq!(consumer.pop(), None);
+
+        for i in 0..4 {
+            producer.push(i).unwrap();
+        }
+        assert!(producer.is_full());
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.len(), 4);
+
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(consumer.pop(), Some(1));
+        // Wraps around into the freed slots
+        producer.push(4).unwrap();
+        producer.push(5).unwrap();
+        assert_eq!(producer.push(6), Err(6));
+        for i in 2..6 {
+            assert_eq!(consumer.pop(), Some(i));
+        }
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn test_spsc_threads() {
+        // Every value arrives once and in order, with the two ends racing each other on a queue
+        // small enough to fill up all the time
+        const COUNT: u32 = 100_000;
+        let mut queue = Spsc::<u32, 8>::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..COUNT {
+                    while producer.push(i).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            });
+
+            let mut expected = 0;
+            while expected < COUNT {
+                match consumer.pop() {
+                    Some(value) => {
+                        assert_eq!(value, expected);
+                        expected += 1;
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+        });
+
+        assert!(queue.split().1.is_empty());
+    }
+}
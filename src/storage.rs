@@ -0,0 +1,809 @@
+//! Settings kept in a reserved flash region, as a log of records spread over all of its sectors
+//!
+//! Every sector starts with a header holding its generation, the newest sector is the head and
+//! records are appended to it. A record only counts if its CRC checks out, so a write cut short by
+//! power loss is skipped and the previous record for the key is still read back. Each key reads
+//! as its record with the highest sequence number.
+//!
+//! The sector after the head is always kept erased. Moving to it copies every record that is still
+//! current out of the oldest sector, then writes the new sector's header, and only then erases the
+//! oldest sector. Until the header is written the old head is still the newest sector, and once it
+//! is written everything in the oldest sector has a newer copy, so a cut at any point loses
+//! nothing. Mounting erases the sector after the head if a cut left anything in it.
+
+use config::crc::Crc32;
+use config::no_std::Layer;
+use config::protocol::{decode_keymap, encode_keymap, KEYMAP_LEN};
+use config::NUM_LAYERS;
+use embedded_storage::nor_flash::{ErrorType, NorFlash};
+
+const SECTOR_MAGIC: u32 = 0x4B42_5331;
+const RECORD_MAGIC: u32 = 0x4B42_5231;
+const ERASED: u32 = 0xFFFF_FFFF;
+// Magic, generation and the generation inverted, so a torn header can't pass for a valid one
+const SECTOR_HEADER_LEN: usize = 12;
+// Magic, key, a reserved byte, data length, sequence number and the CRC of everything after the
+// magic, data included
+const RECORD_HEADER_LEN: usize = 16;
+/// Largest record, header included
+pub const MAX_RECORD_LEN: usize = 1024;
+/// Largest data a record can hold
+pub const MAX_DATA_LEN: usize = MAX_RECORD_LEN - RECORD_HEADER_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKey {
+    Keymap,
+    Settings,
+}
+
+const RECORD_KEYS: [RecordKey; 2] = [RecordKey::Keymap, RecordKey::Settings];
+
+const _: () = assert!(KEYMAP_LEN <= MAX_DATA_LEN);
+
+/// Settings changed at runtime that are kept across resets
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub locked_layers: u16,
+    /// CRC of the keymap partition the stored keymap was edited from, 0 for the compiled-in
+    /// keymap. Once a different partition is written the stored keymap no longer applies.
+    pub keymap_crc: u32,
+}
+
+impl Settings {
+    const LEN: usize = 6;
+
+    fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..2].copy_from_slice(&self.locked_layers.to_le_bytes());
+        bytes[2..6].copy_from_slice(&self.keymap_crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self {
+            locked_layers: u16::from_le_bytes([bytes[0], bytes[1]]),
+            keymap_crc: u32::from_le_bytes(bytes[2..6].try_into().unwrap()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageError<E> {
+    Flash(E),
+    /// The data doesn't fit in a record, or a record doesn't fit in the buffer it's read into
+    TooLarge,
+    /// A record is there but doesn't hold what it should
+    Invalid,
+    /// The region needs at least two sectors, each big enough for a copy of every key and a new
+    /// record
+    BadRegion,
+}
+
+impl<E> From<E> for StorageError<E> {
+    fn from(value: E) -> Self {
+        Self::Flash(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    offset: u32,
+    len: u16,
+    seq: u32,
+}
+
+pub struct Storage<F: NorFlash> {
+    flash: F,
+    sectors: u32,
+    head: u32,
+    generation: u32,
+    // Where the next record goes in the head, `None` once nothing more fits or a write failed
+    offset: Option<u32>,
+    seq: u32,
+    latest: [Option<Location>; RECORD_KEYS.len()],
+    buf: [u8; MAX_RECORD_LEN],
+}
+
+type Result<T, F> = core::result::Result<T, StorageError<<F as ErrorType>::Error>>;
+
+impl<F: NorFlash> Storage<F> {
+    /// Find the current records in `flash`, which is the whole reserved region. A region that was
+    /// never used, or holds something else, is formatted.
+    pub fn mount(flash: F) -> Result<Self, F> {
+        let sectors = (flash.capacity() / F::ERASE_SIZE) as u32;
+        let needed =
+            align::<F>(SECTOR_HEADER_LEN) + (RECORD_KEYS.len() + 1) * align::<F>(MAX_RECORD_LEN);
+        if sectors < 2 || needed > F::ERASE_SIZE || F::WRITE_SIZE > MAX_RECORD_LEN {
+            return Err(StorageError::BadRegion);
+        }
+
+        let mut storage = Self {
+            flash,
+            sectors,
+            head: 0,
+            generation: 0,
+            offset: None,
+            seq: 0,
+            latest: [None; RECORD_KEYS.len()],
+            buf: [0; MAX_RECORD_LEN],
+        };
+
+        let mut head = None;
+        for sector in 0..sectors {
+            if let Some(generation) = storage.sector_generation(sector)? {
+                if head.is_none_or(|(_, newest)| generation > newest) {
+                    head = Some((sector, generation));
+                }
+            }
+        }
+
+        match head {
+            None => storage.format()?,
+            Some((head, generation)) => {
+                for sector in 0..sectors {
+                    if storage.sector_generation(sector)?.is_some() {
+                        let end = storage.scan(sector)?;
+                        if sector == head {
+                            storage.offset = end;
+                        }
+                    }
+                }
+
+                storage.head = head;
+                storage.generation = generation;
+            }
+        }
+
+        let next = storage.next(storage.head);
+        if !storage.is_erased(next)? {
+            storage.erase(next)?;
+        }
+
+        Ok(storage)
+    }
+
+    /// The flash underneath, for setting it up after mounting
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Forget every record. A cut part way through can leave some of them, so clearing again is
+    /// the way to finish.
+    pub fn clear(&mut self) -> Result<(), F> {
+        for sector in 0..self.sectors {
+            self.erase(sector)?;
+        }
+
+        self.latest = [None; RECORD_KEYS.len()];
+        self.seq = 0;
+        self.format()
+    }
+
+    // Start over in the first sector, which must be erased
+    fn format(&mut self) -> Result<(), F> {
+        self.erase(0)?;
+        self.write_sector_header(0, 0)?;
+        self.head = 0;
+        self.generation = 0;
+        self.offset = Some(self.start(0) + align::<F>(SECTOR_HEADER_LEN) as u32);
+        Ok(())
+    }
+
+    /// Copy the current data for `key` into `out`, returning its length, or `None` if the key was
+    /// never written
+    pub fn read(&mut self, key: RecordKey, out: &mut [u8]) -> Result<Option<usize>, F> {
+        let Some(location) = self.latest[key as usize] else {
+            return Ok(None);
+        };
+
+        let len = location.len as usize;
+        if out.len() < len {
+            return Err(StorageError::TooLarge);
+        }
+
+        self.flash
+            .read(location.offset + RECORD_HEADER_LEN as u32, &mut out[..len])?;
+        Ok(Some(len))
+    }
+
+    /// Replace the data for `key`. The old data is read back until this returns.
+    pub fn write(&mut self, key: RecordKey, data: &[u8]) -> Result<(), F> {
+        if data.len() > MAX_DATA_LEN {
+            return Err(StorageError::TooLarge);
+        }
+
+        let len = align::<F>(RECORD_HEADER_LEN + data.len());
+        let end = self.start(self.head) + F::ERASE_SIZE as u32;
+        if !matches!(self.offset, Some(offset) if offset + len as u32 <= end) {
+            self.advance()?;
+        }
+
+        let seq = self.seq;
+        self.buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + data.len()].copy_from_slice(data);
+        self.buf[RECORD_HEADER_LEN + data.len()..len].fill(0xFF);
+        self.fill_record_header(key, data.len(), seq);
+
+        let offset = self.offset.take().unwrap();
+        self.flash.write(offset, &self.buf[..len])?;
+
+        self.commit(key, offset, data.len(), seq);
+        self.seq = seq.wrapping_add(1);
+        self.offset = Some(offset + len as u32);
+        Ok(())
+    }
+
+    /// The stored layers, `None` if there are none. `Invalid` if they don't decode, as after a
+    /// firmware update that changed the behaviors.
+    pub fn load_keymap(&mut self) -> Result<Option<[Option<Layer>; NUM_LAYERS]>, F> {
+        let mut buf = [0; KEYMAP_LEN];
+        match self.read(RecordKey::Keymap, &mut buf) {
+            Ok(Some(KEYMAP_LEN)) => decode_keymap(&buf)
+                .map(Some)
+                .map_err(|_| StorageError::Invalid),
+            Ok(Some(_)) | Err(StorageError::TooLarge) => Err(StorageError::Invalid),
+            res => res.map(|_| None),
+        }
+    }
+
+    pub fn save_keymap(&mut self, layers: &[Option<Layer>; NUM_LAYERS]) -> Result<(), F> {
+        self.write(RecordKey::Keymap, &encode_keymap(layers))
+    }
+
+    pub fn load_settings(&mut self) -> Result<Option<Settings>, F> {
+        let mut buf = [0; Settings::LEN];
+        Ok(match self.read(RecordKey::Settings, &mut buf) {
+            Ok(Some(Settings::LEN)) => Some(Settings::from_bytes(buf)),
+            Ok(_) | Err(StorageError::TooLarge) => None,
+            Err(e) => return Err(e),
+        })
+    }
+
+    pub fn save_settings(&mut self, settings: Settings) -> Result<(), F> {
+        self.write(RecordKey::Settings, &settings.to_bytes())
+    }
+
+    // Move the head to the next sector, copying over what's current in the oldest one
+    fn advance(&mut self) -> Result<(), F> {
+        let next = self.next(self.head);
+        let oldest = self.next(next);
+
+        // Left over from a move that failed part way, the head's header was never written
+        if !self.is_erased(next)? {
+            self.erase(next)?;
+        }
+
+        // The copies only count once the header is written
+        let mut copies = [None; RECORD_KEYS.len()];
+        let mut offset = self.start(next) + align::<F>(SECTOR_HEADER_LEN) as u32;
+        for key in RECORD_KEYS {
+            let Some(location) = self.latest[key as usize] else {
+                continue;
+            };
+            if location.offset / F::ERASE_SIZE as u32 != oldest {
+                continue;
+            }
+
+            let data_len = location.len as usize;
+            let len = align::<F>(RECORD_HEADER_LEN + data_len);
+            self.flash.read(location.offset, &mut self.buf[..len])?;
+
+            let seq = self.seq.wrapping_add(key as u32);
+            self.fill_record_header(key, data_len, seq);
+            self.flash.write(offset, &self.buf[..len])?;
+
+            copies[key as usize] = Some((offset, data_len, seq));
+            offset += len as u32;
+        }
+
+        self.write_sector_header(next, self.generation.wrapping_add(1))?;
+        self.head = next;
+        self.generation = self.generation.wrapping_add(1);
+        self.offset = Some(offset);
+        self.seq = self.seq.wrapping_add(RECORD_KEYS.len() as u32);
+        for (key, copy) in RECORD_KEYS.into_iter().zip(copies) {
+            if let Some((offset, len, seq)) = copy {
+                self.commit(key, offset, len, seq);
+            }
+        }
+
+        // With two sectors the oldest is the old head
+        self.erase(oldest)?;
+        Ok(())
+    }
+
+    fn commit(&mut self, key: RecordKey, offset: u32, len: usize, seq: u32) {
+        self.latest[key as usize] = Some(Location {
+            offset,
+            len: len as u16,
+            seq,
+        });
+    }
+
+    // Fill in the header of the record in `buf`, whose data is already there
+    fn fill_record_header(&mut self, key: RecordKey, len: usize, seq: u32) {
+        let buf = &mut self.buf;
+        buf[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        buf[4] = key as u8;
+        buf[5] = 0;
+        buf[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        buf[8..12].copy_from_slice(&seq.to_le_bytes());
+
+        let crc = record_crc(&buf[..RECORD_HEADER_LEN + len]);
+        buf[12..16].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    // Walk the records of a formatted sector, noting every valid one. Returns where the next record
+    // would go, the first unit with nothing but erased flash after it.
+    fn scan(&mut self, sector: u32) -> Result<Option<u32>, F> {
+        let end = self.start(sector) + F::ERASE_SIZE as u32;
+        let mut offset = self.start(sector) + align::<F>(SECTOR_HEADER_LEN) as u32;
+
+        while end - offset >= RECORD_HEADER_LEN as u32 {
+            self.flash
+                .read(offset, &mut self.buf[..RECORD_HEADER_LEN])?;
+
+            if self.buf[..4] == ERASED.to_le_bytes() && self.is_erased_range(offset, end)? {
+                return Ok(Some(offset));
+            }
+
+            match self.check_record(offset, end)? {
+                Some((key, len, seq)) => {
+                    if self.latest[key as usize].is_none_or(|l| seq > l.seq) {
+                        self.commit(key, offset, len, seq);
+                    }
+                    self.seq = self.seq.max(seq.wrapping_add(1));
+                    offset += align::<F>(RECORD_HEADER_LEN + len) as u32;
+                }
+                // Torn or garbage, records are aligned to write units so step over one unit
+                None => offset += F::WRITE_SIZE as u32,
+            }
+        }
+
+        // Too little left for a record, but it may still be the end of the log
+        Ok(self.is_erased_range(offset, end)?.then_some(offset))
+    }
+
+    // The record at `offset`, whose header is already in `buf`, as (key, data length, sequence
+    // number) if it's whole
+    fn check_record(
+        &mut self,
+        offset: u32,
+        end: u32,
+    ) -> Result<Option<(RecordKey, usize, u32)>, F> {
+        if self.buf[..4] != RECORD_MAGIC.to_le_bytes() {
+            return Ok(None);
+        }
+
+        let Some(key) = RECORD_KEYS.get(self.buf[4] as usize).copied() else {
+            return Ok(None);
+        };
+        let len = u16::from_le_bytes([self.buf[6], self.buf[7]]) as usize;
+        let seq = u32::from_le_bytes(self.buf[8..12].try_into().unwrap());
+        let crc = u32::from_le_bytes(self.buf[12..16].try_into().unwrap());
+
+        let record_len = RECORD_HEADER_LEN + len;
+        if record_len > MAX_RECORD_LEN || offset + align::<F>(record_len) as u32 > end {
+            return Ok(None);
+        }
+
+        self.flash.read(offset, &mut self.buf[..record_len])?;
+        Ok((record_crc(&self.buf[..record_len]) == crc).then_some((key, len, seq)))
+    }
+
+    fn sector_generation(&mut self, sector: u32) -> Result<Option<u32>, F> {
+        let mut header = [0; SECTOR_HEADER_LEN];
+        self.flash.read(self.start(sector), &mut header)?;
+
+        let word = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+        Ok((word(0) == SECTOR_MAGIC && word(1) == !word(2)).then_some(word(1)))
+    }
+
+    fn write_sector_header(&mut self, sector: u32, generation: u32) -> Result<(), F> {
+        let len = align::<F>(SECTOR_HEADER_LEN);
+        self.buf[..len].fill(0xFF);
+        self.buf[0..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        self.buf[4..8].copy_from_slice(&generation.to_le_bytes());
+        self.buf[8..12].copy_from_slice(&(!generation).to_le_bytes());
+
+        self.flash.write(self.start(sector), &self.buf[..len])?;
+        Ok(())
+    }
+
+    fn is_erased(&mut self, sector: u32) -> Result<bool, F> {
+        let start = self.start(sector);
+        self.is_erased_range(start, start + F::ERASE_SIZE as u32)
+    }
+
+    fn is_erased_range(&mut self, mut from: u32, to: u32) -> Result<bool, F> {
+        let mut chunk = [0; 64];
+
+        while from < to {
+            let len = ((to - from) as usize).min(chunk.len());
+            self.flash.read(from, &mut chunk[..len])?;
+            if chunk[..len].iter().any(|b| *b != 0xFF) {
+                return Ok(false);
+            }
+            from += len as u32;
+        }
+
+        Ok(true)
+    }
+
+    fn erase(&mut self, sector: u32) -> Result<(), F> {
+        let start = self.start(sector);
+        self.flash.erase(start, start + F::ERASE_SIZE as u32)?;
+        Ok(())
+    }
+
+    fn start(&self, sector: u32) -> u32 {
+        sector * F::ERASE_SIZE as u32
+    }
+
+    fn next(&self, sector: u32) -> u32 {
+        (sector + 1) % self.sectors
+    }
+}
+
+fn align<F: NorFlash>(len: usize) -> usize {
+    len.div_ceil(F::WRITE_SIZE) * F::WRITE_SIZE
+}
+
+fn record_crc(record: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&record[4..12]);
+    crc.update(&record[RECORD_HEADER_LEN..]);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{
+        ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+    };
+
+    use crate::keymap::default_config;
+    use crate::storage::{RecordKey, Settings, Storage, StorageError, MAX_DATA_LEN};
+
+    const SECTOR: usize = 4096;
+    const SECTORS: usize = 3;
+    const KEYMAP_LEN: usize = 962;
+    const SETTINGS_LEN: usize = 2;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct PowerCut;
+
+    impl NorFlashError for PowerCut {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    /// NOR flash in RAM. Programming can only clear bits, and once the operations allowed run out
+    /// the power is cut half way through the next one, and everything after it fails.
+    struct RamFlash {
+        data: [u8; SECTOR * SECTORS],
+        ops_left: Option<usize>,
+        erases: [u32; SECTORS],
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                data: [0xFF; SECTOR * SECTORS],
+                ops_left: None,
+                erases: [0; SECTORS],
+            }
+        }
+
+        fn power_on(&mut self) {
+            self.ops_left = None;
+        }
+
+        fn cut_after(&mut self, ops: usize) {
+            self.ops_left = Some(ops);
+        }
+
+        fn cut(&mut self) -> bool {
+            match &mut self.ops_left {
+                Some(0) => true,
+                Some(n) => {
+                    *n -= 1;
+                    false
+                }
+                None => false,
+            }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = PowerCut;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerCut> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerCut> {
+            let (from, to) = (from as usize, to as usize);
+            assert!(from % SECTOR == 0 && to % SECTOR == 0);
+
+            if self.cut() {
+                self.data[from..from + (to - from) / 2].fill(0xFF);
+                return Err(PowerCut);
+            }
+
+            self.data[from..to].fill(0xFF);
+            for sector in from / SECTOR..to / SECTOR {
+                self.erases[sector] += 1;
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerCut> {
+            let offset = offset as usize;
+            assert!(offset.is_multiple_of(Self::WRITE_SIZE));
+            assert!(bytes.len().is_multiple_of(Self::WRITE_SIZE));
+
+            let (len, res) = match self.cut() {
+                true => (bytes.len() / 2, Err(PowerCut)),
+                false => (bytes.len(), Ok(())),
+            };
+            for (cell, byte) in self.data[offset..offset + len].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            res
+        }
+    }
+
+    fn keymap(version: u8) -> [u8; KEYMAP_LEN] {
+        core::array::from_fn(|i| version ^ i as u8)
+    }
+
+    fn settings(version: u8) -> [u8; SETTINGS_LEN] {
+        [version, !version]
+    }
+
+    fn read_keymap<F: NorFlash>(storage: &mut Storage<F>) -> Option<[u8; KEYMAP_LEN]> {
+        let mut buf = [0; KEYMAP_LEN];
+        let len = storage.read(RecordKey::Keymap, &mut buf).ok()??;
+        assert_eq!(len, KEYMAP_LEN);
+        Some(buf)
+    }
+
+    fn read_settings<F: NorFlash>(storage: &mut Storage<F>) -> Option<[u8; SETTINGS_LEN]> {
+        let mut buf = [0; SETTINGS_LEN];
+        let len = storage.read(RecordKey::Settings, &mut buf).ok()??;
+        assert_eq!(len, SETTINGS_LEN);
+        Some(buf)
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        assert_eq!(read_keymap(&mut storage), None);
+        assert_eq!(read_settings(&mut storage), None);
+
+        // Flash holding something else is formatted
+        flash.data.fill(0);
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        assert_eq!(read_keymap(&mut storage), None);
+
+        storage.write(RecordKey::Keymap, &keymap(1)).unwrap();
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        assert_eq!(read_keymap(&mut storage), Some(keymap(1)));
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        storage.write(RecordKey::Keymap, &keymap(1)).unwrap();
+        storage.write(RecordKey::Settings, &settings(1)).unwrap();
+        storage.write(RecordKey::Keymap, &keymap(2)).unwrap();
+
+        assert_eq!(read_keymap(&mut storage), Some(keymap(2)));
+        assert_eq!(read_settings(&mut storage), Some(settings(1)));
+
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        assert_eq!(read_keymap(&mut storage), Some(keymap(2)));
+        assert_eq!(read_settings(&mut storage), Some(settings(1)));
+
+        let mut small = [0; 1];
+        assert_eq!(
+            storage.read(RecordKey::Settings, &mut small),
+            Err(StorageError::TooLarge)
+        );
+        assert_eq!(
+            storage.write(RecordKey::Keymap, &[0; MAX_DATA_LEN + 1]),
+            Err(StorageError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn test_keymap_settings() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        assert_eq!(storage.load_keymap(), Ok(None));
+        assert_eq!(storage.load_settings(), Ok(None));
+
+        let mut layers = default_config().layers;
+        layers[1] = None;
+        storage.save_keymap(&layers).unwrap();
+        let settings = Settings {
+            locked_layers: 0b100,
+            keymap_crc: 0x1234_5678,
+        };
+        storage.save_settings(settings).unwrap();
+
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        assert_eq!(storage.load_keymap(), Ok(Some(layers)));
+        assert_eq!(storage.load_settings(), Ok(Some(settings)));
+
+        // Something that isn't a keymap
+        storage.write(RecordKey::Keymap, &[0xFF; 8]).unwrap();
+        assert_eq!(storage.load_keymap(), Err(StorageError::Invalid));
+
+        storage.clear().unwrap();
+        assert_eq!(storage.load_keymap(), Ok(None));
+        assert_eq!(storage.load_settings(), Ok(None));
+
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        assert_eq!(storage.load_keymap(), Ok(None));
+        storage.save_settings(settings).unwrap();
+        assert_eq!(storage.load_settings(), Ok(Some(settings)));
+    }
+
+    #[test]
+    fn test_wear_leveling() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        storage.write(RecordKey::Settings, &settings(7)).unwrap();
+
+        for version in 0..100 {
+            storage.write(RecordKey::Keymap, &keymap(version)).unwrap();
+        }
+
+        // Settings written once are carried along as sectors get reused
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        assert_eq!(read_keymap(&mut storage), Some(keymap(99)));
+        assert_eq!(read_settings(&mut storage), Some(settings(7)));
+
+        let erases = flash.erases;
+        let (min, max) = (erases.iter().min().unwrap(), erases.iter().max().unwrap());
+        assert!(*min > 5, "{:?}", erases);
+        assert!(max - min <= 1, "{:?}", erases);
+    }
+
+    #[test]
+    fn test_bad_region() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::mount(&mut flash).unwrap();
+        storage.write(RecordKey::Keymap, &keymap(1)).unwrap();
+
+        // A single sector, records can't be moved anywhere
+        let mut small = Small(RamFlash::new());
+        assert!(matches!(
+            Storage::mount(&mut small),
+            Err(StorageError::BadRegion)
+        ));
+    }
+
+    struct Small(RamFlash);
+
+    impl ErrorType for Small {
+        type Error = PowerCut;
+    }
+
+    impl ReadNorFlash for Small {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerCut> {
+            self.0.read(offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            SECTOR
+        }
+    }
+
+    impl NorFlash for Small {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerCut> {
+            self.0.erase(from, to)
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerCut> {
+            self.0.write(offset, bytes)
+        }
+    }
+
+    const WRITES: u8 = 24;
+
+    // Write keymaps and settings in turn until the power is cut, returning the versions that are
+    // certainly written and the ones that may be, as (keymap, settings). `None` if it never was.
+    fn write_until_cut(flash: &mut RamFlash) -> Option<([u8; 2], [Option<u8>; 2])> {
+        let Ok(mut storage) = Storage::mount(flash) else {
+            return Some(([0; 2], [None; 2]));
+        };
+        let mut written = [0; 2];
+
+        for version in 1..=WRITES {
+            let (key, i) = match version % 3 {
+                0 => (RecordKey::Settings, 1),
+                _ => (RecordKey::Keymap, 0),
+            };
+            let res = match key {
+                RecordKey::Keymap => storage.write(key, &keymap(version)),
+                RecordKey::Settings => storage.write(key, &settings(version)),
+            };
+
+            if res.is_err() {
+                let mut maybe = [None; 2];
+                maybe[i] = Some(version);
+                return Some((written, maybe));
+            }
+            written[i] = version;
+        }
+
+        None
+    }
+
+    /// Cut the power at every point of a run of writes, and again while mounting after it. Each
+    /// key must read back as either the last value written or the one being written at the cut.
+    #[test]
+    fn test_power_cuts() {
+        for cut in 0.. {
+            let mut flash = RamFlash::new();
+            let mut storage = Storage::mount(&mut flash).unwrap();
+            storage.write(RecordKey::Keymap, &keymap(0)).unwrap();
+            storage.write(RecordKey::Settings, &settings(0)).unwrap();
+
+            flash.cut_after(cut);
+            let Some((written, maybe)) = write_until_cut(&mut flash) else {
+                // The writes all went through, so every cut point has been tried
+                assert!(cut > WRITES as usize);
+                break;
+            };
+
+            for remount_cut in 0..4 {
+                flash.cut_after(remount_cut);
+                if Storage::mount(&mut flash).is_ok() {
+                    break;
+                }
+            }
+            flash.power_on();
+
+            let mut storage = Storage::mount(&mut flash).unwrap();
+            let keymaps = [Some(keymap(written[0])), maybe[0].map(keymap)];
+            let settings_versions = [Some(settings(written[1])), maybe[1].map(settings)];
+            assert!(keymaps.contains(&read_keymap(&mut storage)), "cut {}", cut);
+            assert!(
+                settings_versions.contains(&read_settings(&mut storage)),
+                "cut {}",
+                cut
+            );
+
+            // Still usable afterwards
+            storage.write(RecordKey::Keymap, &keymap(0xAA)).unwrap();
+            let mut storage = Storage::mount(&mut flash).unwrap();
+            assert_eq!(read_keymap(&mut storage), Some(keymap(0xAA)));
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! HID system control device (sleep, wake, power down), which usbd-human-interface-device
+//! doesn't provide
+
+use defmt::unwrap;
+use fugit::ExtU32;
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usbd_human_interface_device::interface::{
+    InBytes8, Interface, InterfaceBuilder, InterfaceConfig, OutNone, ReportSingle, UsbAllocatable,
+};
+use usbd_human_interface_device::usb_class::prelude::*;
+use usbd_human_interface_device::UsbHidError;
+
+/// One 16 bit usage from the System Control collection of the Generic Desktop page. Zero is
+/// outside the logical range, so it means nothing is pressed.
+#[rustfmt::skip]
+pub const SYSTEM_CONTROL_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x80,       // Usage (System Control)
+    0xA1, 0x01,       // Collection (Application)
+    0x19, 0x01,       //   Usage Minimum (0x01)
+    0x2A, 0xB7, 0x00, //   Usage Maximum (0xB7)
+    0x15, 0x01,       //   Logical Minimum (0x01)
+    0x26, 0xB7, 0x00, //   Logical Maximum (0xB7)
+    0x95, 0x01,       //   Report Count (1)
+    0x75, 0x10,       //   Report Size (16)
+    0x81, 0x00,       //   Input (Data, Array, Absolute)
+    0xC0,             // End Collection
+];
+
+pub const SYSTEM_POWER_DOWN: u16 = 0x81;
+pub const SYSTEM_SLEEP: u16 = 0x82;
+pub const SYSTEM_WAKE_UP: u16 = 0x83;
+
+pub struct SystemControl<'a, B: UsbBus> {
+    interface: Interface<'a, B, InBytes8, OutNone, ReportSingle>,
+}
+
+impl<B: UsbBus> SystemControl<'_, B> {
+    /// Send a usage, or 0 to release
+    pub fn write_report(&mut self, usage: u16) -> Result<(), UsbHidError> {
+        self.interface
+            .write_report(&usage.to_le_bytes())
+            .map(|_| ())
+            .map_err(UsbHidError::from)
+    }
+}
+
+impl<'a, B: UsbBus> DeviceClass<'a> for SystemControl<'a, B> {
+    type I = Interface<'a, B, InBytes8, OutNone, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        Ok(())
+    }
+}
+
+pub struct SystemControlConfig<'a> {
+    interface: InterfaceConfig<'a, InBytes8, OutNone, ReportSingle>,
+}
+
+impl Default for SystemControlConfig<'_> {
+    fn default() -> Self {
+        Self {
+            interface: unwrap!(unwrap!(InterfaceBuilder::new(SYSTEM_CONTROL_DESCRIPTOR))
+                .description("System Control")
+                .in_endpoint(50.millis()))
+            .without_out_endpoint()
+            .build(),
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbAllocatable<'a, B> for SystemControlConfig<'a> {
+    type Allocated = SystemControl<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        SystemControl {
+            interface: Interface::new(usb_alloc, self.interface),
+        }
+    }
+}
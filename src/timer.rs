@@ -0,0 +1,12 @@
+//! The RP2040's timer as the firmware's clock
+
+use rp2040_hal::Timer;
+
+use crate::clock::{Clock, Instant};
+
+impl Clock for Timer {
+    /// The timer counts µs from boot in 64 bits, this keeps the ms and lets them wrap
+    fn now(&self) -> Instant {
+        Instant::from_millis((self.get_counter().ticks() / 1000) as u32)
+    }
+}
@@ -0,0 +1,749 @@
+//! USB composition, assembling the interfaces enabled in the options into one device
+
+use config::no_std::UsbOptions;
+use frunk::HList;
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::class::UsbClass;
+use usb_device::device::UsbDevice;
+use usb_device::UsbError;
+use usbd_human_interface_device::device::consumer::{ConsumerControl, ConsumerControlConfig};
+use usbd_human_interface_device::device::mouse::{WheelMouse, WheelMouseConfig};
+use usbd_human_interface_device::prelude::{UsbHidClass, UsbHidClassBuilder};
+use usbd_human_interface_device::usb_class::prelude::DeviceClass;
+use usbd_human_interface_device::UsbHidError;
+use usbd_serial::SerialPort;
+
+use crate::engine::Report;
+use crate::hid::{consumer_report, keyboard_report, system_usage, wheel_mouse_report};
+use crate::keyboard::{Keyboard, KeyboardConfig};
+use crate::mouse::MouseReport;
+use crate::raw_hid::{RawHid, RawHidConfig};
+use crate::system_control::{SystemControl, SystemControlConfig};
+
+/// Endpoints available in each direction besides the control endpoint, on the RP2040
+pub const MAX_ENDPOINTS: u8 = 15;
+
+const MAX_KINDS: usize = 6;
+
+/// The interfaces this firmware can expose, in the order they are allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterfaceKind {
+    Keyboard,
+    Mouse,
+    Consumer,
+    System,
+    RawHid,
+    Serial,
+}
+
+const ALL_KINDS: [InterfaceKind; MAX_KINDS] = [
+    InterfaceKind::Keyboard,
+    InterfaceKind::Mouse,
+    InterfaceKind::Consumer,
+    InterfaceKind::System,
+    InterfaceKind::RawHid,
+    InterfaceKind::Serial,
+];
+
+impl InterfaceKind {
+    /// Number of USB interfaces used, CDC-ACM needs a communication and a data interface
+    pub fn interfaces(self) -> u8 {
+        match self {
+            Self::Serial => 2,
+            _ => 1,
+        }
+    }
+
+    /// Endpoints used as (in, out)
+    pub const fn endpoints(self) -> (u8, u8) {
+        match self {
+            // The keyboard receives LED reports
+            Self::Keyboard => (1, 1),
+            Self::Mouse | Self::Consumer | Self::System => (1, 0),
+            Self::RawHid => (1, 1),
+            // Notification endpoint plus a bulk endpoint each way
+            Self::Serial => (2, 1),
+        }
+    }
+}
+
+// Endpoints used as (in, out) with every interface enabled
+const fn all_endpoints() -> (u8, u8) {
+    let (mut ins, mut outs) = (0, 0);
+    let mut i = 0;
+    while i < MAX_KINDS {
+        let (kind_ins, kind_outs) = ALL_KINDS[i].endpoints();
+        ins += kind_ins;
+        outs += kind_outs;
+        i += 1;
+    }
+    (ins, outs)
+}
+
+/// The interfaces to allocate, which decides their interface numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Plan {
+    kinds: [Option<InterfaceKind>; MAX_KINDS],
+}
+
+impl Plan {
+    pub fn new(options: &UsbOptions) -> Self {
+        // Every interface at once fits in the endpoints there are, so whatever is enabled does
+        const {
+            let (ins, outs) = all_endpoints();
+            assert!(ins <= MAX_ENDPOINTS && outs <= MAX_ENDPOINTS);
+        };
+
+        let enabled = [
+            (InterfaceKind::Keyboard, true),
+            (InterfaceKind::Mouse, options.mouse),
+            (InterfaceKind::Consumer, options.consumer),
+            (InterfaceKind::System, options.system),
+            (InterfaceKind::RawHid, options.raw_hid),
+            (InterfaceKind::Serial, options.serial),
+        ];
+
+        let mut kinds = [None; MAX_KINDS];
+        for (slot, kind) in kinds
+            .iter_mut()
+            .zip(enabled.into_iter().filter(|(_, on)| *on).map(|(k, _)| k))
+        {
+            *slot = Some(kind);
+        }
+
+        Self { kinds }
+    }
+
+    pub fn kinds(&self) -> impl Iterator<Item = InterfaceKind> + '_ {
+        self.kinds.iter().flatten().copied()
+    }
+
+    pub fn contains(&self, kind: InterfaceKind) -> bool {
+        self.kinds().any(|k| k == kind)
+    }
+
+    /// Number of the first interface used by `kind`, if it's enabled
+    pub fn interface_number(&self, kind: InterfaceKind) -> Option<u8> {
+        let mut number = 0;
+
+        for k in self.kinds() {
+            if k == kind {
+                return Some(number);
+            }
+            number += k.interfaces();
+        }
+
+        None
+    }
+
+    pub fn interface_count(&self) -> u8 {
+        self.kinds().map(InterfaceKind::interfaces).sum()
+    }
+
+    /// Endpoints used as (in, out), not counting the control endpoint
+    pub fn endpoints(&self) -> (u8, u8) {
+        self.kinds()
+            .map(InterfaceKind::endpoints)
+            .fold((0, 0), |(i, o), (ki, ko)| (i + ki, o + ko))
+    }
+
+    /// CDC-ACM spans two interfaces, so it needs an interface association descriptor, and the
+    /// device class has to say so
+    pub fn needs_iad(&self) -> bool {
+        self.contains(InterfaceKind::Serial)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorError {
+    Truncated,
+    LongItem,
+    UnbalancedCollection,
+    MissingUsagePage,
+    MissingReportSize,
+    MainItemOutsideCollection,
+}
+
+/// Walk a HID report descriptor and check it is well formed, returning the length in bytes of
+/// its (input, output) reports
+pub fn report_lengths(descriptor: &[u8]) -> Result<(usize, usize), DescriptorError> {
+    let mut i = 0;
+    let mut depth = 0;
+    let mut usage_page = false;
+    let mut report_size = None;
+    let mut report_count = None;
+    let mut input_bits = 0;
+    let mut output_bits = 0;
+
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        if prefix == 0xFE {
+            return Err(DescriptorError::LongItem);
+        }
+
+        let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+        let data = descriptor
+            .get(i + 1..i + 1 + size)
+            .ok_or(DescriptorError::Truncated)?
+            .iter()
+            .rev()
+            .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+
+        match ((prefix >> 2) & 0x03, prefix >> 4) {
+            // Main items
+            (0, tag @ (0x8 | 0x9)) => {
+                if depth == 0 {
+                    return Err(DescriptorError::MainItemOutsideCollection);
+                }
+
+                let bits = match (report_size, report_count) {
+                    (Some(size), Some(count)) => size * count,
+                    _ => return Err(DescriptorError::MissingReportSize),
+                };
+
+                if tag == 0x8 {
+                    input_bits += bits;
+                } else {
+                    output_bits += bits;
+                }
+            }
+            (0, 0xA) => {
+                if !usage_page {
+                    return Err(DescriptorError::MissingUsagePage);
+                }
+                depth += 1;
+            }
+            (0, 0xC) => {
+                if depth == 0 {
+                    return Err(DescriptorError::UnbalancedCollection);
+                }
+                depth -= 1;
+            }
+            // Global items
+            (1, 0x0) => usage_page = true,
+            (1, 0x7) => report_size = Some(data as usize),
+            (1, 0x9) => report_count = Some(data as usize),
+            // Local usage items, 4 byte usages carry their own page
+            (2, 0x0..=0x2) if size != 4 && !usage_page => {
+                return Err(DescriptorError::MissingUsagePage)
+            }
+            _ => {}
+        }
+
+        i += 1 + size;
+    }
+
+    if depth != 0 {
+        return Err(DescriptorError::UnbalancedCollection);
+    }
+
+    Ok((input_bits.div_ceil(8), output_bits.div_ceil(8)))
+}
+
+/// Stands in for disabled interfaces when polling
+struct Unused;
+
+impl<B: UsbBus> UsbClass<B> for Unused {}
+
+fn or_unused<'c, B: UsbBus, C: UsbClass<B>>(
+    class: &'c mut Option<C>,
+    unused: &'c mut Unused,
+) -> &'c mut dyn UsbClass<B> {
+    match class {
+        Some(class) => class,
+        None => unused,
+    }
+}
+
+/// `WouldBlock` means the last report hasn't been collected by the host yet, which is retried later
+fn would_block_ok<T>(res: Result<T, UsbHidError>) -> Result<(), UsbHidError> {
+    is_sent(res).map(|_| ())
+}
+
+/// Whether a report went out, false if the host hasn't collected the last one yet
+fn is_sent<T>(res: Result<T, UsbHidError>) -> Result<bool, UsbHidError> {
+    match res {
+        Ok(_) | Err(UsbHidError::Duplicate) => Ok(true),
+        Err(UsbHidError::WouldBlock) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub struct Composite<'a, B: UsbBus> {
+    pub keyboard: UsbHidClass<'a, B, HList!(Keyboard<'a, B>)>,
+    pub mouse: Option<UsbHidClass<'a, B, HList!(WheelMouse<'a, B>)>>,
+    pub consumer: Option<UsbHidClass<'a, B, HList!(ConsumerControl<'a, B>)>>,
+    pub system: Option<UsbHidClass<'a, B, HList!(SystemControl<'a, B>)>>,
+    pub raw_hid: Option<UsbHidClass<'a, B, HList!(RawHid<'a, B>)>>,
+    pub serial: Option<SerialPort<'a, B>>,
+    // What the host has last seen on each interface
+    last: Report,
+}
+
+impl<'a, B: UsbBus> Composite<'a, B> {
+    pub fn new(usb_alloc: &'a UsbBusAllocator<B>, plan: &Plan) -> Self {
+        // Fields are allocated in the order they're written, which has to match the plan
+        Self {
+            keyboard: UsbHidClassBuilder::new()
+                .add_device(KeyboardConfig::default())
+                .build(usb_alloc),
+            mouse: plan.contains(InterfaceKind::Mouse).then(|| {
+                UsbHidClassBuilder::new()
+                    .add_device(WheelMouseConfig::default())
+                    .build(usb_alloc)
+            }),
+            consumer: plan.contains(InterfaceKind::Consumer).then(|| {
+                UsbHidClassBuilder::new()
+                    .add_device(ConsumerControlConfig::default())
+                    .build(usb_alloc)
+            }),
+            system: plan.contains(InterfaceKind::System).then(|| {
+                UsbHidClassBuilder::new()
+                    .add_device(SystemControlConfig::default())
+                    .build(usb_alloc)
+            }),
+            raw_hid: plan.contains(InterfaceKind::RawHid).then(|| {
+                UsbHidClassBuilder::new()
+                    .add_device(RawHidConfig::default())
+                    .build(usb_alloc)
+            }),
+            serial: plan
+                .contains(InterfaceKind::Serial)
+                .then(|| SerialPort::new(usb_alloc)),
+            last: Report::default(),
+        }
+    }
+
+    /// Poll every enabled interface, returns true if any of them has data from the host
+    pub fn poll(&mut self, usb_dev: &mut UsbDevice<'a, B>) -> bool {
+        let [u0, u1, u2, u3, u4] = &mut [Unused, Unused, Unused, Unused, Unused];
+
+        usb_dev.poll(&mut [
+            &mut self.keyboard,
+            or_unused(&mut self.mouse, u0),
+            or_unused(&mut self.consumer, u1),
+            or_unused(&mut self.system, u2),
+            or_unused(&mut self.raw_hid, u3),
+            or_unused(&mut self.serial, u4),
+        ])
+    }
+
+    /// Run the 1ms housekeeping of the HID interfaces
+    pub fn tick(&mut self) -> Result<(), UsbHidError> {
+        would_block_ok(self.keyboard.tick())?;
+
+        if let Some(mouse) = &mut self.mouse {
+            would_block_ok(mouse.tick())?;
+        }
+        if let Some(consumer) = &mut self.consumer {
+            would_block_ok(consumer.tick())?;
+        }
+        if let Some(system) = &mut self.system {
+            would_block_ok(system.tick())?;
+        }
+        if let Some(raw_hid) = &mut self.raw_hid {
+            would_block_ok(raw_hid.tick())?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a report from the engine, each interface only gets written when its part changed.
+    /// Returns false if an interface was busy, the report then has to be written again. Movement
+    /// the mouse accepted is taken out of `report`, so writing it again doesn't move twice.
+    pub fn write_report(&mut self, report: &mut Report) -> Result<bool, UsbHidError> {
+        let mut sent = is_sent(
+            self.keyboard
+                .device()
+                .write_report(&keyboard_report(report)),
+        )?;
+
+        if let Some(mouse) = &mut self.mouse {
+            // Movement is relative, so keep sending while moving even if nothing changed
+            if report.mouse != self.last.mouse || report.mouse.is_moving() {
+                match mouse
+                    .device()
+                    .write_report(&wheel_mouse_report(&report.mouse))
+                {
+                    Ok(_) => {
+                        report.mouse = MouseReport {
+                            buttons: report.mouse.buttons,
+                            ..MouseReport::default()
+                        };
+                        self.last.mouse = report.mouse;
+                    }
+                    res => sent &= is_sent(res)?,
+                }
+            }
+        }
+
+        if let Some(consumer) = &mut self.consumer {
+            if report.consumer != self.last.consumer {
+                match consumer
+                    .device()
+                    .interface()
+                    .write_report(&consumer_report(report))
+                {
+                    Ok(_) => self.last.consumer = report.consumer,
+                    Err(UsbError::WouldBlock) => sent = false,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        if let Some(system) = &mut self.system {
+            if report.system != self.last.system {
+                match system.device().write_report(system_usage(report.system)) {
+                    Ok(_) => self.last.system = report.system,
+                    res => sent &= is_sent(res)?,
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    use config::no_std::{Mods, UsbOptions};
+    use usb_device::bus::{PollResult, UsbBus, UsbBusAllocator};
+    use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+    use usb_device::endpoint::{EndpointAddress, EndpointType};
+    use usb_device::{UsbDirection, UsbError};
+
+    use crate::engine::Report;
+    use crate::mouse::MouseReport;
+    use crate::raw_hid::{RAW_HID_DESCRIPTOR, RAW_REPORT_LEN};
+    use crate::system_control::SYSTEM_CONTROL_DESCRIPTOR;
+    use crate::usb::{report_lengths, Composite, DescriptorError, InterfaceKind, Plan};
+
+    // The HID class logs over defmt, which has nowhere to go on the host
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!()
+    }
+
+    defmt::timestamp!("");
+
+    const CONFIGURATION: u8 = 2;
+    const INTERFACE: u8 = 4;
+    const ENDPOINT: u8 = 5;
+    const IAD: u8 = 11;
+    const HID: u8 = 0x21;
+
+    /// What passes over the mock bus, shared with the test playing the host
+    #[derive(Default)]
+    struct Wire {
+        // Endpoints handed out so far, a bit per index for each direction
+        allocated: [u16; 2],
+        setup: Option<[u8; 8]>,
+        in_complete: u16,
+        // Everything sent on the control endpoint since the last setup packet
+        control_in: Vec<u8>,
+        // IN endpoints that won't take a report, a bit per index
+        busy: u16,
+        // Reports written to the other IN endpoints, with the endpoint index
+        reports: Vec<(usize, Vec<u8>)>,
+    }
+
+    #[derive(Default)]
+    struct MockBus {
+        wire: Arc<Mutex<Wire>>,
+    }
+
+    impl UsbBus for MockBus {
+        fn alloc_ep(
+            &mut self,
+            ep_dir: UsbDirection,
+            ep_addr: Option<EndpointAddress>,
+            ep_type: EndpointType,
+            _max_packet_size: u16,
+            _interval: u8,
+        ) -> usb_device::Result<EndpointAddress> {
+            let mut wire = self.wire.lock().unwrap();
+            let allocated = &mut wire.allocated[(ep_dir == UsbDirection::In) as usize];
+            let index = match (ep_type, ep_addr) {
+                (EndpointType::Control, _) => 0,
+                (_, Some(addr)) => addr.index(),
+                _ => (1..16)
+                    .find(|i| *allocated & (1 << i) == 0)
+                    .ok_or(UsbError::EndpointOverflow)?,
+            };
+            *allocated |= 1 << index;
+            Ok(EndpointAddress::from_parts(index, ep_dir))
+        }
+
+        fn enable(&mut self) {}
+
+        fn reset(&self) {}
+
+        fn set_device_address(&self, _addr: u8) {}
+
+        fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+            let mut wire = self.wire.lock().unwrap();
+            match ep_addr.index() {
+                0 => wire.control_in.extend_from_slice(buf),
+                index if wire.busy & (1 << index) != 0 => return Err(UsbError::WouldBlock),
+                index => wire.reports.push((index, buf.to_vec())),
+            }
+            wire.in_complete |= 1 << ep_addr.index();
+            Ok(buf.len())
+        }
+
+        fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+            let mut wire = self.wire.lock().unwrap();
+            match wire.setup.take() {
+                Some(setup) if ep_addr.index() == 0 => {
+                    buf[..8].copy_from_slice(&setup);
+                    Ok(8)
+                }
+                _ => Err(UsbError::WouldBlock),
+            }
+        }
+
+        fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+        fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+            false
+        }
+
+        fn suspend(&self) {}
+
+        fn resume(&self) {}
+
+        fn poll(&self) -> PollResult {
+            let mut wire = self.wire.lock().unwrap();
+            let ep_setup = wire.setup.is_some() as u16;
+            let ep_in_complete = core::mem::take(&mut wire.in_complete);
+
+            if ep_setup == 0 && ep_in_complete == 0 {
+                PollResult::None
+            } else {
+                PollResult::Data {
+                    ep_out: 0,
+                    ep_in_complete,
+                    ep_setup,
+                }
+            }
+        }
+    }
+
+    fn options(bits: u8) -> UsbOptions {
+        UsbOptions {
+            mouse: bits & 0x01 != 0,
+            consumer: bits & 0x02 != 0,
+            system: bits & 0x04 != 0,
+            raw_hid: bits & 0x08 != 0,
+            serial: bits & 0x10 != 0,
+        }
+    }
+
+    /// Ask for the configuration descriptor the way a host does, over the control endpoint
+    fn configuration_descriptor<'a>(
+        wire: &Mutex<Wire>,
+        usb_dev: &mut UsbDevice<'a, MockBus>,
+        usb: &mut Composite<'a, MockBus>,
+    ) -> Vec<u8> {
+        {
+            let mut wire = wire.lock().unwrap();
+            wire.control_in.clear();
+            // GET_DESCRIPTOR for configuration 0, taking as much as the device has
+            wire.setup = Some([0x80, 0x06, 0x00, CONFIGURATION, 0x00, 0x00, 0xFF, 0x03]);
+        }
+
+        // Each poll sends one packet of it
+        for _ in 0..100 {
+            usb.poll(usb_dev);
+        }
+
+        wire.lock().unwrap().control_in.clone()
+    }
+
+    // Interface classes each kind shows up as, in order
+    fn classes(kind: InterfaceKind) -> &'static [u8] {
+        match kind {
+            InterfaceKind::Serial => &[0x02, 0x0A],
+            _ => &[0x03],
+        }
+    }
+
+    #[test]
+    fn test_configuration_descriptors() {
+        for bits in 0..32 {
+            let options = options(bits);
+            let plan = Plan::new(&options);
+            let bus = MockBus::default();
+            let wire = bus.wire.clone();
+            let alloc = UsbBusAllocator::new(bus);
+            let mut usb = Composite::new(&alloc, &plan);
+            // Built the way the firmware builds it
+            let builder = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001));
+            let mut usb_dev = if plan.needs_iad() {
+                builder.composite_with_iads().build()
+            } else {
+                builder.build()
+            };
+
+            let descriptor = configuration_descriptor(&wire, &mut usb_dev, &mut usb);
+            assert!(descriptor.len() >= 9, "{:?}: {:?}", options, descriptor);
+            assert_eq!(descriptor[1], CONFIGURATION);
+            assert_eq!(
+                u16::from_le_bytes([descriptor[2], descriptor[3]]) as usize,
+                descriptor.len()
+            );
+            assert_eq!(descriptor[4], plan.interface_count());
+
+            // Interfaces as (number, class, endpoint addresses), and any association
+            let mut interfaces: Vec<(u8, u8, Vec<u8>)> = Vec::new();
+            let mut iads = Vec::new();
+            let mut at = 0;
+            while at < descriptor.len() {
+                let len = descriptor[at] as usize;
+                let item = &descriptor[at..at + len];
+                match item[1] {
+                    CONFIGURATION => assert_eq!(len, 9),
+                    INTERFACE => {
+                        assert_eq!(len, 9);
+                        assert_eq!(item[3], 0, "alternate setting");
+                        interfaces.push((item[2], item[5], Vec::new()));
+                    }
+                    ENDPOINT => {
+                        assert_eq!(len, 7);
+                        assert!(u16::from_le_bytes([item[4], item[5]]) > 0);
+                        interfaces.last_mut().unwrap().2.push(item[2]);
+                    }
+                    IAD => {
+                        assert_eq!(len, 8);
+                        iads.push((item[2], item[3]));
+                    }
+                    HID => assert_eq!(len, 9),
+                    // CDC functional descriptors
+                    0x24 => {}
+                    other => panic!("{:?}: unexpected descriptor type {}", options, other),
+                }
+                at += len;
+            }
+            assert_eq!(at, descriptor.len());
+
+            // Numbered from 0 in the order of the plan, with the endpoints each kind needs
+            let mut expected = interfaces.iter();
+            let mut addresses = Vec::new();
+            for kind in plan.kinds() {
+                let first = plan.interface_number(kind).unwrap();
+                let (mut ins, mut outs) = (0, 0);
+                for (i, class) in classes(kind).iter().enumerate() {
+                    let (number, found, endpoints) = expected.next().unwrap();
+                    assert_eq!((*number, *found), (first + i as u8, *class), "{:?}", kind);
+                    for &address in endpoints {
+                        if address & 0x80 != 0 {
+                            ins += 1;
+                        } else {
+                            outs += 1;
+                        }
+                        assert!(!addresses.contains(&address), "{:#x} used twice", address);
+                        addresses.push(address);
+                    }
+                }
+                assert_eq!((ins, outs), kind.endpoints(), "{:?}", kind);
+            }
+            assert!(expected.next().is_none());
+            assert_eq!(
+                (addresses.iter().filter(|a| *a & 0x80 != 0).count() as u8),
+                plan.endpoints().0
+            );
+
+            // CDC-ACM's two interfaces are tied together, and nothing else is
+            let serial = plan.interface_number(InterfaceKind::Serial);
+            assert_eq!(
+                iads,
+                serial
+                    .map(|first| (first, 2))
+                    .into_iter()
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_retry_keeps_movement() {
+        // Just the keyboard and the mouse
+        let plan = Plan::new(&options(0x01));
+        let bus = MockBus::default();
+        let wire = bus.wire.clone();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut usb = Composite::new(&alloc, &plan);
+        let _usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+        // The first report also fills the keyboard's GET_REPORT buffer, after which a busy
+        // endpoint blocks it
+        let mut report = Report::default();
+        report.mods = Mods::SHIFT;
+        assert!(usb.write_report(&mut report).unwrap());
+
+        // The keyboard is the first interface, so has the first IN endpoint
+        wire.lock().unwrap().busy = 1 << 1;
+        report.mods = Mods::CTRL;
+        report.mouse.x = 5;
+        assert!(!usb.write_report(&mut report).unwrap());
+        assert_eq!(report.mouse, MouseReport::default());
+
+        wire.lock().unwrap().busy = 0;
+        assert!(usb.write_report(&mut report).unwrap());
+
+        // Both keyboard reports, and the movement only once
+        let reports = wire.lock().unwrap().reports.clone();
+        let keyboard = reports.iter().filter(|(index, _)| *index == 1).count();
+        assert_eq!(
+            (keyboard, reports.len() - keyboard),
+            (2, 1),
+            "{:?}",
+            reports
+        );
+    }
+
+    #[test]
+    fn test_report_descriptors() {
+        assert_eq!(report_lengths(SYSTEM_CONTROL_DESCRIPTOR), Ok((2, 0)));
+        assert_eq!(
+            report_lengths(RAW_HID_DESCRIPTOR),
+            Ok((RAW_REPORT_LEN, RAW_REPORT_LEN))
+        );
+    }
+
+    #[test]
+    fn test_bad_report_descriptors() {
+        // Collection never closed
+        assert_eq!(
+            report_lengths(&[0x05, 0x01, 0x09, 0x80, 0xA1, 0x01]),
+            Err(DescriptorError::UnbalancedCollection)
+        );
+        // Usage Page missing its data
+        assert_eq!(report_lengths(&[0x05]), Err(DescriptorError::Truncated));
+        // Usage before any Usage Page
+        assert_eq!(
+            report_lengths(&[0x09, 0x80]),
+            Err(DescriptorError::MissingUsagePage)
+        );
+        // Input without a Report Size
+        assert_eq!(
+            report_lengths(&[0x05, 0x01, 0xA1, 0x01, 0x95, 0x01, 0x81, 0x00, 0xC0]),
+            Err(DescriptorError::MissingReportSize)
+        );
+    }
+}